@@ -0,0 +1,97 @@
+// Terrain shading, see TerrainMaterialExtension.
+// Each vertex has the materials of its triangle and a barycentric weight,
+// so materials blend across triangles between different materials.
+
+#import bevy_pbr::{
+    forward_io::{VertexOutput, FragmentOutput},
+    mesh_functions,
+    pbr_fragment::pbr_input_from_standard_material,
+    pbr_functions::{alpha_discard, apply_pbr_lighting, main_pass_post_lighting_processing},
+    view_transformations::position_world_to_clip,
+}
+
+// Must match MAX_SHADED_MATERIALS.
+const MAX_SHADED_MATERIALS: u32 = 256u;
+
+@group(2) @binding(100) var textures: texture_2d_array<f32>;
+@group(2) @binding(101) var textures_sampler: sampler;
+// roughness, metallic
+@group(2) @binding(102) var<uniform> surfaces: array<vec4<f32>, 256>;
+@group(2) @binding(103) var<uniform> texture_scale: f32;
+
+struct Vertex {
+    @builtin(instance_index) instance_index: u32,
+    @location(0) position: vec3<f32>,
+    @location(1) normal: vec3<f32>,
+    @location(8) material_ids: vec3<u32>,
+    @location(9) material_weights: vec3<f32>,
+}
+
+struct TerrainVertexOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) world_position: vec4<f32>,
+    @location(1) world_normal: vec3<f32>,
+    @location(2) @interpolate(flat) material_ids: vec3<u32>,
+    @location(3) material_weights: vec3<f32>,
+}
+
+@vertex
+fn vertex(vertex: Vertex) -> TerrainVertexOutput {
+    var out: TerrainVertexOutput;
+    let model = mesh_functions::get_model_matrix(vertex.instance_index);
+    out.world_position = mesh_functions::mesh_position_local_to_world(model, vec4<f32>(vertex.position, 1.0));
+    out.position = position_world_to_clip(out.world_position.xyz);
+    out.world_normal = mesh_functions::mesh_normal_local_to_world(vertex.normal, vertex.instance_index);
+    out.material_ids = vertex.material_ids;
+    out.material_weights = vertex.material_weights;
+    return out;
+}
+
+fn material_layer(id: u32) -> i32 {
+    return i32(min(id, u32(textureNumLayers(textures)) - 1u));
+}
+
+// Samples a material texture projected along each axis,
+// blended by how much the surface faces that axis.
+fn triplanar(id: u32, position: vec3<f32>, blend: vec3<f32>) -> vec4<f32> {
+    let layer = material_layer(id);
+    let x = textureSample(textures, textures_sampler, position.zy, layer);
+    let y = textureSample(textures, textures_sampler, position.xz, layer);
+    let z = textureSample(textures, textures_sampler, position.xy, layer);
+    return x * blend.x + y * blend.y + z * blend.z;
+}
+
+@fragment
+fn fragment(
+    in: TerrainVertexOutput,
+    @builtin(front_facing) is_front: bool,
+) -> FragmentOutput {
+    var standard_in: VertexOutput;
+    standard_in.position = in.position;
+    standard_in.world_position = in.world_position;
+    standard_in.world_normal = in.world_normal;
+    var pbr_input = pbr_input_from_standard_material(standard_in, is_front);
+
+    var blend = pow(abs(normalize(in.world_normal)), vec3<f32>(4.0));
+    blend = blend / (blend.x + blend.y + blend.z);
+    let position = in.world_position.xyz * texture_scale;
+    let weights = in.material_weights / max(in.material_weights.x + in.material_weights.y + in.material_weights.z, 0.0001);
+
+    var color = vec4<f32>(0.0);
+    var surface = vec4<f32>(0.0);
+    for (var i = 0; i < 3; i += 1) {
+        let id = in.material_ids[i];
+        color += triplanar(id, position, blend) * weights[i];
+        surface += surfaces[min(id, MAX_SHADED_MATERIALS - 1u)] * weights[i];
+    }
+
+    pbr_input.material.base_color = pbr_input.material.base_color * color;
+    pbr_input.material.perceptual_roughness = surface.x;
+    pbr_input.material.metallic = surface.y;
+    pbr_input.material.base_color = alpha_discard(pbr_input.material, pbr_input.material.base_color);
+
+    var out: FragmentOutput;
+    out.color = apply_pbr_lighting(pbr_input);
+    out.color = main_pass_post_lighting_processing(pbr_input, out.color);
+    return out;
+}
@@ -0,0 +1,359 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bevy::{
+    ecs::system::SystemParam,
+    input::{
+        gamepad::{GamepadAxisType, GamepadButtonType},
+        mouse::{MouseMotion, MouseWheel},
+        InputSystem,
+    },
+    prelude::*,
+};
+use serde::Deserialize;
+
+use crate::item::inventory::HOTBAR_SIZE;
+
+// Degrees turned per unit of mouse motion at sensitivity 1.
+const MOUSE_DEGREES_PER_UNIT: f32 = 0.022;
+
+pub struct InputPlugin {
+    // RON file defining the bindings,
+    // the built in bindings are used if None.
+    pub bindings_path: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash)]
+pub enum Action {
+    MoveForward,
+    MoveBack,
+    MoveLeft,
+    MoveRight,
+    // Also moves up in noclip.
+    Jump,
+    // Also moves down in noclip.
+    Crouch,
+    Dig,
+    Place,
+    ToggleNoclip,
+    Undo,
+    Redo,
+    // Selects a hotbar slot, from 0.
+    SelectSlot(u8),
+    NextSlot,
+    PreviousSlot,
+    ReleaseCursor,
+    ToggleDebugRender,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    // Triggered once per frame the wheel moves.
+    MouseWheelUp,
+    MouseWheelDown,
+    // Buttons and axes of any connected gamepad.
+    GamepadButton(GamepadButtonType),
+    // Pressed when the axis is past the threshold,
+    // negative thresholds for the negative direction.
+    GamepadAxis(GamepadAxisType, f32),
+}
+
+// Maps actions to the inputs triggering them.
+#[derive(Resource, Clone, Debug, Deserialize)]
+pub struct InputBindings {
+    pub bindings: HashMap<Action, Vec<Binding>>,
+    #[serde(default = "default_mouse_sensitivity")]
+    pub mouse_sensitivity: f32,
+    // Degrees per second at full right stick.
+    #[serde(default = "default_gamepad_look_speed")]
+    pub gamepad_look_speed: f32,
+}
+
+fn default_mouse_sensitivity() -> f32 {
+    return 4.0;
+}
+
+fn default_gamepad_look_speed() -> f32 {
+    return 180.0;
+}
+
+impl Default for InputBindings {
+    fn default() -> Self {
+        use Binding::*;
+
+        let mut bindings = HashMap::from([
+            (
+                Action::MoveForward,
+                vec![
+                    Key(KeyCode::KeyW),
+                    GamepadAxis(GamepadAxisType::LeftStickY, 0.1),
+                ],
+            ),
+            (
+                Action::MoveBack,
+                vec![
+                    Key(KeyCode::KeyS),
+                    GamepadAxis(GamepadAxisType::LeftStickY, -0.1),
+                ],
+            ),
+            (
+                Action::MoveLeft,
+                vec![
+                    Key(KeyCode::KeyA),
+                    GamepadAxis(GamepadAxisType::LeftStickX, -0.1),
+                ],
+            ),
+            (
+                Action::MoveRight,
+                vec![
+                    Key(KeyCode::KeyD),
+                    GamepadAxis(GamepadAxisType::LeftStickX, 0.1),
+                ],
+            ),
+            (
+                Action::Jump,
+                vec![Key(KeyCode::Space), GamepadButton(GamepadButtonType::South)],
+            ),
+            (
+                Action::Crouch,
+                vec![
+                    Key(KeyCode::ControlLeft),
+                    GamepadButton(GamepadButtonType::East),
+                ],
+            ),
+            (
+                Action::Dig,
+                vec![
+                    Mouse(MouseButton::Left),
+                    GamepadButton(GamepadButtonType::RightTrigger2),
+                ],
+            ),
+            (
+                Action::Place,
+                vec![
+                    Mouse(MouseButton::Right),
+                    GamepadButton(GamepadButtonType::LeftTrigger2),
+                ],
+            ),
+            (
+                Action::ToggleNoclip,
+                vec![Key(KeyCode::KeyV), GamepadButton(GamepadButtonType::North)],
+            ),
+            (
+                Action::Undo,
+                vec![
+                    Key(KeyCode::KeyZ),
+                    GamepadButton(GamepadButtonType::DPadLeft),
+                ],
+            ),
+            (
+                Action::Redo,
+                vec![
+                    Key(KeyCode::KeyY),
+                    GamepadButton(GamepadButtonType::DPadRight),
+                ],
+            ),
+            (
+                Action::NextSlot,
+                vec![
+                    MouseWheelDown,
+                    GamepadButton(GamepadButtonType::RightTrigger),
+                ],
+            ),
+            (
+                Action::PreviousSlot,
+                vec![MouseWheelUp, GamepadButton(GamepadButtonType::LeftTrigger)],
+            ),
+            (
+                Action::ReleaseCursor,
+                vec![
+                    Key(KeyCode::Escape),
+                    GamepadButton(GamepadButtonType::Select),
+                ],
+            ),
+            (Action::ToggleDebugRender, vec![Key(KeyCode::F1)]),
+        ]);
+
+        let digits = [
+            KeyCode::Digit1,
+            KeyCode::Digit2,
+            KeyCode::Digit3,
+            KeyCode::Digit4,
+            KeyCode::Digit5,
+            KeyCode::Digit6,
+            KeyCode::Digit7,
+            KeyCode::Digit8,
+            KeyCode::Digit9,
+        ];
+        for (slot, key) in digits.into_iter().take(HOTBAR_SIZE).enumerate() {
+            bindings.insert(Action::SelectSlot(slot as u8), vec![Key(key)]);
+        }
+
+        return InputBindings {
+            bindings,
+            mouse_sensitivity: default_mouse_sensitivity(),
+            gamepad_look_speed: default_gamepad_look_speed(),
+        };
+    }
+}
+
+impl InputBindings {
+    // Loads bindings from a RON file, see assets/input.ron.
+    // Actions left out of the file keep their built in bindings.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let loaded: InputBindings = ron::from_str(&text).map_err(|err| err.to_string())?;
+
+        let mut bindings = Self::default();
+        bindings.bindings.extend(loaded.bindings);
+        bindings.mouse_sensitivity = loaded.mouse_sensitivity;
+        bindings.gamepad_look_speed = loaded.gamepad_look_speed;
+        return Ok(bindings);
+    }
+}
+
+// State of the actions this frame, read by other plugins
+// instead of the raw input.
+#[derive(Resource, Default, Debug)]
+pub struct ActionState {
+    // How strongly each held action is pressed, 0 to 1.
+    values: HashMap<Action, f32>,
+    just_pressed: HashSet<Action>,
+    // Degrees to turn this frame, x to the right and y down.
+    pub look: Vec2,
+}
+
+impl ActionState {
+    pub fn pressed(&self, action: Action) -> bool {
+        return self.values.contains_key(&action);
+    }
+
+    pub fn just_pressed(&self, action: Action) -> bool {
+        return self.just_pressed.contains(&action);
+    }
+
+    // Gets how strongly an action is pressed, 0 to 1.
+    // Only gamepad axes give values between.
+    pub fn value(&self, action: Action) -> f32 {
+        return self.values.get(&action).copied().unwrap_or(0.0);
+    }
+}
+
+// Raw input the actions are read from.
+#[derive(SystemParam)]
+struct RawInput<'w, 's> {
+    keys: Res<'w, ButtonInput<KeyCode>>,
+    mouse_buttons: Res<'w, ButtonInput<MouseButton>>,
+    mouse_motion: EventReader<'w, 's, MouseMotion>,
+    mouse_wheel: EventReader<'w, 's, MouseWheel>,
+    gamepads: Res<'w, Gamepads>,
+    gamepad_buttons: Res<'w, ButtonInput<GamepadButton>>,
+    gamepad_axes: Res<'w, Axis<GamepadAxis>>,
+}
+
+impl<'w, 's> RawInput<'w, 's> {
+    // Gets how strongly a binding is pressed, 0 if not at all.
+    // wheel is the mouse wheel movement this frame.
+    fn value(&self, binding: &Binding, wheel: f32) -> f32 {
+        let pressed = |is_pressed: bool| if is_pressed { 1.0 } else { 0.0 };
+        match binding {
+            Binding::Key(key) => return pressed(self.keys.pressed(*key)),
+            Binding::Mouse(button) => return pressed(self.mouse_buttons.pressed(*button)),
+            Binding::MouseWheelUp => return pressed(wheel > 0.0),
+            Binding::MouseWheelDown => return pressed(wheel < 0.0),
+            Binding::GamepadButton(button_type) => {
+                let any_pressed = self.gamepads.iter().any(|gamepad| {
+                    return self
+                        .gamepad_buttons
+                        .pressed(GamepadButton::new(gamepad, *button_type));
+                });
+                return pressed(any_pressed);
+            }
+            Binding::GamepadAxis(axis_type, threshold) => {
+                // axis value in the direction of the threshold
+                let along = self.axis(*axis_type) * threshold.signum();
+                if along >= threshold.abs() {
+                    return along;
+                }
+                return 0.0;
+            }
+        }
+    }
+
+    // Gets an axis summed over the connected gamepads.
+    fn axis(&self, axis_type: GamepadAxisType) -> f32 {
+        return self
+            .gamepads
+            .iter()
+            .filter_map(|gamepad| self.gamepad_axes.get(GamepadAxis::new(gamepad, axis_type)))
+            .sum::<f32>()
+            .clamp(-1.0, 1.0);
+    }
+}
+
+impl InputPlugin {
+    fn update_action_state(
+        mut state: ResMut<ActionState>,
+        bindings: Res<InputBindings>,
+        mut raw: RawInput,
+        time: Res<Time>,
+    ) {
+        let wheel: f32 = raw.mouse_wheel.read().map(|ev| ev.y).sum();
+
+        let mut values = HashMap::new();
+        let mut just_pressed = HashSet::new();
+        for (action, action_bindings) in &bindings.bindings {
+            let mut value: f32 = 0.0;
+            for binding in action_bindings {
+                let binding_value = raw.value(binding, wheel);
+                // the wheel has no held state, every frame it moves is a press
+                let is_wheel = matches!(binding, Binding::MouseWheelUp | Binding::MouseWheelDown);
+                if binding_value > 0.0 && is_wheel {
+                    just_pressed.insert(*action);
+                }
+                value = value.max(binding_value);
+            }
+
+            if value > 0.0 {
+                if !state.pressed(*action) {
+                    just_pressed.insert(*action);
+                }
+                values.insert(*action, value);
+            }
+        }
+        state.values = values;
+        state.just_pressed = just_pressed;
+
+        let mut look = Vec2::ZERO;
+        for ev in raw.mouse_motion.read() {
+            look += ev.delta;
+        }
+        look *= MOUSE_DEGREES_PER_UNIT * bindings.mouse_sensitivity;
+
+        let stick = Vec2::new(
+            raw.axis(GamepadAxisType::RightStickX),
+            -raw.axis(GamepadAxisType::RightStickY),
+        );
+        look += stick * bindings.gamepad_look_speed * time.delta_seconds();
+        state.look = look;
+    }
+}
+
+impl Plugin for InputPlugin {
+    fn build(&self, app: &mut App) {
+        let bindings = match &self.bindings_path {
+            Some(path) => InputBindings::load(path).unwrap_or_else(|err| {
+                error!("Failed to load input bindings {:?}: {}", path, err);
+                return InputBindings::default();
+            }),
+            None => InputBindings::default(),
+        };
+
+        app.insert_resource(bindings)
+            .init_resource::<ActionState>()
+            .add_systems(PreUpdate, Self::update_action_state.after(InputSystem));
+    }
+}
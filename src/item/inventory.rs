@@ -0,0 +1,81 @@
+use bevy::prelude::*;
+
+use crate::terrain::material::MaterialId;
+
+// Number of inventory stacks that can be picked from the hotbar.
+pub const HOTBAR_SIZE: usize = 9;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ItemStack {
+    pub material: MaterialId,
+    // In cells of solid volume.
+    pub amount: f32,
+}
+
+// Materials held by an entity, one stack per material
+// in the order they were first collected.
+// The first HOTBAR_SIZE stacks are on the hotbar.
+#[derive(Component, Default, Debug)]
+pub struct Inventory {
+    stacks: Vec<ItemStack>,
+    // Selected hotbar slot.
+    selected: usize,
+}
+
+impl Inventory {
+    pub fn add(&mut self, material: MaterialId, amount: f32) {
+        match self
+            .stacks
+            .iter_mut()
+            .find(|stack| stack.material == material)
+        {
+            Some(stack) => stack.amount += amount,
+            None => self.stacks.push(ItemStack { material, amount }),
+        }
+    }
+
+    // Removes up to amount of a material, returning how much was removed.
+    // Empty stacks keep their slot so the hotbar doesn't shift.
+    pub fn take(&mut self, material: MaterialId, amount: f32) -> f32 {
+        let Some(stack) = self
+            .stacks
+            .iter_mut()
+            .find(|stack| stack.material == material)
+        else {
+            return 0.0;
+        };
+        let taken = amount.min(stack.amount);
+        stack.amount -= taken;
+        return taken;
+    }
+
+    // Gets how much of a material is held.
+    pub fn amount_of(&self, material: MaterialId) -> f32 {
+        return self
+            .stacks
+            .iter()
+            .find(|stack| stack.material == material)
+            .map_or(0.0, |stack| stack.amount);
+    }
+
+    pub fn get(&self, slot: usize) -> Option<&ItemStack> {
+        return self.stacks.get(slot);
+    }
+
+    pub fn selected(&self) -> usize {
+        return self.selected;
+    }
+
+    pub fn selected_stack(&self) -> Option<&ItemStack> {
+        return self.get(self.selected);
+    }
+
+    pub fn select(&mut self, slot: usize) {
+        self.selected = slot.min(HOTBAR_SIZE - 1);
+    }
+
+    // Moves the selection by a number of slots, wrapping around the hotbar.
+    pub fn cycle(&mut self, offset: i32) {
+        self.selected = (self.selected as i32 + offset).rem_euclid(HOTBAR_SIZE as i32) as usize;
+    }
+}
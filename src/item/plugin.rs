@@ -0,0 +1,289 @@
+use bevy::prelude::*;
+use bevy_rapier3d::{dynamics::RigidBody, geometry::Collider};
+
+use super::inventory::{Inventory, HOTBAR_SIZE};
+use crate::terrain::history::EditHistory;
+use crate::terrain::material::{CellMaterialRegistry, MaterialId};
+use crate::terrain::plugin::{TerrainMinedEvent, TerrainPlacedEvent};
+
+// Drops smaller than this many cells of material are not spawned.
+const MIN_DROP_AMOUNT: f32 = 0.05;
+// Size of a drop holding one cell of material.
+const DROP_SIZE: f32 = 0.25;
+const DROP_LIFETIME_SECS: f32 = 300.0;
+const HOTBAR_SLOT_SIZE: f32 = 56.0;
+
+pub struct ItemPlugin {}
+
+// Material lying in the world, waiting to be collected.
+#[derive(Component)]
+pub struct ItemDrop {
+    pub material: MaterialId,
+    // In cells of solid volume.
+    pub amount: f32,
+    despawn_timer: Timer,
+}
+
+// Collects item drops within its radius.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ItemCollector {
+    pub radius: f32,
+}
+
+impl Default for ItemCollector {
+    fn default() -> Self {
+        return ItemCollector { radius: 1.5 };
+    }
+}
+
+// Sent when an ItemCollector picks up a drop.
+#[derive(Event, Debug)]
+pub struct ItemCollectedEvent {
+    pub collector: Entity,
+    pub drop: Entity,
+    pub material: MaterialId,
+    pub amount: f32,
+}
+
+// Mesh and per material colors of item drops.
+#[derive(Resource)]
+struct ItemDropAssets {
+    mesh: Handle<Mesh>,
+    materials: Vec<Handle<StandardMaterial>>,
+}
+
+#[derive(Component)]
+struct HotbarSlot(usize);
+
+#[derive(Component)]
+struct HotbarSlotText(usize);
+
+impl ItemPlugin {
+    fn setup_drop_assets(
+        mut commands: Commands,
+        mut meshes: ResMut<Assets<Mesh>>,
+        mut materials: ResMut<Assets<StandardMaterial>>,
+        cell_materials: Res<CellMaterialRegistry>,
+    ) {
+        let drop_materials = cell_materials
+            .iter()
+            .map(|(_, material)| {
+                let [r, g, b, a] = material.color;
+                return materials.add(StandardMaterial {
+                    base_color: Color::rgba_linear(r, g, b, a),
+                    perceptual_roughness: material.roughness,
+                    metallic: material.metallic,
+                    ..default()
+                });
+            })
+            .collect();
+
+        commands.insert_resource(ItemDropAssets {
+            mesh: meshes.add(Cuboid::from_size(Vec3::splat(DROP_SIZE))),
+            materials: drop_materials,
+        });
+    }
+
+    fn spawn_drops(
+        mut commands: Commands,
+        mut mined_events: EventReader<TerrainMinedEvent>,
+        assets: Res<ItemDropAssets>,
+    ) {
+        for event in mined_events.read() {
+            for ((material, amount), drop_id) in event.amounts.iter().zip(&event.drops) {
+                let drop_material = assets.materials.get(*material as usize);
+                let Some(drop_material) = drop_material.filter(|_| *amount >= MIN_DROP_AMOUNT)
+                else {
+                    commands.entity(*drop_id).despawn();
+                    continue;
+                };
+
+                // bigger drops for more material, by volume
+                let scale = amount.cbrt().clamp(0.5, 2.0);
+                commands.entity(*drop_id).insert((
+                    PbrBundle {
+                        mesh: assets.mesh.clone(),
+                        material: drop_material.clone(),
+                        transform: Transform::from_translation(event.position)
+                            .with_scale(Vec3::splat(scale)),
+                        ..default()
+                    },
+                    RigidBody::Dynamic,
+                    Collider::cuboid(DROP_SIZE * 0.5, DROP_SIZE * 0.5, DROP_SIZE * 0.5),
+                    ItemDrop {
+                        material: *material,
+                        amount: *amount,
+                        despawn_timer: Timer::from_seconds(DROP_LIFETIME_SECS, TimerMode::Once),
+                    },
+                ));
+            }
+        }
+    }
+
+    fn collect_drops(
+        mut commands: Commands,
+        q_drops: Query<(Entity, &GlobalTransform, &ItemDrop)>,
+        q_collectors: Query<(Entity, &GlobalTransform, &ItemCollector)>,
+        mut collected_events: EventWriter<ItemCollectedEvent>,
+    ) {
+        for (drop_id, drop_trans, drop) in &q_drops {
+            let collector = q_collectors.iter().find(|(_, trans, collector)| {
+                return trans.translation().distance(drop_trans.translation()) < collector.radius;
+            });
+            if let Some((collector_id, _, _)) = collector {
+                collected_events.send(ItemCollectedEvent {
+                    collector: collector_id,
+                    drop: drop_id,
+                    material: drop.material,
+                    amount: drop.amount,
+                });
+                commands.entity(drop_id).despawn_recursive();
+            }
+        }
+    }
+
+    fn add_collected_items(
+        mut collected_events: EventReader<ItemCollectedEvent>,
+        mut q_inventories: Query<&mut Inventory>,
+        mut history: ResMut<EditHistory>,
+    ) {
+        for event in collected_events.read() {
+            if let Ok(mut inventory) = q_inventories.get_mut(event.collector) {
+                inventory.add(event.material, event.amount);
+            }
+            // undoing the edit that dropped it takes it back
+            history.mark_collected(event.drop);
+        }
+    }
+
+    // Placing terrain uses up the placed materials.
+    fn consume_placed_materials(
+        mut placed_events: EventReader<TerrainPlacedEvent>,
+        mut q_inventories: Query<&mut Inventory>,
+    ) {
+        for event in placed_events.read() {
+            let Some(mut inventory) = event
+                .source
+                .and_then(|source| q_inventories.get_mut(source).ok())
+            else {
+                continue;
+            };
+            for (material, amount) in &event.amounts {
+                inventory.take(*material, *amount);
+            }
+        }
+    }
+
+    fn spawn_hotbar(mut commands: Commands) {
+        commands
+            .spawn(NodeBundle {
+                style: Style {
+                    position_type: PositionType::Absolute,
+                    bottom: Val::Px(16.0),
+                    width: Val::Percent(100.0),
+                    justify_content: JustifyContent::Center,
+                    column_gap: Val::Px(4.0),
+                    ..default()
+                },
+                ..default()
+            })
+            .with_children(|parent| {
+                for slot in 0..HOTBAR_SIZE {
+                    parent
+                        .spawn((
+                            NodeBundle {
+                                style: Style {
+                                    width: Val::Px(HOTBAR_SLOT_SIZE),
+                                    height: Val::Px(HOTBAR_SLOT_SIZE),
+                                    border: UiRect::all(Val::Px(3.0)),
+                                    padding: UiRect::all(Val::Px(2.0)),
+                                    justify_content: JustifyContent::End,
+                                    align_items: AlignItems::End,
+                                    ..default()
+                                },
+                                background_color: Color::rgba(0.0, 0.0, 0.0, 0.4).into(),
+                                ..default()
+                            },
+                            HotbarSlot(slot),
+                        ))
+                        .with_children(|slot_node| {
+                            slot_node.spawn((
+                                TextBundle::from_section(
+                                    "",
+                                    TextStyle {
+                                        font_size: 16.0,
+                                        color: Color::WHITE,
+                                        ..default()
+                                    },
+                                ),
+                                HotbarSlotText(slot),
+                            ));
+                        });
+                }
+            });
+    }
+
+    // Shows the stacks and selection of the inventory on the hotbar.
+    fn update_hotbar(
+        q_inventory: Query<&Inventory, Changed<Inventory>>,
+        mut q_slots: Query<(&HotbarSlot, &mut BackgroundColor, &mut BorderColor)>,
+        mut q_texts: Query<(&HotbarSlotText, &mut Text)>,
+        cell_materials: Res<CellMaterialRegistry>,
+    ) {
+        let Some(inventory) = q_inventory.iter().next() else {
+            return;
+        };
+
+        for (slot, mut background, mut border) in &mut q_slots {
+            background.0 = match inventory.get(slot.0) {
+                Some(stack) => {
+                    let [r, g, b, _] = cell_materials.get(stack.material).color;
+                    Color::rgba_linear(r, g, b, 0.9)
+                }
+                None => Color::rgba(0.0, 0.0, 0.0, 0.4),
+            };
+            border.0 = if slot.0 == inventory.selected() {
+                Color::WHITE
+            } else {
+                Color::rgba(1.0, 1.0, 1.0, 0.2)
+            };
+        }
+
+        for (slot_text, mut text) in &mut q_texts {
+            text.sections[0].value = match inventory.get(slot_text.0) {
+                Some(stack) => format!("{:.1}", stack.amount),
+                None => String::new(),
+            };
+        }
+    }
+
+    fn despawn_old_drops(
+        mut commands: Commands,
+        mut q_drops: Query<(Entity, &mut ItemDrop)>,
+        time: Res<Time>,
+    ) {
+        for (drop_id, mut drop) in &mut q_drops {
+            if drop.despawn_timer.tick(time.delta()).just_finished() {
+                commands.entity(drop_id).despawn_recursive();
+            }
+        }
+    }
+}
+
+impl Plugin for ItemPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ItemCollectedEvent>()
+            .add_systems(Startup, (Self::setup_drop_assets, Self::spawn_hotbar))
+            .add_systems(
+                Update,
+                (
+                    Self::spawn_drops,
+                    Self::collect_drops,
+                    Self::add_collected_items,
+                    Self::consume_placed_materials,
+                    Self::update_hotbar,
+                    Self::despawn_old_drops,
+                ),
+            );
+    }
+}
@@ -1,16 +1,60 @@
+mod input;
+mod item;
 mod player;
+mod replay;
 mod terrain;
 
-use bevy::{prelude::*, window::CursorGrabMode};
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+use bevy::{
+    app::ScheduleRunnerPlugin,
+    prelude::*,
+    render::{settings::WgpuSettings, RenderPlugin},
+    window::{CursorGrabMode, ExitCondition},
+    winit::WinitPlugin,
+};
 use bevy_rapier3d::{
     plugin::{NoUserData, RapierPhysicsPlugin},
     render::{DebugRenderContext, RapierDebugRenderPlugin},
 };
+use input::plugin::{Action, ActionState, InputPlugin};
+use item::plugin::ItemPlugin;
 use player::plugin::PlayerPlugin;
-use terrain::plugin::TerrainPlugin;
+use replay::{
+    plugin::{ReplayMode, ReplayPlugin},
+    recording::Recording,
+};
+use terrain::{chunk::MeshMode, plugin::TerrainPlugin};
+
+const SEED: u32 = 1337;
+// Time between frames of headless replays, one fixed step.
+const REPLAY_FRAME_SECS: f64 = 1.0 / 64.0;
+
+// Reads --record <file> or --replay <file> from the command line.
+fn replay_mode_from_args() -> Option<ReplayMode> {
+    let args: Vec<String> = env::args().collect();
+    let path_after = |flag: &str| {
+        let index = args.iter().position(|arg| arg == flag)?;
+        return args.get(index + 1).map(PathBuf::from);
+    };
 
-fn debug_input(kb_input: Res<ButtonInput<KeyCode>>, mut debug_render: ResMut<DebugRenderContext>) {
-    if kb_input.just_pressed(KeyCode::F1) {
+    if let Some(path) = path_after("--replay") {
+        match Recording::load(&path) {
+            Ok(recording) => return Some(ReplayMode::Replay(recording)),
+            Err(err) => {
+                eprintln!("Failed to load recording {:?}: {}", path, err);
+                process::exit(1);
+            }
+        }
+    }
+    return path_after("--record").map(ReplayMode::Record);
+}
+
+fn debug_input(actions: Res<ActionState>, mut debug_render: ResMut<DebugRenderContext>) {
+    if actions.just_pressed(Action::ToggleDebugRender) {
         debug_render.enabled = !debug_render.enabled;
     }
 }
@@ -18,44 +62,97 @@ fn debug_input(kb_input: Res<ButtonInput<KeyCode>>, mut debug_render: ResMut<Deb
 fn grab_mouse(
     mut windows: Query<&mut Window>,
     mouse: Res<ButtonInput<MouseButton>>,
-    key: Res<ButtonInput<KeyCode>>,
+    actions: Res<ActionState>,
 ) {
-    let mut window = windows.single_mut();
+    let Ok(mut window) = windows.get_single_mut() else {
+        return;
+    };
 
     if mouse.just_pressed(MouseButton::Left) {
         window.cursor.visible = false;
         window.cursor.grab_mode = CursorGrabMode::Locked;
     }
 
-    if key.just_pressed(KeyCode::Escape) {
+    if actions.just_pressed(Action::ReleaseCursor) {
         window.cursor.visible = true;
         window.cursor.grab_mode = CursorGrabMode::None;
     }
 }
 
 fn main() {
-    App::new()
-        .insert_resource(ClearColor(Color::BLACK))
+    let replay_mode = replay_mode_from_args();
+    // recorded sessions start from a freshly generated world
+    let (seed, save_dir) = match &replay_mode {
+        Some(ReplayMode::Replay(recording)) => (recording.seed, None),
+        Some(ReplayMode::Record(_)) => (SEED, None),
+        None => (SEED, Some("saves/world".into())),
+    };
+    let headless = matches!(replay_mode, Some(ReplayMode::Replay(_)));
+
+    let mut app = App::new();
+    app.insert_resource(ClearColor(Color::BLACK))
         .insert_resource(AmbientLight {
             color: Color::WHITE,
             brightness: 10.0,
-        })
-        .add_plugins(DefaultPlugins.set(WindowPlugin {
+        });
+
+    if headless {
+        app.add_plugins(
+            DefaultPlugins
+                .set(WindowPlugin {
+                    primary_window: None,
+                    exit_condition: ExitCondition::DontExit,
+                    close_when_requested: false,
+                })
+                .set(RenderPlugin {
+                    render_creation: WgpuSettings {
+                        backends: None,
+                        ..default()
+                    }
+                    .into(),
+                    ..default()
+                })
+                .disable::<WinitPlugin>(),
+        )
+        .add_plugins(ScheduleRunnerPlugin::run_loop(Duration::from_secs_f64(
+            REPLAY_FRAME_SECS,
+        )));
+    } else {
+        app.add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
                 title: "Luola".into(),
                 resolution: (1920.0, 1080.0).into(),
                 ..default()
             }),
             ..default()
-        }))
-        .add_plugins(RapierPhysicsPlugin::<NoUserData>::default())
+        }));
+    }
+
+    app.add_plugins(RapierPhysicsPlugin::<NoUserData>::default().in_fixed_schedule())
         .add_plugins(RapierDebugRenderPlugin {
             enabled: false,
             ..default()
         })
-        .add_plugins(TerrainPlugin { seed: 1337 })
+        .add_plugins(InputPlugin {
+            bindings_path: Some("assets/input.ron".into()),
+        })
+        .add_plugins(TerrainPlugin {
+            seed,
+            mesh_mode: MeshMode::Smooth,
+            save_dir,
+            materials_path: Some("assets/materials.ron".into()),
+            biomes_path: Some("assets/biomes.ron".into()),
+            density_path: Some("assets/density.ron".into()),
+            ores_path: Some("assets/ores.ron".into()),
+        })
         .add_plugins(PlayerPlugin {})
+        .add_plugins(ItemPlugin {})
         .add_systems(Update, debug_input)
-        .add_systems(Update, grab_mouse)
-        .run();
+        .add_systems(Update, grab_mouse);
+
+    if let Some(mode) = replay_mode {
+        app.add_plugins(ReplayPlugin { mode });
+    }
+
+    app.run();
 }
@@ -1,6 +1,7 @@
+use std::f32::consts::PI;
+
 use bevy::{
     core_pipeline::{bloom::BloomSettings, tonemapping::Tonemapping},
-    input::mouse::MouseMotion,
     prelude::*,
 };
 use bevy_rapier3d::{
@@ -8,13 +9,47 @@ use bevy_rapier3d::{
     dynamics::RigidBody,
     geometry::{Collider, TOIStatus},
     math::Vect,
+    pipeline::QueryFilter,
+    plugin::{PhysicsSet, RapierContext},
     prelude::KinematicCharacterController,
 };
-
-use crate::terrain::chunk::{CellType, CHUNK_CUBE_SIZE};
-use crate::terrain::plugin::{TerrainCellEvent, TerrainEditShape, RENDER_DISTANCE_CHUNKS};
+use serde::{Deserialize, Serialize};
+
+use crate::input::plugin::{Action, ActionState};
+use crate::item::inventory::{Inventory, HOTBAR_SIZE};
+use crate::item::plugin::ItemCollector;
+use crate::terrain::chunk::{Chunk, CHUNK_CUBE_SIZE};
+use crate::terrain::chunk_map::ChunkMap;
+use crate::terrain::history::{RedoTerrainEdit, UndoTerrainEdit};
+use crate::terrain::liquid::{ChunkLiquid, LiquidType};
+use crate::terrain::plugin::{
+    ChunkLoader, TerrainCellEvent, TerrainEditMode, TerrainEditSet, TerrainEditShape,
+    RENDER_DISTANCE_CHUNKS,
+};
 
 const RENDER_DISTANCE: f32 = CHUNK_CUBE_SIZE as f32 * RENDER_DISTANCE_CHUNKS as f32;
+// Least material needed in the selected stack to place terrain.
+const MIN_PLACE_AMOUNT: f32 = 1.0;
+// Radius of the sphere of terrain placed and dug.
+const EDIT_RADIUS: f32 = 1.5;
+const CAPSULE_RADIUS: f32 = 0.3;
+// Heights of the capsule segment above the player origin.
+const STAND_HEIGHT: f32 = 1.6;
+const CROUCH_HEIGHT: f32 = 0.8;
+// Radius shrink of the standing up check,
+// so touching walls doesn't count as being blocked.
+const STAND_CLEARANCE: f32 = 0.05;
+// How fast the camera moves when crouching, per second.
+const CROUCH_CAMERA_SPEED: f32 = 4.0;
+// Number of points along the capsule liquid is sampled at.
+const LIQUID_SAMPLES: usize = 6;
+// Fraction of the capsule in liquid at which the player swims.
+const SWIM_SUBMERSION: f32 = 0.4;
+// Liquid level above which a cell covers the player's head.
+const HEAD_SUBMERGED_LEVEL: f32 = 0.5;
+// How many times faster breath comes back than it runs out.
+const BREATH_RECOVERY_RATE: f32 = 5.0;
+const BREATH_BAR_WIDTH: f32 = 200.0;
 
 pub struct PlayerPlugin {}
 
@@ -32,6 +67,7 @@ pub struct Player {
     accel: f32,
     air_accel: f32,
     max_vel_ground: f32,
+    max_vel_crouch: f32,
     max_vel_air: f32,
     max_fall_vel: f32,
     jump_vel: f32,
@@ -40,16 +76,66 @@ pub struct Player {
     wish_dir: Vec3,
     wish_jump: bool,
     wish_duck: bool,
+    crouched: bool,
     camera_height: f32,
+    stand_camera_height: f32,
+    crouch_camera_height: f32,
+    swimming: bool,
+    // Fraction of the capsule in liquid, 0 to 1.
+    submersion: f32,
+    // Liquid the player is deepest in.
+    liquid: LiquidType,
+    swim_accel: f32,
+    max_vel_swim: f32,
+    swim_drag: f32,
+    // Upward acceleration when fully submerged.
+    buoyancy: f32,
+    // Seconds the player can stay under liquid for.
+    breath: f32,
+    max_breath: f32,
+    // Positions after the last two fixed steps. The camera is drawn
+    // between them, as frames fall between steps.
+    previous_translation: Vec3,
+    fixed_translation: Vec3,
+}
+
+// Input of a player for the next movement step,
+// collected every frame and consumed in FixedUpdate.
+#[derive(Component, Default, Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct PlayerCommand {
+    // Forward and right, up to 1 with analog sticks.
+    pub move_input: Vec2,
+    // Look angles in degrees.
+    pub yaw: f32,
+    pub pitch: f32,
+    pub jump: bool,
+    pub crouch: bool,
+    // Pressed since the last step.
+    pub toggle_noclip: bool,
+    pub dig: bool,
+    pub place: bool,
+    #[serde(default)]
+    pub undo: bool,
+    #[serde(default)]
+    pub redo: bool,
 }
 
+// Player movement in FixedUpdate,
+// systems changing the PlayerCommand of a step run before it.
+#[derive(SystemSet, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PlayerMoveSet;
+
 #[derive(Bundle, Default)]
 struct PlayerBundle {
     player: Player,
+    command: PlayerCommand,
     transform: SpatialBundle,
     controller: KinematicCharacterController,
     rigidbody: RigidBody,
     collider: Collider,
+    chunk_loader: ChunkLoader,
+    item_collector: ItemCollector,
+    inventory: Inventory,
     tag: PlayerTag,
 }
 
@@ -75,6 +161,13 @@ struct PlayerTag {}
 #[derive(Component, Default)]
 struct PlayerLightTag {}
 
+// Shown while the player is out of breath.
+#[derive(Component)]
+struct BreathMeter;
+
+#[derive(Component)]
+struct BreathBar;
+
 impl PlayerPlugin {
     fn spawn_player(
         mut commands: Commands,
@@ -87,6 +180,7 @@ impl PlayerPlugin {
                 player: Player {
                     noclip_speed: 40.0,
                     max_vel_ground: 5.0,
+                    max_vel_crouch: 2.5,
                     max_vel_air: 8.0,
                     max_fall_vel: 30.0,
                     jump_vel: 4.0,
@@ -94,6 +188,16 @@ impl PlayerPlugin {
                     air_accel: 8.0,
                     friction: 8.0,
                     camera_height: 1.4,
+                    stand_camera_height: 1.4,
+                    crouch_camera_height: 0.6,
+                    swim_accel: 12.0,
+                    max_vel_swim: 3.0,
+                    swim_drag: 2.0,
+                    buoyancy: 12.0,
+                    breath: 20.0,
+                    max_breath: 20.0,
+                    previous_translation: trans.translation,
+                    fixed_translation: trans.translation,
                     ..default()
                 },
                 transform: SpatialBundle {
@@ -112,15 +216,7 @@ impl PlayerPlugin {
                     ..default()
                 },
                 rigidbody: RigidBody::KinematicPositionBased,
-                collider: Collider::capsule(
-                    Vect::ZERO,
-                    Vect {
-                        x: 0.0,
-                        y: 1.6,
-                        z: 0.0,
-                    },
-                    0.3,
-                ),
+                collider: Self::player_collider(STAND_HEIGHT),
                 ..default()
             })
             .with_children(|parent| {
@@ -175,41 +271,54 @@ impl PlayerPlugin {
             });
     }
 
-    // todo this should only save the input values.
-    // move the translation, etc. to update.
+    fn hotbar_input(
+        actions: Res<ActionState>,
+        mut q_inventory: Query<&mut Inventory, With<Player>>,
+    ) {
+        for mut inventory in &mut q_inventory {
+            for slot in 0..HOTBAR_SIZE {
+                if actions.just_pressed(Action::SelectSlot(slot as u8)) {
+                    inventory.select(slot);
+                }
+            }
+            if actions.just_pressed(Action::NextSlot) {
+                inventory.cycle(1);
+            }
+            if actions.just_pressed(Action::PreviousSlot) {
+                inventory.cycle(-1);
+            }
+        }
+    }
+
+    // Turns the player and camera right away so looking stays smooth,
+    // everything else is saved to the PlayerCommand for player_move.
+    // The player moves in fixed steps, so the camera is moved back
+    // to where the player was between the last two of them.
     fn player_input(
-        mut mouse: EventReader<MouseMotion>,
-        mouse_buttons: Res<ButtonInput<MouseButton>>,
-        kb_input: Res<ButtonInput<KeyCode>>,
-        mut q_parent: Query<(
-            Entity,
-            &mut Player,
-            &mut KinematicCharacterController,
-            &Children,
-        )>,
+        actions: Res<ActionState>,
+        mut q_parent: Query<(Entity, &mut Player, &mut PlayerCommand, &Children)>,
         mut q_cam: Query<(Entity, &mut CameraAngles)>,
         q_light: Query<Entity, With<PlayerLightTag>>,
         mut q_trans: Query<&mut Transform, With<PlayerTag>>,
-        time: Res<Time>,
-        mut events: EventWriter<TerrainCellEvent>,
+        fixed_time: Res<Time<Fixed>>,
     ) {
-        let mut mouse_move = Vec2 { x: 0.0, y: 0.0 };
-        for ev in mouse.read() {
-            mouse_move.x += ev.delta.x;
-            mouse_move.y += ev.delta.y;
-        }
+        let look = actions.look;
 
-        let sensitivity = 4.0;
-
-        for (player_id, mut player, mut controller, children) in &mut q_parent {
-            if f32::abs(mouse_move.x) > f32::EPSILON {
-                player.yaw -= mouse_move.x * 0.022 * sensitivity;
+        for (player_id, mut player, mut command, children) in &mut q_parent {
+            if f32::abs(look.x) > f32::EPSILON {
+                player.yaw -= look.x;
             }
-            if kb_input.just_pressed(KeyCode::KeyV) {
-                player.noclip = !player.noclip;
+
+            let mut eye = Vec3::Y * player.camera_height;
+            if let Ok(mut player_transform) = q_trans.get_mut(player_id) {
+                player_transform.rotation = Quat::from_rotation_y(player.yaw.to_radians());
+
+                let drawn = player
+                    .previous_translation
+                    .lerp(player.fixed_translation, fixed_time.overstep_fraction());
+                eye += player_transform.rotation.inverse() * (drawn - player_transform.translation);
             }
 
-            let mut cam_fwd = -Vec3::Z;
             let mut cam_rot = Quat::IDENTITY;
 
             for child in children.iter() {
@@ -217,21 +326,17 @@ impl PlayerPlugin {
                     let cam_id = cam.0;
                     let mut cam_angles = cam.1;
 
-                    if f32::abs(mouse_move.y) > f32::EPSILON {
-                        cam_angles.pitch -= mouse_move.y * 0.022 * sensitivity;
+                    if f32::abs(look.y) > f32::EPSILON {
+                        cam_angles.pitch -= look.y;
                         cam_angles.pitch = f32::clamp(cam_angles.pitch, -89.0, 89.0);
                     }
+                    command.pitch = cam_angles.pitch;
 
                     if let Ok(mut cam_trans) = q_trans.get_mut(cam_id) {
-                        cam_trans.translation = Vec3 {
-                            x: 0.0,
-                            y: player.camera_height,
-                            z: 0.0,
-                        };
+                        cam_trans.translation = eye;
                         cam_trans.rotation = Quat::IDENTITY;
                         cam_trans.rotate_local_x(cam_angles.pitch.to_radians());
                         cam_rot = cam_trans.rotation;
-                        cam_fwd = cam_trans.forward().into();
                     }
                 }
             }
@@ -239,108 +344,342 @@ impl PlayerPlugin {
             for child in children.iter() {
                 if let Ok(light) = q_light.get(*child) {
                     if let Ok(mut light_trans) = q_trans.get_mut(light) {
-                        light_trans.translation = Vec3 {
-                            x: 0.0,
-                            y: player.camera_height,
-                            z: 0.0,
-                        };
+                        light_trans.translation = eye;
                         light_trans.rotation = cam_rot;
                     }
                 }
             }
 
-            if let Ok(mut player_transform) = q_trans.get_mut(player_id) {
-                player_transform.rotation = Quat::IDENTITY;
-                player_transform.rotate_y(player.yaw.to_radians());
+            command.yaw = player.yaw;
+            command.move_input = Vec2 {
+                x: actions.value(Action::MoveForward) - actions.value(Action::MoveBack),
+                y: actions.value(Action::MoveRight) - actions.value(Action::MoveLeft),
+            }
+            .clamp_length_max(1.0);
+            command.jump = actions.pressed(Action::Jump);
+            command.crouch = actions.pressed(Action::Crouch);
+            // presses are kept until a step consumes them,
+            // frames can pass without a fixed step
+            command.toggle_noclip |= actions.just_pressed(Action::ToggleNoclip);
+            command.dig |= actions.just_pressed(Action::Dig);
+            command.place |= actions.just_pressed(Action::Place);
+            command.undo |= actions.just_pressed(Action::Undo);
+            command.redo |= actions.just_pressed(Action::Redo);
+        }
+    }
 
-                let fwd: Vec3 = player_transform.forward().into();
-                let right: Vec3 = player_transform.right().into();
-                let up = Vec3::Y;
-                cam_fwd = player_transform.rotation.mul_vec3(cam_fwd);
+    fn spawn_breath_meter(mut commands: Commands) {
+        commands
+            .spawn((
+                NodeBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        bottom: Val::Px(88.0),
+                        left: Val::Percent(50.0),
+                        margin: UiRect::left(Val::Px(-BREATH_BAR_WIDTH * 0.5)),
+                        width: Val::Px(BREATH_BAR_WIDTH),
+                        height: Val::Px(8.0),
+                        ..default()
+                    },
+                    background_color: Color::rgba(0.0, 0.0, 0.0, 0.4).into(),
+                    visibility: Visibility::Hidden,
+                    ..default()
+                },
+                BreathMeter,
+            ))
+            .with_children(|parent| {
+                parent.spawn((
+                    NodeBundle {
+                        style: Style {
+                            width: Val::Percent(100.0),
+                            height: Val::Percent(100.0),
+                            ..default()
+                        },
+                        background_color: Color::rgb(0.4, 0.7, 1.0).into(),
+                        ..default()
+                    },
+                    BreathBar,
+                ));
+            });
+    }
 
-                if player.noclip {
-                    let delta = player.noclip_speed * time.delta_seconds();
+    fn update_breath_meter(
+        q_player: Query<&Player>,
+        mut q_meter: Query<&mut Visibility, With<BreathMeter>>,
+        mut q_bar: Query<&mut Style, With<BreathBar>>,
+    ) {
+        let Some(player) = q_player.iter().next() else {
+            return;
+        };
+        let fraction = (player.breath / player.max_breath).clamp(0.0, 1.0);
+
+        for mut visibility in &mut q_meter {
+            *visibility = if fraction < 1.0 {
+                Visibility::Inherited
+            } else {
+                Visibility::Hidden
+            };
+        }
+        for mut style in &mut q_bar {
+            style.width = Val::Percent(fraction * 100.0);
+        }
+    }
 
-                    if kb_input.pressed(KeyCode::KeyE) {
-                        player_transform.translation += cam_fwd * delta;
-                    }
-                    if kb_input.pressed(KeyCode::KeyD) {
-                        player_transform.translation -= cam_fwd * delta;
-                    }
-                    if kb_input.pressed(KeyCode::KeyF) {
-                        player_transform.translation += right * delta;
-                    }
-                    if kb_input.pressed(KeyCode::KeyS) {
-                        player_transform.translation -= right * delta;
-                    }
-                    if kb_input.pressed(KeyCode::Space) {
-                        player_transform.translation += up * delta;
-                    }
-                    if kb_input.pressed(KeyCode::ControlLeft) {
-                        player_transform.translation -= up * delta;
-                    }
+    // Finds how deep the player is in liquid and updates its breath.
+    fn player_liquid(
+        mut q_player: Query<(&mut Player, &Transform)>,
+        q_liquids: Query<&ChunkLiquid>,
+        chunk_map: Res<ChunkMap>,
+        time: Res<Time>,
+    ) {
+        let get_liquid = |chunk_pos| {
+            return chunk_map
+                .get(chunk_pos)
+                .and_then(|chunk_id| q_liquids.get(chunk_id).ok());
+        };
+
+        for (mut player, player_transform) in &mut q_player {
+            let height = if player.crouched {
+                CROUCH_HEIGHT
+            } else {
+                STAND_HEIGHT
+            };
+            let bottom = player_transform.translation - Vec3::Y * CAPSULE_RADIUS;
+            let span = height + CAPSULE_RADIUS * 2.0;
+
+            let mut total_level = 0.0;
+            let mut deepest = 0.0;
+            for sample in 0..LIQUID_SAMPLES {
+                let offset = span * (sample as f32 + 0.5) / LIQUID_SAMPLES as f32;
+                let Some(cell) = ChunkLiquid::at_world(bottom + Vec3::Y * offset, get_liquid)
+                else {
+                    continue;
+                };
+                let level = cell.level.clamp(0.0, 1.0);
+                total_level += level;
+                if level > deepest {
+                    deepest = level;
+                    player.liquid = cell.kind;
+                }
+            }
+            player.submersion = total_level / LIQUID_SAMPLES as f32;
+            player.swimming = !player.noclip && player.submersion >= SWIM_SUBMERSION;
+
+            let head = player_transform.translation + Vec3::Y * player.camera_height;
+            let head_submerged = ChunkLiquid::at_world(head, get_liquid)
+                .is_some_and(|cell| cell.level > HEAD_SUBMERGED_LEVEL);
+            let dt = time.delta_seconds();
+            player.breath = if head_submerged && !player.noclip {
+                (player.breath - dt).max(0.0)
+            } else {
+                (player.breath + dt * BREATH_RECOVERY_RATE).min(player.max_breath)
+            };
+        }
+    }
 
-                    player.velocity = Vect::ZERO;
-                } else {
-                    player.velocity.y -= 9.81 * time.delta_seconds();
+    fn player_collider(height: f32) -> Collider {
+        return Collider::capsule(Vect::ZERO, Vect::Y * height, CAPSULE_RADIUS);
+    }
 
-                    let mut wish_dir = Vec3::ZERO;
+    // Shrinks the collider while crouching and grows it back
+    // once there is no terrain above the player.
+    fn player_crouch(
+        mut q_player: Query<(&mut Player, &PlayerCommand, &mut Collider, &Transform)>,
+        q_colliders: Query<&Parent, With<Collider>>,
+        q_chunks: Query<(), With<Chunk>>,
+        rapier_context: Res<RapierContext>,
+        time: Res<Time>,
+    ) {
+        for (mut player, command, mut collider, player_transform) in &mut q_player {
+            // crouch is swimming down in liquid
+            player.wish_duck = command.crouch && !player.noclip && !player.swimming;
+
+            if player.wish_duck && !player.crouched {
+                player.crouched = true;
+                *collider = Self::player_collider(CROUCH_HEIGHT);
+            } else if !player.wish_duck && player.crouched {
+                // the space the upper body takes when standing
+                let upper_body = Collider::capsule(
+                    Vect::Y * CROUCH_HEIGHT,
+                    Vect::Y * STAND_HEIGHT,
+                    CAPSULE_RADIUS - STAND_CLEARANCE,
+                );
+                let is_chunk_collider = |entity| {
+                    return q_colliders
+                        .get(entity)
+                        .is_ok_and(|parent| q_chunks.contains(parent.get()));
+                };
+                let query_filter = QueryFilter::new().predicate(&is_chunk_collider);
+                let blocked = rapier_context
+                    .intersection_with_shape(
+                        player_transform.translation,
+                        Quat::IDENTITY,
+                        &upper_body,
+                        query_filter,
+                    )
+                    .is_some();
+                if !blocked {
+                    player.crouched = false;
+                    *collider = Self::player_collider(STAND_HEIGHT);
+                }
+            }
 
-                    if kb_input.pressed(KeyCode::KeyE) {
-                        wish_dir += fwd;
-                    }
-                    if kb_input.pressed(KeyCode::KeyD) {
-                        wish_dir -= fwd;
-                    }
-                    if kb_input.pressed(KeyCode::KeyF) {
-                        wish_dir += right;
-                    }
-                    if kb_input.pressed(KeyCode::KeyS) {
-                        wish_dir -= right;
-                    }
+            let target_height = if player.crouched {
+                player.crouch_camera_height
+            } else {
+                player.stand_camera_height
+            };
+            let max_step = CROUCH_CAMERA_SPEED * time.delta_seconds();
+            player.camera_height +=
+                (target_height - player.camera_height).clamp(-max_step, max_step);
+        }
+    }
 
-                    if wish_dir.length() > f32::EPSILON {
-                        wish_dir = wish_dir.normalize();
-                    }
-                    player.wish_dir = wish_dir;
-                    player.wish_jump = kb_input.pressed(KeyCode::Space);
-                    player.wish_duck = kb_input.pressed(KeyCode::ControlLeft);
-
-                    let accel = if player.grounded {
-                        player.accel
-                    } else {
-                        player.air_accel
-                    };
-                    let delta_v = player.wish_dir * accel * time.delta_seconds();
-                    player.velocity += delta_v;
-
-                    if player.grounded && player.wish_jump {
-                        player.velocity.y = player.jump_vel;
-                    }
+    // Moves the player by its PlayerCommand, once per fixed step.
+    fn player_move(
+        mut q_player: Query<(
+            Entity,
+            &mut Player,
+            &mut PlayerCommand,
+            &mut KinematicCharacterController,
+            &mut Transform,
+            &Inventory,
+        )>,
+        time: Res<Time>,
+        mut events: EventWriter<TerrainCellEvent>,
+        mut undo_events: EventWriter<UndoTerrainEdit>,
+        mut redo_events: EventWriter<RedoTerrainEdit>,
+    ) {
+        for (player_id, mut player, mut pending, mut controller, mut player_transform, inventory) in
+            &mut q_player
+        {
+            let command = *pending;
+            pending.toggle_noclip = false;
+            pending.dig = false;
+            pending.place = false;
+            pending.undo = false;
+            pending.redo = false;
+
+            if command.toggle_noclip {
+                player.noclip = !player.noclip;
+            }
+
+            player.yaw = command.yaw;
+            player_transform.rotation = Quat::from_rotation_y(command.yaw.to_radians());
 
-                    let movement = player.velocity * time.delta_seconds();
-                    controller.translation = Some(movement);
+            let fwd: Vec3 = player_transform.forward().into();
+            let right: Vec3 = player_transform.right().into();
+            let up = Vec3::Y;
+            let cam_fwd = player_transform.rotation
+                * Quat::from_rotation_x(command.pitch.to_radians())
+                * Vec3::NEG_Z;
+            let move_input = command.move_input;
+
+            if player.noclip {
+                let delta = player.noclip_speed * time.delta_seconds();
+
+                player_transform.translation +=
+                    (cam_fwd * move_input.x + right * move_input.y) * delta;
+                if command.jump {
+                    player_transform.translation += up * delta;
+                }
+                if command.crouch {
+                    player_transform.translation -= up * delta;
                 }
 
-                if mouse_buttons.just_pressed(MouseButton::Left) {
-                    events.send(TerrainCellEvent {
-                        origin: player_transform.translation + Vec3::Y * player.camera_height,
-                        dir: cam_fwd,
-                        value: 1.0,
-                        shape: TerrainEditShape::Sphere(1.5),
-                        cell_type: None,
-                    });
+                player.velocity = Vect::ZERO;
+            } else if player.swimming {
+                let dt = time.delta_seconds();
+                player.velocity.y += (player.buoyancy * player.submersion - 9.81) * dt;
+
+                // swim where the camera looks
+                let mut wish_dir = cam_fwd * move_input.x + right * move_input.y;
+                if command.jump {
+                    wish_dir += up;
+                }
+                if command.crouch {
+                    wish_dir -= up;
+                }
+                if player.breath <= 0.0 {
+                    // out of breath, head for the surface
+                    wish_dir = up;
+                }
+                player.wish_dir = wish_dir.clamp_length_max(1.0);
+                player.wish_jump = command.jump;
+
+                let delta_v = player.wish_dir * player.swim_accel * dt;
+                player.velocity += delta_v;
+                let drag = player.swim_drag * player.liquid.drag();
+                player.velocity /= 1.0 + drag * dt;
+
+                let movement = player.velocity * dt;
+                controller.translation = Some(movement);
+            } else {
+                player.velocity.y -= 9.81 * time.delta_seconds();
+
+                player.wish_dir = fwd * move_input.x + right * move_input.y;
+                player.wish_jump = command.jump;
+
+                let accel = if player.grounded {
+                    player.accel
+                } else {
+                    player.air_accel
+                };
+                let delta_v = player.wish_dir * accel * time.delta_seconds();
+                player.velocity += delta_v;
+
+                if player.grounded && player.wish_jump {
+                    player.velocity.y = player.jump_vel;
                 }
-                if mouse_buttons.just_pressed(MouseButton::Right) {
+
+                let movement = player.velocity * time.delta_seconds();
+                controller.translation = Some(movement);
+            }
+
+            if command.dig {
+                events.send(TerrainCellEvent {
+                    origin: player_transform.translation + Vec3::Y * player.camera_height,
+                    dir: cam_fwd,
+                    value: 1.0,
+                    shape: TerrainEditShape::Sphere(EDIT_RADIUS),
+                    mode: TerrainEditMode::Subtract,
+                    strength: 1.0,
+                    falloff: 0.5,
+                    material: None,
+                    hit: None,
+                    source: Some(player_id),
+                });
+            }
+            // placing uses up the selected material, the terrain
+            // refuses edits adding more than the stack holds
+            let place_stack = inventory
+                .selected_stack()
+                .filter(|stack| stack.amount >= MIN_PLACE_AMOUNT);
+            if command.place {
+                if let Some(stack) = place_stack {
+                    // a full strength edit adds about the volume of the
+                    // sphere, smaller stacks place a thinner layer
+                    let volume = 4.0 / 3.0 * PI * EDIT_RADIUS.powi(3);
                     events.send(TerrainCellEvent {
                         origin: player_transform.translation + Vec3::Y * player.camera_height,
                         dir: cam_fwd,
                         value: 0.0,
-                        shape: TerrainEditShape::Sphere(1.5),
-                        cell_type: Some(CellType::Stone),
+                        shape: TerrainEditShape::Sphere(EDIT_RADIUS),
+                        mode: TerrainEditMode::Add,
+                        strength: (stack.amount / volume).min(1.0),
+                        falloff: 0.5,
+                        material: Some(stack.material),
+                        hit: None,
+                        source: Some(player_id),
                     });
                 }
             }
+            if command.undo {
+                undo_events.send_default();
+            }
+            if command.redo {
+                redo_events.send_default();
+            }
         }
     }
 
@@ -369,7 +708,9 @@ impl PlayerPlugin {
             }
 
             player.grounded = output.grounded;
-            if player.grounded {
+            if player.swimming {
+                player.velocity = player.velocity.clamp_length_max(player.max_vel_swim);
+            } else if player.grounded {
                 player.velocity.y = 0.0;
 
                 let friction = player.friction * time.delta_seconds();
@@ -390,7 +731,12 @@ impl PlayerPlugin {
                     player.velocity.z += friction;
                 }
 
-                player.velocity = player.velocity.clamp_length_max(player.max_vel_ground);
+                let max_vel = if player.crouched {
+                    player.max_vel_crouch
+                } else {
+                    player.max_vel_ground
+                };
+                player.velocity = player.velocity.clamp_length_max(max_vel);
             } else {
                 let vel_y = player
                     .velocity
@@ -402,12 +748,35 @@ impl PlayerPlugin {
             }
         }
     }
+
+    // Keeps where the player ended up after the last two fixed steps,
+    // see player_input.
+    fn track_fixed_translation(mut q_player: Query<(&mut Player, &Transform)>) {
+        for (mut player, player_transform) in &mut q_player {
+            player.previous_translation = player.fixed_translation;
+            player.fixed_translation = player_transform.translation;
+        }
+    }
 }
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, Self::spawn_player)
-            .add_systems(Update, Self::player_update)
-            .add_systems(Update, Self::player_input);
+        app.add_systems(Startup, (Self::spawn_player, Self::spawn_breath_meter))
+            .add_systems(
+                FixedUpdate,
+                (
+                    (Self::player_liquid, Self::player_crouch, Self::player_move)
+                        .chain()
+                        .in_set(PlayerMoveSet)
+                        .before(PhysicsSet::SyncBackend),
+                    Self::player_update.after(PhysicsSet::Writeback),
+                    Self::track_fixed_translation.after(PhysicsSet::Writeback),
+                ),
+            )
+            // edits made while moving are applied in the same step
+            .configure_sets(FixedUpdate, PlayerMoveSet.before(TerrainEditSet))
+            .add_systems(Update, Self::player_input)
+            .add_systems(Update, Self::hotbar_input)
+            .add_systems(Update, Self::update_breath_meter);
     }
 }
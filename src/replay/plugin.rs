@@ -0,0 +1,334 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::process;
+
+use bevy::app::AppExit;
+use bevy::prelude::*;
+
+use super::recording::{hash_cells, RecordedEdit, RecordedTick, Recording};
+use crate::player::plugin::{Player, PlayerCommand, PlayerMoveSet};
+use crate::terrain::chunk::{Chunk, CHUNK_CUBE_SIZE};
+use crate::terrain::chunk_map::ChunkMap;
+use crate::terrain::history::{RedoTerrainEdit, UndoTerrainEdit};
+use crate::terrain::plugin::{
+    FixedTick, TerrainCellEvent, TerrainEdit, TerrainEditSet, TerrainEditedEvent,
+    TerrainMinedEvent, TerrainPlacedEvent, TerrainSettings,
+};
+
+// Distance from an edit within which chunks are checked,
+// covering the edit shape and any cave-in it causes.
+const EDIT_CHECK_DISTANCE: f32 = CHUNK_CUBE_SIZE as f32;
+// Most fixed steps a tick waits for the chunks around its edits to load.
+const MAX_HELD_TICKS: u32 = 600;
+
+pub enum ReplayMode {
+    // Records the session to a file when the app exits.
+    Record(PathBuf),
+    // Plays a recording back and exits when it ends, reporting chunks
+    // that ended up different and failing if there are any.
+    Replay(Recording),
+}
+
+// Records the player commands and terrain edits of every fixed step,
+// or replays them. Edits are replayed at the points they hit when recorded
+// rather than from the dig and place commands, and each tick waits for the
+// chunks around its edits to load, so they change the same cells even if
+// the player drifts or chunks load at a different pace. The world must be
+// generated from the recorded seed without saved edits for replays to match.
+// Only edits and undos that were applied are recorded, and they are replayed
+// without a source, so they don't depend on the inventory of the player.
+pub struct ReplayPlugin {
+    pub mode: ReplayMode,
+}
+
+#[derive(Resource)]
+struct Recorder {
+    path: PathBuf,
+    recording: Recording,
+    // FixedTick of the first recorded tick.
+    first_tick: Option<u64>,
+    // Chunks near edits, hashed when saving.
+    edited_chunks: HashSet<IVec3>,
+}
+
+#[derive(Resource)]
+struct Replay {
+    recording: Recording,
+    tick: usize,
+    // Fixed steps the current tick has waited for chunks.
+    held_ticks: u32,
+}
+
+impl ReplayPlugin {
+    fn record_tick(
+        mut recorder: ResMut<Recorder>,
+        q_player: Query<&PlayerCommand, With<Player>>,
+        tick: Res<FixedTick>,
+    ) {
+        let Ok(command) = q_player.get_single() else {
+            return;
+        };
+        recorder.first_tick.get_or_insert(tick.0);
+        recorder.recording.ticks.push(RecordedTick {
+            command: *command,
+            edits: Vec::new(),
+        });
+    }
+
+    // Adds the applied edits to the ticks they were applied in.
+    fn record_edits(
+        mut recorder: ResMut<Recorder>,
+        mut edited_events: EventReader<TerrainEditedEvent>,
+    ) {
+        for event in edited_events.read() {
+            let edit = match &event.edit {
+                TerrainEdit::Cell(edit) => RecordedEdit::Cell {
+                    event: edit.clone(),
+                },
+                TerrainEdit::Undo => RecordedEdit::Undo,
+                TerrainEdit::Redo => RecordedEdit::Redo,
+            };
+            let index = recorder
+                .first_tick
+                .and_then(|first_tick| event.tick.checked_sub(first_tick));
+            let tick = index.and_then(|index| recorder.recording.ticks.get_mut(index as usize));
+            match tick {
+                Some(tick) => tick.edits.push(edit),
+                None => warn!("Terrain edit outside the recorded ticks was not recorded"),
+            }
+        }
+    }
+
+    fn track_edited_chunks(
+        mut recorder: ResMut<Recorder>,
+        mut mined_events: EventReader<TerrainMinedEvent>,
+        mut placed_events: EventReader<TerrainPlacedEvent>,
+        chunk_map: Res<ChunkMap>,
+    ) {
+        let edit_positions = mined_events
+            .read()
+            .map(|event| event.position)
+            .chain(placed_events.read().map(|event| event.position));
+        let reach = Vec3::splat(EDIT_CHECK_DISTANCE);
+        for position in edit_positions {
+            for (chunk_pos, _) in chunk_map.get_in_bounds(position - reach, position + reach) {
+                recorder.edited_chunks.insert(chunk_pos);
+            }
+        }
+    }
+
+    fn save_recording(
+        mut exit_events: EventReader<AppExit>,
+        mut recorder: ResMut<Recorder>,
+        chunk_map: Res<ChunkMap>,
+        q_chunks: Query<&Chunk>,
+        settings: Res<TerrainSettings>,
+    ) {
+        if exit_events.read().last().is_none() {
+            return;
+        }
+
+        let mut chunk_hashes: Vec<(IVec3, u64)> = recorder
+            .edited_chunks
+            .iter()
+            .filter_map(|chunk_pos| {
+                let chunk = q_chunks.get(chunk_map.get(*chunk_pos)?).ok()?;
+                return Some((*chunk_pos, hash_cells(&chunk.cells)));
+            })
+            .collect();
+        chunk_hashes.sort_by_key(|(chunk_pos, _)| chunk_pos.to_array());
+
+        recorder.recording.seed = settings.seed();
+        recorder.recording.chunk_hashes = chunk_hashes;
+        match recorder.recording.save(&recorder.path) {
+            Ok(()) => info!(
+                "Recorded {} ticks to {:?}",
+                recorder.recording.ticks.len(),
+                recorder.path
+            ),
+            Err(err) => error!("Failed to save recording {:?}: {}", recorder.path, err),
+        }
+    }
+
+    // Whether the chunks an edit at a point can change are loaded,
+    // edits leave chunks that are still being generated alone.
+    fn are_chunks_loaded(
+        position: Vec3,
+        chunk_map: &ChunkMap,
+        q_chunks: &Query<(), With<Chunk>>,
+    ) -> bool {
+        let reach = Vec3::splat(EDIT_CHECK_DISTANCE);
+        let chunk_size = CHUNK_CUBE_SIZE as f32;
+        let min = ((position - reach) / chunk_size).floor().as_ivec3();
+        let max = ((position + reach) / chunk_size).floor().as_ivec3();
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    let chunk_id = chunk_map.get(IVec3 { x, y, z });
+                    if !chunk_id.is_some_and(|chunk_id| q_chunks.contains(chunk_id)) {
+                        return false;
+                    }
+                }
+            }
+        }
+        return true;
+    }
+
+    fn replay_tick(
+        mut replay: ResMut<Replay>,
+        mut q_player: Query<&mut PlayerCommand, With<Player>>,
+        q_chunks: Query<(), With<Chunk>>,
+        chunk_map: Res<ChunkMap>,
+        mut cell_events: EventWriter<TerrainCellEvent>,
+        mut undo_events: EventWriter<UndoTerrainEdit>,
+        mut redo_events: EventWriter<RedoTerrainEdit>,
+        mut exit_events: EventWriter<AppExit>,
+    ) {
+        let Ok(mut command) = q_player.get_single_mut() else {
+            return;
+        };
+        let Some(tick) = replay.recording.ticks.get(replay.tick).cloned() else {
+            exit_events.send(AppExit);
+            return;
+        };
+
+        let is_ready = tick.edits.iter().all(|edit| match edit {
+            RecordedEdit::Cell { event } => match event.hit {
+                Some(hit) => Self::are_chunks_loaded(hit, &chunk_map, &q_chunks),
+                // older recordings cast a ray when replayed
+                None => true,
+            },
+            RecordedEdit::Undo | RecordedEdit::Redo => true,
+        });
+        if !is_ready {
+            if replay.held_ticks < MAX_HELD_TICKS {
+                replay.held_ticks += 1;
+                // stand still while waiting
+                *command = PlayerCommand {
+                    move_input: Vec2::ZERO,
+                    jump: false,
+                    crouch: false,
+                    toggle_noclip: false,
+                    dig: false,
+                    place: false,
+                    undo: false,
+                    redo: false,
+                    ..*command
+                };
+                return;
+            }
+            warn!(
+                "Chunks around the edits of tick {} didn't load, replaying it anyway",
+                replay.tick
+            );
+        }
+        replay.held_ticks = 0;
+
+        // edits come from the recording instead
+        *command = PlayerCommand {
+            dig: false,
+            place: false,
+            undo: false,
+            redo: false,
+            ..tick.command
+        };
+
+        for edit in &tick.edits {
+            match edit {
+                RecordedEdit::Cell { event } => {
+                    // applied when recorded, the inventory isn't checked again
+                    cell_events.send(TerrainCellEvent {
+                        source: None,
+                        ..event.clone()
+                    });
+                }
+                RecordedEdit::Undo => {
+                    undo_events.send_default();
+                }
+                RecordedEdit::Redo => {
+                    redo_events.send_default();
+                }
+            }
+        }
+
+        replay.tick += 1;
+    }
+
+    // Compares the chunks to the recording once it has been played.
+    fn check_replay(
+        mut exit_events: EventReader<AppExit>,
+        replay: Res<Replay>,
+        chunk_map: Res<ChunkMap>,
+        q_chunks: Query<&Chunk>,
+    ) {
+        if exit_events.read().last().is_none() {
+            return;
+        }
+
+        let mut mismatches = 0;
+        for (chunk_pos, hash) in &replay.recording.chunk_hashes {
+            let chunk = chunk_map
+                .get(*chunk_pos)
+                .and_then(|chunk_id| q_chunks.get(chunk_id).ok());
+            match chunk {
+                Some(chunk) if hash_cells(&chunk.cells) == *hash => {}
+                Some(_) => {
+                    warn!("Chunk {} differs from the recording", chunk_pos);
+                    mismatches += 1;
+                }
+                None => {
+                    warn!(
+                        "Chunk {} was not loaded at the end of the replay",
+                        chunk_pos
+                    );
+                    mismatches += 1;
+                }
+            }
+        }
+
+        info!(
+            "Replayed {} of {} ticks, {} of {} chunks differ",
+            replay.tick,
+            replay.recording.ticks.len(),
+            mismatches,
+            replay.recording.chunk_hashes.len()
+        );
+        // so scripts running replays notice
+        if mismatches > 0 {
+            process::exit(1);
+        }
+    }
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        match &self.mode {
+            ReplayMode::Record(path) => {
+                app.insert_resource(Recorder {
+                    path: path.clone(),
+                    recording: Recording::default(),
+                    first_tick: None,
+                    edited_chunks: HashSet::new(),
+                })
+                .add_systems(
+                    FixedUpdate,
+                    (
+                        Self::record_tick.before(PlayerMoveSet),
+                        Self::record_edits.after(TerrainEditSet),
+                    ),
+                )
+                .add_systems(Update, Self::track_edited_chunks)
+                .add_systems(Last, Self::save_recording);
+            }
+            ReplayMode::Replay(recording) => {
+                app.insert_resource(Replay {
+                    recording: recording.clone(),
+                    tick: 0,
+                    held_ticks: 0,
+                })
+                .add_systems(FixedUpdate, Self::replay_tick.before(PlayerMoveSet))
+                .add_systems(Last, Self::check_replay);
+            }
+        }
+    }
+}
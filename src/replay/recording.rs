@@ -0,0 +1,60 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::player::plugin::PlayerCommand;
+use crate::terrain::chunk::{Cell, CELL_GRID_SIZE_3};
+use crate::terrain::plugin::TerrainCellEvent;
+
+// Terrain edit applied during a tick, see TerrainEdit.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum RecordedEdit {
+    Cell { event: TerrainCellEvent },
+    Undo,
+    Redo,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RecordedTick {
+    // Command the player moved by in this fixed step.
+    pub command: PlayerCommand,
+    #[serde(default)]
+    pub edits: Vec<RecordedEdit>,
+}
+
+// A session recorded for replaying, see ReplayPlugin.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Recording {
+    pub seed: u32,
+    // One per FixedUpdate step.
+    pub ticks: Vec<RecordedTick>,
+    // Hashes of the cells of the chunks around the edits
+    // at the end of the session, to check replays against.
+    pub chunk_hashes: Vec<(IVec3, u64)>,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        return ron::from_str(&text).map_err(|err| err.to_string());
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = ron::to_string(self).map_err(|err| err.to_string())?;
+        return fs::write(path, text).map_err(|err| err.to_string());
+    }
+}
+
+// Hashes the values and materials of chunk cells.
+pub fn hash_cells(cells: &[Cell; CELL_GRID_SIZE_3]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for cell in cells {
+        cell.value.to_bits().hash(&mut hasher);
+        cell.material.hash(&mut hasher);
+    }
+    return hasher.finish();
+}
@@ -0,0 +1,236 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use noise::{NoiseFn, Perlin};
+use serde::Deserialize;
+
+use super::liquid::BiomeLiquid;
+use super::material::{CellMaterialRegistry, MaterialGeneration, MaterialId, DEFAULT_MATERIAL};
+
+// Climate distance over which neighboring biomes blend.
+const BIOME_BLEND: f32 = 0.08;
+
+#[derive(Clone, Debug)]
+pub struct Biome {
+    pub name: String,
+    // Temperature and humidity the biome is found at, 0 to 1.
+    // The biomes closest to the climate at a position are used.
+    pub climate: Vec2,
+    // Frequency of the density noise along each axis,
+    // relative to the terrain noise scale.
+    pub density_scale: Vec3,
+    // Added to the density, positive values make the biome more open.
+    pub density_offset: f32,
+    // Material of cells no palette entry applies to.
+    pub base_material: MaterialId,
+    // Materials generated by terrain type noise, the first match wins.
+    // Uses the generation rules of the material registry if None.
+    pub palette: Option<Vec<(MaterialId, MaterialGeneration)>>,
+    // Liquid the caves of the biome are flooded with.
+    pub liquid: Option<BiomeLiquid>,
+}
+
+impl Biome {
+    // Gets the material generated for a type noise value.
+    pub fn material_at(&self, value: f32, materials: &CellMaterialRegistry) -> MaterialId {
+        let Some(palette) = &self.palette else {
+            return materials.generated_at(value);
+        };
+
+        for (material, rule) in palette {
+            if value >= rule.min && value < rule.max {
+                return *material;
+            }
+        }
+        return self.base_material;
+    }
+}
+
+#[derive(Deserialize)]
+struct BiomeDef {
+    name: String,
+    climate: (f32, f32),
+    #[serde(default = "default_density_scale")]
+    density_scale: (f32, f32, f32),
+    #[serde(default)]
+    density_offset: f32,
+    base_material: String,
+    #[serde(default)]
+    palette: Option<Vec<PaletteDef>>,
+    #[serde(default)]
+    liquid: Option<BiomeLiquid>,
+}
+
+#[derive(Deserialize)]
+struct PaletteDef {
+    material: String,
+    min: f32,
+    max: f32,
+}
+
+#[derive(Deserialize)]
+struct BiomeFile {
+    biomes: Vec<BiomeDef>,
+}
+
+fn default_density_scale() -> (f32, f32, f32) {
+    return (1.0, 1.0, 1.0);
+}
+
+// All biomes and the climate noise choosing between them.
+// Cheap to clone so it can be sent to background tasks.
+#[derive(Clone)]
+pub struct BiomeMap {
+    biomes: Arc<Vec<Biome>>,
+    temperature: Perlin,
+    humidity: Perlin,
+    scale: f64,
+}
+
+impl BiomeMap {
+    pub fn new(biomes: Vec<Biome>, seed: u32, scale: f64) -> Self {
+        assert!(!biomes.is_empty(), "biome map can't be empty");
+        return BiomeMap {
+            biomes: Arc::new(biomes),
+            temperature: Perlin::new(seed.wrapping_add(1)),
+            humidity: Perlin::new(seed.wrapping_add(2)),
+            scale,
+        };
+    }
+
+    // A single biome using the material registry generation rules.
+    pub fn default_biomes(materials: &CellMaterialRegistry) -> Vec<Biome> {
+        return vec![Biome {
+            name: "caves".to_string(),
+            climate: Vec2::splat(0.5),
+            density_scale: Vec3::ONE,
+            density_offset: 0.0,
+            base_material: materials.id_of("stone").unwrap_or(DEFAULT_MATERIAL),
+            palette: None,
+            liquid: None,
+        }];
+    }
+
+    // Loads biomes from a RON file, see assets/biomes.ron.
+    // Materials are referenced by name.
+    pub fn load_biomes(
+        path: &Path,
+        materials: &CellMaterialRegistry,
+    ) -> Result<Vec<Biome>, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let file: BiomeFile = ron::from_str(&text).map_err(|err| err.to_string())?;
+        if file.biomes.is_empty() {
+            return Err("no biomes defined".to_string());
+        }
+
+        let material_id = |name: &str| {
+            return materials
+                .id_of(name)
+                .ok_or_else(|| format!("unknown material {:?}", name));
+        };
+
+        let mut biomes: Vec<Biome> = Vec::new();
+        for def in file.biomes {
+            if biomes.iter().any(|biome| biome.name == def.name) {
+                return Err(format!("duplicate biome {:?}", def.name));
+            }
+
+            let palette = match def.palette {
+                Some(entries) => {
+                    let mut palette = Vec::new();
+                    for entry in entries {
+                        let rule = MaterialGeneration {
+                            min: entry.min,
+                            max: entry.max,
+                        };
+                        palette.push((material_id(&entry.material)?, rule));
+                    }
+                    Some(palette)
+                }
+                None => None,
+            };
+
+            biomes.push(Biome {
+                name: def.name,
+                climate: Vec2::new(def.climate.0, def.climate.1),
+                density_scale: Vec3::new(
+                    def.density_scale.0,
+                    def.density_scale.1,
+                    def.density_scale.2,
+                ),
+                density_offset: def.density_offset,
+                base_material: material_id(&def.base_material)?,
+                palette,
+                liquid: def.liquid,
+            });
+        }
+
+        return Ok(biomes);
+    }
+
+    pub fn get(&self, index: usize) -> &Biome {
+        return &self.biomes[index];
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Biome> {
+        return self.biomes.iter();
+    }
+
+    // Gets the temperature and humidity at a world position.
+    pub fn climate_at(&self, world_pos: Vec3) -> Vec2 {
+        let p = [
+            world_pos.x as f64 * self.scale,
+            world_pos.y as f64 * self.scale,
+            world_pos.z as f64 * self.scale,
+        ];
+        return Vec2 {
+            x: self.temperature.get(p) as f32 * 0.5 + 0.5,
+            y: self.humidity.get(p) as f32 * 0.5 + 0.5,
+        };
+    }
+
+    // Gets the indices and weights of the biomes at a world position.
+    // Weights add up to 1, biomes are only blended near borders.
+    pub fn weights_at(&self, world_pos: Vec3) -> Vec<(usize, f32)> {
+        let climate = self.climate_at(world_pos);
+        let distances: Vec<f32> = self
+            .biomes
+            .iter()
+            .map(|biome| biome.climate.distance(climate))
+            .collect();
+        let nearest = distances.iter().copied().fold(f32::INFINITY, f32::min);
+
+        let mut weights: Vec<(usize, f32)> = distances
+            .iter()
+            .enumerate()
+            .map(|(i, d)| (i, (1.0 - (d - nearest) / BIOME_BLEND).max(0.0)))
+            .filter(|(_, w)| *w > 0.0)
+            .collect();
+
+        let total: f32 = weights.iter().map(|(_, w)| w).sum();
+        for (_, w) in weights.iter_mut() {
+            *w /= total;
+        }
+        return weights;
+    }
+}
+
+// Deterministic value from 0 to 1 for a cell,
+// used to dither materials between blended biomes.
+pub fn cell_hash(pos: IVec3, seed: u32) -> f32 {
+    let mut h = seed
+        .wrapping_mul(0x9E37_79B9)
+        .wrapping_add(pos.x as u32)
+        .wrapping_mul(0x85EB_CA6B)
+        .wrapping_add(pos.y as u32)
+        .wrapping_mul(0xC2B2_AE35)
+        .wrapping_add(pos.z as u32);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7FEB_352D);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846C_A68B);
+    h ^= h >> 16;
+    return h as f32 / u32::MAX as f32;
+}
@@ -0,0 +1,192 @@
+use std::f32::consts::TAU;
+
+use bevy::prelude::*;
+
+use super::chunk::{Cell, Chunk, CELL_GRID_SIZE_3, CHUNK_CUBE_SIZE};
+use super::rng::SplitMix64;
+
+// Size of the regions worms start in.
+const REGION_SIZE: f32 = 96.0;
+// Max number of worms starting in a region.
+const MAX_WORMS_PER_REGION: u32 = 2;
+// Chance of a worm having a branch.
+const BRANCH_CHANCE: f32 = 0.35;
+// Distance between worm points.
+const STEP_LENGTH: f32 = 3.0;
+const MIN_STEPS: u32 = 20;
+const MAX_STEPS: u32 = 40;
+const MIN_RADIUS: f32 = 1.5;
+const MAX_RADIUS: f32 = 4.0;
+// Max pitch of worms, keeps tunnels walkable.
+const MAX_PITCH: f32 = 0.6;
+// Furthest a worm and its branch can get from their start, plus their radius.
+const MAX_REACH: f32 = 2.0 * MAX_STEPS as f32 * STEP_LENGTH + MAX_RADIUS;
+// Salt for the carver random numbers, see SplitMix64::at.
+const CARVER_SALT: u64 = 0xCA4E;
+
+// Segment of a worm, a capsule with a radius changing along its length.
+struct Segment {
+    start: Vec3,
+    end: Vec3,
+    start_radius: f32,
+    end_radius: f32,
+}
+
+impl Segment {
+    fn bounds(&self) -> (Vec3, Vec3) {
+        let radius = self.start_radius.max(self.end_radius);
+        return (
+            self.start.min(self.end) - Vec3::splat(radius),
+            self.start.max(self.end) + Vec3::splat(radius),
+        );
+    }
+
+    // Gets how far inside the segment a position is,
+    // 1 on its center line and 0 or less outside.
+    fn depth(&self, pos: Vec3) -> f32 {
+        let dir = self.end - self.start;
+        let t =
+            ((pos - self.start).dot(dir) / dir.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+        let radius = f32::lerp(self.start_radius, self.end_radius, t);
+        let distance = (pos - (self.start + dir * t)).length();
+        return 1.0 - distance / radius;
+    }
+}
+
+// Carves long connected tunnels through the terrain with worms,
+// random walks seeded per region from the world seed.
+// Each chunk carves the worms of every region close enough to reach it,
+// so tunnels continue across chunk borders.
+#[derive(Clone)]
+pub struct WormCarvers {
+    seed: u32,
+}
+
+impl WormCarvers {
+    pub fn new(seed: u32) -> Self {
+        return WormCarvers { seed };
+    }
+
+    // Opens up the cells of a chunk inside worm tunnels.
+    pub fn carve(&self, chunk_pos: IVec3, cells: &mut [Cell; CELL_GRID_SIZE_3]) {
+        let chunk_min = (chunk_pos * CHUNK_CUBE_SIZE as i32).as_vec3();
+        let chunk_max = chunk_min + Vec3::splat(CHUNK_CUBE_SIZE as f32);
+
+        let segments = self.segments_near(chunk_min, chunk_max);
+        if segments.is_empty() {
+            return;
+        }
+
+        for (index, cell) in cells.iter_mut().enumerate() {
+            let pos = Chunk::cell_index_to_world(chunk_pos, index).as_vec3();
+            let depth = segments
+                .iter()
+                .map(|segment| segment.depth(pos))
+                .fold(0.0, f32::max);
+            if depth > 0.0 {
+                // surface at the tunnel wall, fully open at its center
+                cell.value = cell.value.max(0.5 + depth * 0.5);
+            }
+        }
+    }
+
+    // Gets the worm segments overlapping a box.
+    fn segments_near(&self, min: Vec3, max: Vec3) -> Vec<Segment> {
+        let region_min = ((min - MAX_REACH) / REGION_SIZE).floor().as_ivec3();
+        let region_max = ((max + MAX_REACH) / REGION_SIZE).floor().as_ivec3();
+
+        let mut segments = Vec::new();
+        for x in region_min.x..=region_max.x {
+            for y in region_min.y..=region_max.y {
+                for z in region_min.z..=region_max.z {
+                    self.region_worms(IVec3 { x, y, z }, &mut |segment: Segment| {
+                        let (seg_min, seg_max) = segment.bounds();
+                        if seg_min.cmple(max).all() && seg_max.cmpge(min).all() {
+                            segments.push(segment);
+                        }
+                    });
+                }
+            }
+        }
+        return segments;
+    }
+
+    // Walks the worms starting in a region.
+    fn region_worms(&self, region: IVec3, on_segment: &mut impl FnMut(Segment)) {
+        let mut rng = SplitMix64::at(self.seed, region, CARVER_SALT);
+        let count = (rng.next_f32() * (MAX_WORMS_PER_REGION + 1) as f32) as u32;
+
+        for _ in 0..count {
+            let start = (region.as_vec3()
+                + Vec3::new(rng.next_f32(), rng.next_f32(), rng.next_f32()))
+                * REGION_SIZE;
+            let yaw = rng.range(0.0, TAU);
+            let branch_at = if rng.next_f32() < BRANCH_CHANCE {
+                Some(rng.range(0.3, 0.7))
+            } else {
+                None
+            };
+
+            let branch = Self::walk(start, yaw, branch_at, &mut rng, on_segment);
+            if let Some((branch_start, branch_yaw)) = branch {
+                // branches turn off to the side
+                let side = if rng.next_f32() < 0.5 { -1.0 } else { 1.0 };
+                let yaw = branch_yaw + side * rng.range(0.6, 1.4);
+                Self::walk(branch_start, yaw, None, &mut rng, on_segment);
+            }
+        }
+    }
+
+    // Walks a single worm, returning the position and heading
+    // at the fraction of its length a branch starts at.
+    fn walk(
+        start: Vec3,
+        yaw: f32,
+        branch_at: Option<f32>,
+        rng: &mut SplitMix64,
+        on_segment: &mut impl FnMut(Segment),
+    ) -> Option<(Vec3, f32)> {
+        let steps = MIN_STEPS + (rng.next_f32() * (MAX_STEPS - MIN_STEPS) as f32) as u32;
+        let branch_step = branch_at.map(|t| (t * steps as f32) as u32);
+
+        let mut pos = start;
+        let mut yaw = yaw;
+        let mut pitch = rng.range(-0.3, 0.3);
+        let mut yaw_speed = 0.0;
+        let mut pitch_speed = 0.0;
+        let mut radius = rng.range(MIN_RADIUS, MAX_RADIUS);
+        let mut branch = None;
+
+        for step in 0..steps {
+            if Some(step) == branch_step {
+                branch = Some((pos, yaw));
+            }
+
+            // smooth random turns, pulled back towards level
+            yaw_speed = yaw_speed * 0.8 + rng.range(-0.25, 0.25);
+            pitch_speed = pitch_speed * 0.6 + rng.range(-0.1, 0.1);
+            yaw += yaw_speed;
+            pitch = ((pitch + pitch_speed) * 0.9).clamp(-MAX_PITCH, MAX_PITCH);
+
+            let dir = Vec3::new(
+                pitch.cos() * yaw.cos(),
+                pitch.sin(),
+                pitch.cos() * yaw.sin(),
+            );
+            let next_pos = pos + dir * STEP_LENGTH;
+            let next_radius = (radius + rng.range(-0.3, 0.3)).clamp(MIN_RADIUS, MAX_RADIUS);
+
+            on_segment(Segment {
+                start: pos,
+                end: next_pos,
+                start_radius: radius,
+                end_radius: next_radius,
+            });
+
+            pos = next_pos;
+            radius = next_radius;
+        }
+
+        return branch;
+    }
+}
@@ -1,36 +1,142 @@
+use std::collections::HashMap;
+
 use bevy::{
     prelude::*,
     render::{mesh::Indices, render_asset::RenderAssetUsages},
 };
-use bevy_rapier3d::geometry::Collider;
-use noise::{Fbm, NoiseFn, Perlin};
+use bevy_rapier3d::geometry::{Collider, ComputedColliderShape};
+use noise::NoiseFn;
 
 use super::{
+    biome::cell_hash,
+    density::DensityContext,
     marching_cube::*,
-    plugin::{TerrainCellEvent, TerrainEditShape},
+    material::{CellMaterialRegistry, MaterialId},
+    plugin::{TerrainCellEvent, TerrainEditMode, TerrainEditShape, TerrainSettings},
+    render::{ATTRIBUTE_MATERIAL_IDS, ATTRIBUTE_MATERIAL_WEIGHTS},
+    transition::{is_transition_block, surface_loops, transition_faces, CoarseFaces},
 };
 
 pub const CHUNK_CUBE_SIZE: usize = 16;
 
 const CELL_GRID_SIZE: usize = CHUNK_CUBE_SIZE + 1;
 const CELL_GRID_SIZE_2: usize = CELL_GRID_SIZE * CELL_GRID_SIZE;
-const CELL_GRID_SIZE_3: usize = CELL_GRID_SIZE_2 * CELL_GRID_SIZE;
+pub const CELL_GRID_SIZE_3: usize = CELL_GRID_SIZE_2 * CELL_GRID_SIZE;
+
+// Cells with lower values are inside the surface.
+const ISO_LEVEL: f32 = 0.5;
 
-#[derive(Copy, Clone, Default, Debug)]
-pub enum CellType {
+// Cell grid with a one cell border on each side.
+const PADDED_GRID_SIZE: usize = CELL_GRID_SIZE + 2;
+const PADDED_GRID_SIZE_3: usize = PADDED_GRID_SIZE * PADDED_GRID_SIZE * PADDED_GRID_SIZE;
+
+#[derive(Copy, Clone, Default, Debug, PartialEq)]
+pub enum MeshMode {
+    // Unique vertices per triangle with flat normals.
     #[default]
-    Stone,
-    Granite,
-    Dirt,
-    Iron,
-    Gold,
-    Ruby,
+    Flat,
+    // Shared vertices with normals from the density gradient.
+    Smooth,
 }
 
-#[derive(Copy, Clone, Default)]
+// Gets the position of the chunk containing a world position.
+pub fn world_to_chunk(world_pos: Vec3) -> IVec3 {
+    return (world_pos / CHUNK_CUBE_SIZE as f32).floor().as_ivec3();
+}
+
+// Gets how strongly an edit affects a point, from the signed distance
+// to the edit shape surface (negative inside) and the shape size.
+// Falloff 0 edits the whole shape fully, 1 fades out from the center.
+pub fn falloff_weight(distance: f32, size: f32, falloff: f32) -> f32 {
+    if distance > 0.0 {
+        return 0.0;
+    }
+
+    let width = size * falloff.clamp(0.0, 1.0);
+    if width <= f32::EPSILON {
+        return 1.0;
+    }
+
+    let t = (-distance / width).clamp(0.0, 1.0);
+    return t * t * (3.0 - 2.0 * t);
+}
+
+// Offsets to the chunks sharing a face with a chunk.
+pub const FACE_OFFSETS: [IVec3; 6] = [
+    IVec3::X,
+    IVec3::NEG_X,
+    IVec3::Y,
+    IVec3::NEG_Y,
+    IVec3::Z,
+    IVec3::NEG_Z,
+];
+
+// Rounds a world space bounding box to the cells it covers.
+pub fn bounds_to_cells(min: Vec3, max: Vec3) -> (IVec3, IVec3) {
+    return (min.round().as_ivec3(), max.round().as_ivec3());
+}
+
+// Gets the positions of all chunks containing cells
+// within a world space bounding box.
+// Cells on chunk borders are shared, so both chunks are included.
+pub fn chunks_in_bounds(min: Vec3, max: Vec3) -> impl Iterator<Item = IVec3> {
+    let (cell_min, cell_max) = bounds_to_cells(min, max);
+    let size = IVec3::splat(CHUNK_CUBE_SIZE as i32);
+    let chunk_min = (cell_min - IVec3::ONE).div_euclid(size);
+    let chunk_max = cell_max.div_euclid(size);
+
+    return (chunk_min.x..=chunk_max.x).flat_map(move |x| {
+        return (chunk_min.y..=chunk_max.y).flat_map(move |y| {
+            return (chunk_min.z..=chunk_max.z).map(move |z| IVec3 { x, y, z });
+        });
+    });
+}
+
+#[derive(Copy, Clone, Default, PartialEq)]
 pub struct Cell {
     pub value: f32,
-    pub cell_type: CellType,
+    pub material: MaterialId,
+}
+
+impl Cell {
+    // How much of the cell is filled with material, 0 to 1.
+    pub fn solidity(&self) -> f32 {
+        return 1.0 - self.value.clamp(0.0, 1.0);
+    }
+}
+
+// A cell changed by an edit.
+#[derive(Copy, Clone)]
+pub struct CellChange {
+    pub index: usize,
+    pub before: Cell,
+    pub after: Cell,
+}
+
+// Coarsest level of detail, sampling every 2^MAX_LOD cells.
+pub const MAX_LOD: u32 = 3;
+
+// Everything needed to polygonize a chunk on a background task.
+pub struct ChunkMeshInput {
+    pub position: IVec3,
+    pub cells: Box<[Cell; CELL_GRID_SIZE_3]>,
+    // Builds an indexed, smooth shaded mesh if given (see padded_values),
+    // otherwise a flat shaded one.
+    pub padded_values: Option<Vec<f32>>,
+    // Level of detail of the chunk, meshed every 2^lod cells.
+    pub lod: u32,
+    // Level of detail of the neighbors, in get_face_neighbors order,
+    // at most one level coarser than the chunk.
+    pub neighbor_lods: [u32; 6],
+    // Builds a collider for the mesh, only chunks at full
+    // detail get one so physics doesn't depend on the detail.
+    pub collider: bool,
+}
+
+// Result of polygonizing a chunk.
+pub struct ChunkMesh {
+    pub mesh: Option<Mesh>,
+    pub collider: Option<Collider>,
 }
 
 #[derive(Component)]
@@ -38,37 +144,39 @@ pub struct Chunk {
     pub position: IVec3,
     pub cells: [Cell; CELL_GRID_SIZE_3],
     pub is_dirty: bool,
+    // Edited since generated or loaded, needs saving.
+    pub is_modified: bool,
     pub should_destroy: bool,
-    pub mesh: Option<Mesh>,
+    // Level of detail used for meshing, see ChunkMeshInput.
+    pub lod: u32,
     pub mesh_handle: Option<Handle<Mesh>>,
-    pub material_handle: Option<Handle<StandardMaterial>>,
     pub collider: Option<Collider>,
 }
 
 impl Chunk {
-    pub fn new(
-        fbm: &Fbm<Perlin>,
-        cell_noise_scale: f64,
-        type_noise: Perlin,
-        type_noise_scale: f64,
-        x: i32,
-        y: i32,
-        z: i32,
-    ) -> Self {
-        let mut chunk = Chunk {
-            position: IVec3 { x, y, z },
-            cells: [Cell::default(); CELL_GRID_SIZE_3],
+    pub fn new(settings: &TerrainSettings, x: i32, y: i32, z: i32) -> Self {
+        let mut chunk = Self::from_cells(
+            IVec3 { x, y, z },
+            Box::new([Cell::default(); CELL_GRID_SIZE_3]),
+        );
+
+        chunk.generate_noise(settings);
+
+        return chunk;
+    }
+
+    // Creates a chunk from previously saved cells.
+    pub fn from_cells(position: IVec3, cells: Box<[Cell; CELL_GRID_SIZE_3]>) -> Self {
+        return Chunk {
+            position,
+            cells: *cells,
             is_dirty: true,
+            is_modified: false,
             should_destroy: false,
-            mesh: None,
+            lod: 0,
             mesh_handle: None,
-            material_handle: None,
             collider: None,
         };
-
-        chunk.generate_noise(fbm, cell_noise_scale, type_noise, type_noise_scale);
-
-        return chunk;
     }
 
     pub fn is_in_chunk(&self, world_pos: Vec3) -> bool {
@@ -85,148 +193,231 @@ impl Chunk {
         return true;
     }
 
-    pub fn edit(&mut self, end_pos: Vec3, event: &TerrainCellEvent) {
-        let mut cells_to_edit = Vec::new();
-
-        match event.shape {
-            TerrainEditShape::Sphere(radius) => {
-                let min = end_pos - radius;
-                let max = end_pos + radius;
+    // Edits the cells of the chunk covered by an edit event,
+    // returning the cells that changed.
+    // Smoothing averages the values get_original gives for world cells,
+    // which should be from before any chunk was edited so the cells on
+    // chunk borders get the same value in every chunk.
+    pub fn edit(
+        &mut self,
+        end_pos: Vec3,
+        event: &TerrainCellEvent,
+        materials: &CellMaterialRegistry,
+        get_original: impl Fn(IVec3) -> Option<f32>,
+    ) -> Vec<CellChange> {
+        let mut changes = Vec::new();
+        let (min, max) = event.shape.bounds(end_pos, event.dir);
+        let (mut int_min, mut int_max) = bounds_to_cells(min, max);
+
+        let chunk_min = self.position * CHUNK_CUBE_SIZE as i32; // inclusive
+        let chunk_max = chunk_min + IVec3::ONE * CHUNK_CUBE_SIZE as i32; // inclusive
+
+        if int_min.x > chunk_max.x || int_min.y > chunk_max.y || int_min.z > chunk_max.z {
+            return changes;
+        }
+        if int_max.x < chunk_min.x || int_max.y < chunk_min.y || int_max.z < chunk_min.z {
+            return changes;
+        }
 
-                let mut int_min = IVec3 {
-                    x: min.x.round() as i32,
-                    y: min.y.round() as i32,
-                    z: min.z.round() as i32,
-                };
-                let mut int_max = IVec3 {
-                    x: max.x.round() as i32,
-                    y: max.y.round() as i32,
-                    z: max.z.round() as i32,
-                };
+        int_min = int_min.clamp(chunk_min, chunk_max);
+        int_max = int_max.clamp(chunk_min, chunk_max);
+
+        let shape_size = event.shape.size();
+        let to_shape = TerrainEditShape::rotation(event.dir).inverse();
+        // plane through the hit point facing the editor
+        let flatten_normal = -event.dir.normalize_or_zero();
+
+        for x in int_min.x..(int_max.x + 1) {
+            for y in int_min.y..(int_max.y + 1) {
+                for z in int_min.z..(int_max.z + 1) {
+                    let world_pos = IVec3 { x, y, z };
+                    let local_pos = world_pos.as_vec3() - end_pos;
+                    let distance = event.shape.distance(to_shape * local_pos);
+                    let weight = falloff_weight(distance, shape_size, event.falloff)
+                        * event.strength.clamp(0.0, 1.0);
+                    if weight <= 0.0 {
+                        continue;
+                    }
 
-                let chunk_min = self.position * CHUNK_CUBE_SIZE as i32; // inclusive
-                let chunk_max = chunk_min + IVec3::ONE * CHUNK_CUBE_SIZE as i32; // inclusive
+                    let cell_pos = self.world_to_cell(world_pos);
+                    let index = Self::ivec_to_index(cell_pos);
+                    let before = self.cells[index];
+                    let old_value = before.value;
+
+                    // low values are solid, high values empty
+                    let new_value = match event.mode {
+                        TerrainEditMode::Add => old_value - weight,
+                        TerrainEditMode::Subtract => {
+                            // harder materials take longer to dig
+                            let hardness = materials.get(before.material).hardness;
+                            old_value + weight / hardness.max(f32::EPSILON)
+                        }
+                        TerrainEditMode::Set => f32::lerp(old_value, event.value, weight),
+                        TerrainEditMode::Smooth => {
+                            let average = Self::neighbor_average(world_pos, &get_original)
+                                .unwrap_or(old_value);
+                            f32::lerp(old_value, average, weight)
+                        }
+                        TerrainEditMode::Flatten => {
+                            // iso surface on the plane, empty above it
+                            let height = local_pos.dot(flatten_normal);
+                            let target = (0.5 + height * 0.5).clamp(0.0, 1.0);
+                            f32::lerp(old_value, target, weight)
+                        }
+                    }
+                    .clamp(0.0, 1.0);
 
-                if int_min.x > chunk_max.x || int_min.y > chunk_max.y || int_min.z > chunk_max.z {
-                    return;
-                }
-                if int_max.x < chunk_min.x || int_max.y < chunk_min.y || int_max.z < chunk_min.z {
-                    return;
-                }
+                    if f32::abs(new_value - old_value) < f32::EPSILON {
+                        continue;
+                    }
 
-                int_min = int_min.clamp(chunk_min, chunk_max);
-                int_max = int_max.clamp(chunk_min, chunk_max);
-
-                for x in int_min.x..(int_max.x + 1) {
-                    for y in int_min.y..(int_max.y + 1) {
-                        for z in int_min.z..(int_max.z + 1) {
-                            let cell_pos = self.world_to_cell(IVec3 { x, y, z });
-                            let index = Self::cell_to_index(
-                                cell_pos.x as usize,
-                                cell_pos.y as usize,
-                                cell_pos.z as usize,
-                            );
-                            cells_to_edit.push(index);
+                    self.cells[index].value = new_value;
+                    // only recolor cells material was added to
+                    if let Some(material) = event.material {
+                        if new_value < old_value {
+                            self.cells[index].material = material;
                         }
                     }
+                    self.is_dirty = true;
+                    self.is_modified = true;
+
+                    changes.push(CellChange {
+                        index,
+                        before,
+                        after: self.cells[index],
+                    });
                 }
             }
         }
 
-        for index in cells_to_edit {
-            self.cells[index].value = event.value;
-            if let Some(t) = event.cell_type {
-                self.cells[index].cell_type = t;
-            }
-            self.is_dirty = true;
-        }
+        return changes;
     }
 
-    // Gets all neighboring chunk positions,
-    // including diagonals.
-    pub fn get_neighbors(&self) -> [IVec3; 26] {
-        let mut neighbors = [self.position; 26];
-        let mut i = 0;
-        for x in -1..2 {
-            for y in -1..2 {
-                for z in -1..2 {
-                    if x == 0 && y == 0 && z == 0 {
-                        continue;
-                    }
-                    neighbors[i].x += x;
-                    neighbors[i].y += y;
-                    neighbors[i].z += z;
-                    i += 1;
-                }
-            }
-        }
-        return neighbors;
+    // Sets changed cells back to their values before the change,
+    // or forward to their values after it.
+    pub fn apply_changes(&mut self, changes: &[CellChange], revert: bool) {
+        Self::apply_changes_to_cells(&mut self.cells, changes, revert);
+        self.is_dirty = true;
+        self.is_modified = true;
     }
 
-    fn generate_noise(
-        &mut self,
-        fbm: &Fbm<Perlin>,
-        cell_noise_scale: f64,
-        type_noise: Perlin,
-        type_noise_scale: f64,
+    pub fn apply_changes_to_cells(
+        cells: &mut [Cell; CELL_GRID_SIZE_3],
+        changes: &[CellChange],
+        revert: bool,
     ) {
-        for cell_x in 0..CELL_GRID_SIZE {
-            for cell_y in 0..CELL_GRID_SIZE {
-                for cell_z in 0..CELL_GRID_SIZE {
-                    let index = Self::cell_to_index(cell_x, cell_y, cell_z);
-                    let cell_world = self.cell_to_world(cell_x, cell_y, cell_z);
-                    let cell_world_f = [
-                        cell_world.x as f64 * cell_noise_scale,
-                        cell_world.y as f64 * cell_noise_scale,
-                        cell_world.z as f64 * cell_noise_scale,
-                    ];
-                    let type_world_f = [
-                        cell_world.x as f64 * type_noise_scale,
-                        cell_world.y as f64 * type_noise_scale,
-                        cell_world.z as f64 * type_noise_scale,
-                    ];
-                    self.cells[index].value = (fbm.get(cell_world_f) as f32) * 0.5 + 0.5;
-                    self.cells[index].cell_type =
-                        Self::get_cell_type(type_noise.get(type_world_f) as f32 * 0.5 + 0.5);
-                }
-            }
+        for change in changes {
+            cells[change.index] = if revert { change.before } else { change.after };
         }
     }
 
-    // TODO: just pass cell type to shader and have a list of colors, etc.
-    fn get_cell_color(cell_type: CellType) -> Vec4 {
-        match cell_type {
-            CellType::Stone => Vec4::new(0.4, 0.4, 0.4, 1.0),
-            CellType::Granite => Vec4::new(1.0, 1.0, 1.0, 1.0),
-            CellType::Dirt => Vec4::new(0.3, 0.15, 0.1, 1.0),
-            CellType::Iron => Vec4::new(0.6, 0.3, 0.0, 1.0),
-            CellType::Gold => Vec4::new(1.0, 0.8, 0.1, 1.0),
-            CellType::Ruby => Vec4::new(1.0, 0.0, 0.0, 1.0),
+    // Opens up the cells at world cell positions, keeping their materials.
+    // Positions outside the chunk are ignored.
+    pub fn clear_cells(&mut self, world_cells: &[IVec3]) -> Vec<CellChange> {
+        let mut changes = Vec::new();
+        for world_cell in world_cells {
+            let Some(index) = self.world_cell_index(*world_cell) else {
+                continue;
+            };
+            let before = self.cells[index];
+            self.cells[index].value = 1.0;
+            changes.push(CellChange {
+                index,
+                before,
+                after: self.cells[index],
+            });
         }
-    }
 
-    fn get_cell_type(value: f32) -> CellType {
-        if value < 0.2 {
-            return CellType::Dirt;
+        if !changes.is_empty() {
+            self.is_dirty = true;
+            self.is_modified = true;
         }
+        return changes;
+    }
 
-        if value > 0.3 && value < 0.4 {
-            return CellType::Granite;
-        }
+    // Gets the cell at a world cell position, if it is in the chunk.
+    pub fn get_cell(&self, world_cell: IVec3) -> Option<Cell> {
+        return self
+            .world_cell_index(world_cell)
+            .map(|index| self.cells[index]);
+    }
 
-        if value > 0.6 && value < 0.64 {
-            return CellType::Iron;
+    fn world_cell_index(&self, world_cell: IVec3) -> Option<usize> {
+        let cell = self.world_to_cell(world_cell);
+        let size = CHUNK_CUBE_SIZE as i32;
+        if cell.min_element() < 0 || cell.max_element() > size {
+            return None;
         }
+        return Some(Self::ivec_to_index(cell));
+    }
 
-        if value > 0.8 && value < 0.82 {
-            return CellType::Gold;
+    // Gets the average value of the neighbors of a world cell,
+    // None if none of them are loaded.
+    fn neighbor_average(
+        world_cell: IVec3,
+        get_value: impl Fn(IVec3) -> Option<f32>,
+    ) -> Option<f32> {
+        let values: Vec<f32> = FACE_OFFSETS
+            .iter()
+            .filter_map(|offset| get_value(world_cell + *offset))
+            .collect();
+
+        if values.is_empty() {
+            return None;
         }
+        return Some(values.iter().sum::<f32>() / values.len() as f32);
+    }
 
-        if value > 0.9 && value < 0.91 {
-            return CellType::Ruby;
+    // Gets the positions of the chunks sharing a face with this one.
+    pub fn get_face_neighbors(&self) -> [IVec3; 6] {
+        return FACE_OFFSETS.map(|offset| self.position + offset);
+    }
+
+    fn generate_noise(&mut self, settings: &TerrainSettings) {
+        let scale = settings.fbm_scale;
+        let type_scale = settings.type_noise_scale;
+
+        for cell_x in 0..CELL_GRID_SIZE {
+            for cell_y in 0..CELL_GRID_SIZE {
+                for cell_z in 0..CELL_GRID_SIZE {
+                    let index = Self::cell_to_index(cell_x, cell_y, cell_z);
+                    let cell_world = self.cell_to_world(cell_x, cell_y, cell_z);
+                    let weights = settings.biomes.weights_at(cell_world.as_vec3());
+
+                    let context = DensityContext {
+                        biomes: &settings.biomes,
+                        biome_weights: &weights,
+                        biome_noise: &settings.fbm,
+                        biome_noise_scale: scale,
+                    };
+                    let value = settings.density.sample(cell_world.as_vec3(), &context);
+
+                    // materials dithered between the biomes
+                    let dither = cell_hash(cell_world, settings.seed);
+                    let mut biome_index = weights[0].0;
+                    let mut cumulative = 0.0;
+                    for (i, weight) in &weights {
+                        cumulative += weight;
+                        biome_index = *i;
+                        if dither < cumulative {
+                            break;
+                        }
+                    }
+
+                    let type_world_f = (cell_world.as_dvec3() * type_scale).to_array();
+                    let type_value = settings.type_noise.get(type_world_f) as f32 * 0.5 + 0.5;
+
+                    self.cells[index].value = value.clamp(0.0, 1.0);
+                    self.cells[index].material = settings
+                        .biomes
+                        .get(biome_index)
+                        .material_at(type_value, &settings.materials);
+                }
+            }
         }
 
-        return CellType::Stone;
+        settings.carvers.carve(self.position, &mut self.cells);
+        settings.ores.place(self.position, &mut self.cells);
     }
 
     fn cell_to_world(&self, cell_x: usize, cell_y: usize, cell_z: usize) -> IVec3 {
@@ -242,16 +433,29 @@ impl Chunk {
         return world_pos - self.position * CHUNK_CUBE_SIZE as i32;
     }
 
-    fn cell_index_to_world(&self, index: usize) -> IVec3 {
+    pub(super) fn cell_index_to_world(position: IVec3, index: usize) -> IVec3 {
         let cells = Self::index_to_cell(index);
-        return self.cell_to_world(cells[0], cells[1], cells[2]);
+        return position * CHUNK_CUBE_SIZE as i32
+            + IVec3 {
+                x: cells[0] as i32,
+                y: cells[1] as i32,
+                z: cells[2] as i32,
+            };
     }
 
     fn cell_to_index(cell_x: usize, cell_y: usize, cell_z: usize) -> usize {
         return cell_x * CELL_GRID_SIZE_2 + cell_y * CELL_GRID_SIZE + cell_z;
     }
 
-    fn index_to_cell(index: usize) -> [usize; 3] {
+    pub(super) fn ivec_to_index(cell: IVec3) -> usize {
+        return Self::cell_to_index(cell.x as usize, cell.y as usize, cell.z as usize);
+    }
+
+    fn padded_to_index(x: usize, y: usize, z: usize) -> usize {
+        return x * PADDED_GRID_SIZE * PADDED_GRID_SIZE + y * PADDED_GRID_SIZE + z;
+    }
+
+    pub(super) fn index_to_cell(index: usize) -> [usize; 3] {
         let mut coords: [usize; 3] = [0; 3];
         coords[2] = index % CELL_GRID_SIZE;
         coords[1] = ((index - coords[2]) % CELL_GRID_SIZE_2) / CELL_GRID_SIZE;
@@ -259,206 +463,533 @@ impl Chunk {
         return coords;
     }
 
-    fn cube_to_cell_indices(cube_x: usize, cube_y: usize, cube_z: usize) -> [usize; 8] {
+    // Gets the corner cells of a cube spanning step cells.
+    fn cube_to_cell_indices(
+        cube_x: usize,
+        cube_y: usize,
+        cube_z: usize,
+        step: usize,
+    ) -> [usize; 8] {
+        let s = step;
         return [
             // bottom
             Self::cell_to_index(cube_x, cube_y, cube_z),
-            Self::cell_to_index(cube_x + 1, cube_y, cube_z),
-            Self::cell_to_index(cube_x + 1, cube_y, cube_z + 1),
-            Self::cell_to_index(cube_x, cube_y, cube_z + 1),
+            Self::cell_to_index(cube_x + s, cube_y, cube_z),
+            Self::cell_to_index(cube_x + s, cube_y, cube_z + s),
+            Self::cell_to_index(cube_x, cube_y, cube_z + s),
             // top
-            Self::cell_to_index(cube_x, cube_y + 1, cube_z),
-            Self::cell_to_index(cube_x + 1, cube_y + 1, cube_z),
-            Self::cell_to_index(cube_x + 1, cube_y + 1, cube_z + 1),
-            Self::cell_to_index(cube_x, cube_y + 1, cube_z + 1),
+            Self::cell_to_index(cube_x, cube_y + s, cube_z),
+            Self::cell_to_index(cube_x + s, cube_y + s, cube_z),
+            Self::cell_to_index(cube_x + s, cube_y + s, cube_z + s),
+            Self::cell_to_index(cube_x, cube_y + s, cube_z + s),
         ];
     }
 
-    pub fn polygonize(&mut self) -> Option<Mesh> {
-        self.is_dirty = false;
-        let mut mesh_verts = Vec::new();
-        let mut mesh_colors: Vec<Vec4> = Vec::new();
-
-        for cube_x in 0..CHUNK_CUBE_SIZE {
-            for cube_y in 0..CHUNK_CUBE_SIZE {
-                for cube_z in 0..CHUNK_CUBE_SIZE {
-                    let iso_level: f32 = 0.5;
-                    let corner_indices = Self::cube_to_cell_indices(cube_x, cube_y, cube_z);
-                    let corner_cells = corner_indices.map(|i| self.cells[i]);
-
-                    // Determine the index into the edge table, which
-                    // tells us which vertices are inside of the surface.
-                    let mut cube_index = 0;
-                    for i in 0..8 {
-                        if corner_cells[i].value < iso_level {
-                            cube_index |= 1 << i;
-                        }
-                    }
+    // Builds the render mesh and collider for a chunk.
+    // Takes a copy of the cells instead of the chunk
+    // so it can run on a background task.
+    pub fn polygonize(input: ChunkMeshInput) -> ChunkMesh {
+        // neighbor_lods are at most one level coarser
+        let coarse_faces = input.neighbor_lods.map(|nb_lod| nb_lod > input.lod);
+        let mesh = Self::build_mesh(&input, input.lod, coarse_faces);
+
+        let collider = if input.collider {
+            mesh.as_ref().and_then(Self::mesh_collider)
+        } else {
+            None
+        };
 
-                    let edge = MC_EDGE_TABLE[cube_index];
+        return ChunkMesh { mesh, collider };
+    }
 
-                    // Is the cube entirely in/out of the surface?
-                    if edge == 0 {
+    fn mesh_collider(mesh: &Mesh) -> Option<Collider> {
+        return Collider::from_bevy_mesh(mesh, &ComputedColliderShape::TriMesh);
+    }
+
+    // Polygonizes the cells every 2^lod cells. The cubes along faces with
+    // a coarser neighbor are replaced by transition cells matching it.
+    fn build_mesh(input: &ChunkMeshInput, lod: u32, coarse_faces: CoarseFaces) -> Option<Mesh> {
+        let step = 1 << lod.min(MAX_LOD);
+        let has_transitions = coarse_faces.contains(&true);
+        let mut builder =
+            MeshBuilder::new(input.position, &input.cells, input.padded_values.as_deref());
+
+        for cube_x in (0..CHUNK_CUBE_SIZE).step_by(step) {
+            for cube_y in (0..CHUNK_CUBE_SIZE).step_by(step) {
+                for cube_z in (0..CHUNK_CUBE_SIZE).step_by(step) {
+                    let cube = IVec3::new(cube_x as i32, cube_y as i32, cube_z as i32);
+                    let block_size = step as i32 * 2;
+                    let block = cube - cube.rem_euclid(IVec3::splat(block_size));
+                    if has_transitions && is_transition_block(block, step as i32, &coarse_faces) {
                         continue;
                     }
 
-                    let corners = corner_indices
-                        .map(|i| self.cell_index_to_world(i))
-                        .map(|v| Vec3 {
-                            x: v.x as f32,
-                            y: v.y as f32,
-                            z: v.z as f32,
-                        });
-
-                    let mut vertices = [Vec3::default(); 12];
-                    let mut types = [CellType::default(); 12];
-
-                    // Find the vertices where the surface intersects the cube.
-                    if (edge & 1) == 1 {
-                        (vertices[0], types[0]) = mc_interpolate_vertex(
-                            iso_level,
-                            corners[0],
-                            corners[1],
-                            corner_cells[0],
-                            corner_cells[1],
-                        );
-                    }
-                    if (edge & 2) == 2 {
-                        (vertices[1], types[1]) = mc_interpolate_vertex(
-                            iso_level,
-                            corners[1],
-                            corners[2],
-                            corner_cells[1],
-                            corner_cells[2],
-                        );
-                    }
-                    if (edge & 4) == 4 {
-                        (vertices[2], types[2]) = mc_interpolate_vertex(
-                            iso_level,
-                            corners[2],
-                            corners[3],
-                            corner_cells[2],
-                            corner_cells[3],
-                        );
-                    }
-                    if (edge & 8) == 8 {
-                        (vertices[3], types[3]) = mc_interpolate_vertex(
-                            iso_level,
-                            corners[3],
-                            corners[0],
-                            corner_cells[3],
-                            corner_cells[0],
-                        );
-                    }
-                    if (edge & 16) == 16 {
-                        (vertices[4], types[4]) = mc_interpolate_vertex(
-                            iso_level,
-                            corners[4],
-                            corners[5],
-                            corner_cells[4],
-                            corner_cells[5],
-                        );
-                    }
-                    if (edge & 32) == 32 {
-                        (vertices[5], types[5]) = mc_interpolate_vertex(
-                            iso_level,
-                            corners[5],
-                            corners[6],
-                            corner_cells[5],
-                            corner_cells[6],
-                        );
-                    }
-                    if (edge & 64) == 64 {
-                        (vertices[6], types[6]) = mc_interpolate_vertex(
-                            iso_level,
-                            corners[6],
-                            corners[7],
-                            corner_cells[6],
-                            corner_cells[7],
-                        );
-                    }
-                    if (edge & 128) == 128 {
-                        (vertices[7], types[7]) = mc_interpolate_vertex(
-                            iso_level,
-                            corners[7],
-                            corners[4],
-                            corner_cells[7],
-                            corner_cells[4],
-                        );
-                    }
-                    if (edge & 256) == 256 {
-                        (vertices[8], types[8]) = mc_interpolate_vertex(
-                            iso_level,
-                            corners[0],
-                            corners[4],
-                            corner_cells[0],
-                            corner_cells[4],
-                        );
-                    }
-                    if (edge & 512) == 512 {
-                        (vertices[9], types[9]) = mc_interpolate_vertex(
-                            iso_level,
-                            corners[1],
-                            corners[5],
-                            corner_cells[1],
-                            corner_cells[5],
-                        );
-                    }
-                    if (edge & 1024) == 1024 {
-                        (vertices[10], types[10]) = mc_interpolate_vertex(
-                            iso_level,
-                            corners[2],
-                            corners[6],
-                            corner_cells[2],
-                            corner_cells[6],
-                        );
+                    builder.add_cube(Self::cube_to_cell_indices(cube_x, cube_y, cube_z, step));
+                }
+            }
+        }
+
+        if has_transitions {
+            for x in (0..CHUNK_CUBE_SIZE).step_by(step * 2) {
+                for y in (0..CHUNK_CUBE_SIZE).step_by(step * 2) {
+                    for z in (0..CHUNK_CUBE_SIZE).step_by(step * 2) {
+                        let block = IVec3::new(x as i32, y as i32, z as i32);
+                        if is_transition_block(block, step as i32, &coarse_faces) {
+                            builder.add_transition_cell(block, step as i32, &coarse_faces);
+                        }
                     }
-                    if (edge & 2048) == 2048 {
-                        (vertices[11], types[11]) = mc_interpolate_vertex(
-                            iso_level,
-                            corners[3],
-                            corners[7],
-                            corner_cells[3],
-                            corner_cells[7],
-                        );
+                }
+            }
+        }
+
+        return builder.build();
+    }
+
+    // Gets the cell values of the chunk with a one cell border
+    // read from the 6 face neighbors, for computing normals on the chunk edges.
+    // The border is NaN where a neighbor is not loaded.
+    pub fn padded_values<'a>(&self, get_chunk: impl Fn(IVec3) -> Option<&'a Chunk>) -> Vec<f32> {
+        let mut values = vec![f32::NAN; PADDED_GRID_SIZE_3];
+        let size = IVec3::splat(CHUNK_CUBE_SIZE as i32);
+
+        for x in 0..PADDED_GRID_SIZE {
+            for y in 0..PADDED_GRID_SIZE {
+                for z in 0..PADDED_GRID_SIZE {
+                    let cell = IVec3 {
+                        x: x as i32 - 1,
+                        y: y as i32 - 1,
+                        z: z as i32 - 1,
+                    };
+                    let outside = cell.cmplt(IVec3::ZERO) | cell.cmpgt(size);
+
+                    let value = if !outside.any() {
+                        Some(self.cells[Self::ivec_to_index(cell)].value)
+                    } else if outside.bitmask().count_ones() == 1 {
+                        let offset = cell.div_euclid(size);
+                        get_chunk(self.position + offset)
+                            .map(|nb| nb.cells[Self::ivec_to_index(cell.rem_euclid(size))].value)
+                    } else {
+                        // not needed for central differences
+                        None
+                    };
+
+                    if let Some(value) = value {
+                        values[Self::padded_to_index(x, y, z)] = value;
                     }
+                }
+            }
+        }
+
+        return values;
+    }
 
-                    // Create the triangle.
-                    let mut idx = 0;
-                    while MC_TRI_TABLE[cube_index][idx] != -1 {
-                        let i1 = MC_TRI_TABLE[cube_index][idx] as usize;
-                        let i2 = MC_TRI_TABLE[cube_index][idx + 1] as usize;
-                        let i3 = MC_TRI_TABLE[cube_index][idx + 2] as usize;
+    // Gets the density gradient at a cell using central differences,
+    // falling back to one-sided differences where the padding is missing.
+    // Points from solid towards empty space.
+    fn gradient(padded: &[f32], index: usize) -> Vec3 {
+        let [x, y, z] = Self::index_to_cell(index).map(|c| c + 1);
+        let sample = |x: usize, y: usize, z: usize| padded[Self::padded_to_index(x, y, z)];
+        let center = sample(x, y, z);
+
+        let diff = |minus: f32, plus: f32| -> f32 {
+            match (minus.is_nan(), plus.is_nan()) {
+                (false, false) => (plus - minus) * 0.5,
+                (true, false) => plus - center,
+                (false, true) => center - minus,
+                (true, true) => 0.0,
+            }
+        };
 
-                        mesh_verts.push(vertices[i1]);
-                        mesh_verts.push(vertices[i2]);
-                        mesh_verts.push(vertices[i3]);
+        return Vec3 {
+            x: diff(sample(x - 1, y, z), sample(x + 1, y, z)),
+            y: diff(sample(x, y - 1, z), sample(x, y + 1, z)),
+            z: diff(sample(x, y, z - 1), sample(x, y, z + 1)),
+        };
+    }
+}
 
-                        mesh_colors.push(Self::get_cell_color(types[i1]));
-                        mesh_colors.push(Self::get_cell_color(types[i2]));
-                        mesh_colors.push(Self::get_cell_color(types[i3]));
+// A point where the surface crosses the edge between two cells.
+#[derive(Copy, Clone, Default)]
+struct EdgeVertex {
+    position: Vec3,
+    material: MaterialId,
+    // Index in a smooth mesh.
+    id: u32,
+}
 
-                        idx += 3;
-                    }
-                }
+// Collects the vertices and triangles of a chunk mesh.
+struct MeshBuilder<'a> {
+    position: IVec3,
+    cells: &'a [Cell; CELL_GRID_SIZE_3],
+    // Builds a smooth mesh if given, see ChunkMeshInput.
+    padded_values: Option<&'a [f32]>,
+    verts: Vec<Vec3>,
+    material_ids: Vec<[u32; 3]>,
+    material_weights: Vec<[f32; 3]>,
+    normals: Vec<Vec3>,
+    indices: Vec<u32>,
+    // Smooth mesh vertices keyed by the cells of the edge they are on,
+    // so neighboring cubes share them.
+    edge_vertices: HashMap<(usize, usize), u32>,
+}
+
+impl<'a> MeshBuilder<'a> {
+    fn new(
+        position: IVec3,
+        cells: &'a [Cell; CELL_GRID_SIZE_3],
+        padded_values: Option<&'a [f32]>,
+    ) -> Self {
+        return MeshBuilder {
+            position,
+            cells,
+            padded_values,
+            verts: Vec::new(),
+            material_ids: Vec::new(),
+            material_weights: Vec::new(),
+            normals: Vec::new(),
+            indices: Vec::new(),
+            edge_vertices: HashMap::new(),
+        };
+    }
+
+    // Adds the triangles of a marching cube, given its corner cells.
+    fn add_cube(&mut self, corner_indices: [usize; 8]) {
+        let corner_cells = corner_indices.map(|i| self.cells[i]);
+
+        // Determine the index into the edge table, which
+        // tells us which vertices are inside of the surface.
+        let mut cube_index = 0;
+        for i in 0..8 {
+            if corner_cells[i].value < ISO_LEVEL {
+                cube_index |= 1 << i;
+            }
+        }
+
+        let edge = MC_EDGE_TABLE[cube_index];
+
+        // Is the cube entirely in/out of the surface?
+        if edge == 0 {
+            return;
+        }
+
+        // Find the vertices where the surface intersects the cube.
+        let mut vertices = [EdgeVertex::default(); 12];
+        for (i, [c1, c2]) in MC_EDGE_CORNERS.into_iter().enumerate() {
+            if (edge & (1 << i)) != 0 {
+                vertices[i] = self.edge_vertex(corner_indices[c1], corner_indices[c2]);
             }
         }
 
-        if mesh_verts.len() == 0 {
+        // Create the triangle.
+        let mut idx = 0;
+        while MC_TRI_TABLE[cube_index][idx] != -1 {
+            let i1 = MC_TRI_TABLE[cube_index][idx] as usize;
+            let i2 = MC_TRI_TABLE[cube_index][idx + 1] as usize;
+            let i3 = MC_TRI_TABLE[cube_index][idx + 2] as usize;
+            self.add_triangle([vertices[i1], vertices[i2], vertices[i3]]);
+            idx += 3;
+        }
+    }
+
+    // Adds the triangles of the transition cell at min, see transition.rs.
+    fn add_transition_cell(&mut self, min: IVec3, step: i32, coarse_faces: &CoarseFaces) {
+        let cells = self.cells;
+        let is_solid = |cell: IVec3| cells[Chunk::ivec_to_index(cell)].value < ISO_LEVEL;
+        let faces = transition_faces(min, step, coarse_faces);
+
+        for surface_loop in surface_loops(&faces, is_solid) {
+            let mut vertices: Vec<EdgeVertex> = surface_loop
+                .iter()
+                .map(|(solid, empty)| {
+                    return self
+                        .edge_vertex(Chunk::ivec_to_index(*solid), Chunk::ivec_to_index(*empty));
+                })
+                .collect();
+
+            // wind the triangles to face the empty side like the cubes do
+            let mut normal = Vec3::ZERO;
+            for (i, vertex) in vertices.iter().enumerate() {
+                normal += vertex
+                    .position
+                    .cross(vertices[(i + 1) % vertices.len()].position);
+            }
+            let outward: Vec3 = surface_loop
+                .iter()
+                .map(|(solid, empty)| (*empty - *solid).as_vec3())
+                .sum();
+            if normal.dot(outward) < 0.0 {
+                vertices.reverse();
+            }
+
+            for i in 1..vertices.len() - 1 {
+                self.add_triangle([vertices[0], vertices[i], vertices[i + 1]]);
+            }
+        }
+    }
+
+    // Gets the vertex where the surface crosses the edge between two cells,
+    // adding it to a smooth mesh if no cube added it yet.
+    fn edge_vertex(&mut self, index1: usize, index2: usize) -> EdgeVertex {
+        // interpolated in the same direction whichever cube the edge is in
+        let (index1, index2) = (index1.min(index2), index1.max(index2));
+        let p1 = Chunk::cell_index_to_world(self.position, index1).as_vec3();
+        let p2 = Chunk::cell_index_to_world(self.position, index2).as_vec3();
+        let (position, material) =
+            mc_interpolate_vertex(ISO_LEVEL, p1, p2, self.cells[index1], self.cells[index2]);
+        let mut vertex = EdgeVertex {
+            position,
+            material,
+            id: 0,
+        };
+
+        let Some(padded) = self.padded_values else {
+            return vertex;
+        };
+
+        if let Some(id) = self.edge_vertices.get(&(index1, index2)) {
+            vertex.id = *id;
+            return vertex;
+        }
+
+        // Normal from the density gradient, interpolated
+        // along the edge like the vertex position.
+        let t = (position - p1).length() / (p2 - p1).length();
+        let normal = Chunk::gradient(padded, index1)
+            .lerp(Chunk::gradient(padded, index2), t)
+            .normalize_or_zero();
+
+        vertex.id = self.verts.len() as u32;
+        self.edge_vertices.insert((index1, index2), vertex.id);
+        self.verts.push(position);
+        self.normals.push(normal);
+        self.material_ids.push([material as u32; 3]);
+        self.material_weights.push([1.0, 0.0, 0.0]);
+        return vertex;
+    }
+
+    fn add_triangle(&mut self, triangle: [EdgeVertex; 3]) {
+        let tri_ids = triangle.map(|vertex| vertex.material as u32);
+        let single_material = tri_ids[0] == tri_ids[1] && tri_ids[1] == tri_ids[2];
+
+        if self.padded_values.is_some() && single_material {
+            self.indices.extend(triangle.map(|vertex| vertex.id));
+            return;
+        }
+
+        // Triangles between materials get their own vertices,
+        // all with the materials of the triangle and a
+        // barycentric weight the shader blends them by.
+        for (corner, vertex) in triangle.into_iter().enumerate() {
+            if self.padded_values.is_some() {
+                self.indices.push(self.verts.len() as u32);
+                self.normals.push(self.normals[vertex.id as usize]);
+            }
+            let mut weights = [0.0; 3];
+            weights[corner] = 1.0;
+            self.verts.push(vertex.position);
+            self.material_ids.push(tri_ids);
+            self.material_weights.push(weights);
+        }
+    }
+
+    fn build(self) -> Option<Mesh> {
+        if self.verts.is_empty() {
             return None;
         }
 
-        let mesh_indices: Vec<u32> = (0u32..mesh_verts.len() as u32).collect();
-
-        return Some(
-            Mesh::new(
-                bevy::render::mesh::PrimitiveTopology::TriangleList,
-                RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
-            )
-            .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, mesh_verts)
-            .with_inserted_attribute(Mesh::ATTRIBUTE_COLOR, mesh_colors)
-            .with_computed_flat_normals()
-            .with_inserted_indices(Indices::U32(mesh_indices)),
-        );
+        let mut mesh = Mesh::new(
+            bevy::render::mesh::PrimitiveTopology::TriangleList,
+            RenderAssetUsages::MAIN_WORLD | RenderAssetUsages::RENDER_WORLD,
+        )
+        .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, self.verts)
+        .with_inserted_attribute(ATTRIBUTE_MATERIAL_IDS, self.material_ids)
+        .with_inserted_attribute(ATTRIBUTE_MATERIAL_WEIGHTS, self.material_weights);
+
+        if self.padded_values.is_some() {
+            mesh = mesh
+                .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, self.normals)
+                .with_inserted_indices(Indices::U32(self.indices));
+        } else {
+            // flat normals can only be computed for unindexed meshes
+            let vert_count = mesh.count_vertices() as u32;
+            mesh = mesh
+                .with_computed_flat_normals()
+                .with_inserted_indices(Indices::U32((0u32..vert_count).collect()));
+        }
+
+        return Some(mesh);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::mesh::VertexAttributeValues;
+
+    use super::*;
+
+    // Vertex positions are compared at this precision.
+    const POSITION_SCALE: f32 = 4096.0;
+
+    type EdgeKey = [[i64; 3]; 2];
+
+    // Cells with random values, so the surface crosses most edges.
+    fn random_cells(seed: u64) -> Box<[Cell; CELL_GRID_SIZE_3]> {
+        let mut state = seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1;
+        let mut cells = Box::new([Cell::default(); CELL_GRID_SIZE_3]);
+        for cell in cells.iter_mut() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            cell.value = (state % 1000) as f32 / 1000.0 + 0.0005;
+        }
+        return cells;
+    }
+
+    // Counts the triangles using each edge of a mesh.
+    fn edge_counts(mesh: &Mesh) -> HashMap<EdgeKey, usize> {
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            panic!("mesh has no positions");
+        };
+        let key = |index: usize| {
+            return positions[index].map(|coord| (coord * POSITION_SCALE).round() as i64);
+        };
+        let indices: Vec<usize> = mesh.indices().unwrap().iter().collect();
+
+        let mut counts = HashMap::new();
+        for triangle in indices.chunks(3) {
+            for i in 0..3 {
+                let mut edge = [key(triangle[i]), key(triangle[(i + 1) % 3])];
+                edge.sort();
+                *counts.entry(edge).or_insert(0) += 1;
+            }
+        }
+        return counts;
+    }
+
+    // Whether both ends of an edge lie on a face of the chunk,
+    // in get_face_neighbors order.
+    fn is_on_face(edge: &EdgeKey, face: usize) -> bool {
+        let depth = if face % 2 == 0 {
+            CHUNK_CUBE_SIZE as i64
+        } else {
+            0
+        };
+        return edge
+            .iter()
+            .all(|point| point[face / 2] == depth * POSITION_SCALE as i64);
+    }
+
+    // The open edges along a face, where the mesh meets the neighbor's.
+    // Triangles lying flat on the face only add closed edges.
+    fn face_edges(counts: &HashMap<EdgeKey, usize>, face: usize) -> Vec<EdgeKey> {
+        let mut edges: Vec<EdgeKey> = counts
+            .iter()
+            .filter(|(edge, count)| is_on_face(edge, face) && *count % 2 == 1)
+            .map(|(edge, _)| *edge)
+            .collect();
+        edges.sort();
+        return edges;
+    }
+
+    // A chunk across a face of the chunk at the origin, with other cells
+    // but the same ones on the shared face.
+    fn neighbor_input(input: &ChunkMeshInput, face: usize, seed: u64) -> ChunkMeshInput {
+        let mut cells = random_cells(seed);
+        let axis = face / 2;
+        let (depth, neighbor_depth) = if face % 2 == 0 {
+            (CHUNK_CUBE_SIZE as i32, 0)
+        } else {
+            (0, CHUNK_CUBE_SIZE as i32)
+        };
+        for index in 0..CELL_GRID_SIZE_3 {
+            let mut cell = Chunk::cell_index_to_world(IVec3::ZERO, index);
+            if cell[axis] == depth {
+                cell[axis] = neighbor_depth;
+                cells[Chunk::ivec_to_index(cell)] = input.cells[index];
+            }
+        }
+        return ChunkMeshInput {
+            position: FACE_OFFSETS[face],
+            cells,
+            padded_values: None,
+            lod: 0,
+            neighbor_lods: [0; 6],
+            collider: false,
+        };
+    }
+
+    #[test]
+    fn transition_cells_match_neighbors() {
+        for seed in 0..2 {
+            let input = ChunkMeshInput {
+                position: IVec3::ZERO,
+                cells: random_cells(seed),
+                padded_values: None,
+                lod: 0,
+                neighbor_lods: [0; 6],
+                collider: false,
+            };
+            let neighbors: [ChunkMeshInput; 6] = std::array::from_fn(|face| {
+                neighbor_input(&input, face, seed * 6 + face as u64 + 100)
+            });
+
+            for lod in 0..2 {
+                // coarse chunks don't border coarser ones here
+                let coarse_neighbor_edges: [Vec<EdgeKey>; 6] = std::array::from_fn(|face| {
+                    let mesh = Chunk::build_mesh(&neighbors[face], lod + 1, [false; 6]).unwrap();
+                    return face_edges(&edge_counts(&mesh), face);
+                });
+                let mut same_neighbor_edges: HashMap<(usize, CoarseFaces), Vec<EdgeKey>> =
+                    HashMap::new();
+
+                for mask in 0..64 {
+                    let coarse: CoarseFaces = std::array::from_fn(|face| mask & (1 << face) != 0);
+                    let counts = edge_counts(&Chunk::build_mesh(&input, lod, coarse).unwrap());
+
+                    // closed everywhere but on the faces of the chunk
+                    for (edge, count) in &counts {
+                        let on_any_face = (0..6).any(|face| is_on_face(edge, face));
+                        assert!(
+                            on_any_face || count % 2 == 0,
+                            "open edge {:?} with coarse faces {:?} at lod {}",
+                            edge,
+                            coarse,
+                            lod
+                        );
+                    }
+
+                    // the same edges as the neighbor on every face
+                    for face in 0..6 {
+                        let neighbor_edges = if coarse[face] {
+                            &coarse_neighbor_edges[face]
+                        } else {
+                            // the chunks diagonal to this one have the detail
+                            // of the chunks next to them, like the rings around
+                            // a loader give them
+                            let mut neighbor_coarse = coarse;
+                            neighbor_coarse[face] = false;
+                            neighbor_coarse[face ^ 1] = false;
+                            same_neighbor_edges
+                                .entry((face, neighbor_coarse))
+                                .or_insert_with(|| {
+                                    let mesh =
+                                        Chunk::build_mesh(&neighbors[face], lod, neighbor_coarse);
+                                    return face_edges(&edge_counts(&mesh.unwrap()), face);
+                                })
+                        };
+                        assert_eq!(
+                            &face_edges(&counts, face),
+                            neighbor_edges,
+                            "face {} differs with coarse faces {:?} at lod {}",
+                            face,
+                            coarse,
+                            lod
+                        );
+                    }
+                }
+            }
+        }
     }
 }
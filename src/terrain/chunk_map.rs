@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use super::chunk::{chunks_in_bounds, world_to_chunk};
+
+// Maps chunk positions to chunk entities.
+// Chunks still being generated are included,
+// their entities don't have a Chunk component yet.
+#[derive(Resource, Default)]
+pub struct ChunkMap {
+    chunks: HashMap<IVec3, Entity>,
+}
+
+impl ChunkMap {
+    pub fn get(&self, chunk_pos: IVec3) -> Option<Entity> {
+        return self.chunks.get(&chunk_pos).copied();
+    }
+
+    pub fn contains(&self, chunk_pos: IVec3) -> bool {
+        return self.chunks.contains_key(&chunk_pos);
+    }
+
+    // Gets the chunk containing a world position.
+    pub fn get_at_world(&self, world_pos: Vec3) -> Option<Entity> {
+        return self.get(world_to_chunk(world_pos));
+    }
+
+    // Gets all chunks containing cells within a world space bounding box.
+    pub fn get_in_bounds(
+        &self,
+        min: Vec3,
+        max: Vec3,
+    ) -> impl Iterator<Item = (IVec3, Entity)> + '_ {
+        return chunks_in_bounds(min, max)
+            .filter_map(|chunk_pos| self.get(chunk_pos).map(|chunk_id| (chunk_pos, chunk_id)));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (IVec3, Entity)> + '_ {
+        return self
+            .chunks
+            .iter()
+            .map(|(chunk_pos, chunk_id)| (*chunk_pos, *chunk_id));
+    }
+
+    pub(super) fn insert(&mut self, chunk_pos: IVec3, chunk_id: Entity) {
+        self.chunks.insert(chunk_pos, chunk_id);
+    }
+
+    pub(super) fn remove(&mut self, chunk_pos: IVec3) {
+        self.chunks.remove(&chunk_pos);
+    }
+}
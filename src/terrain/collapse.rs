@@ -0,0 +1,166 @@
+use std::collections::{HashSet, VecDeque};
+
+use bevy::prelude::*;
+
+use super::chunk::{Cell, Chunk, ChunkMesh, ChunkMeshInput, CELL_GRID_SIZE_3, CHUNK_CUBE_SIZE};
+
+// Cells with a density below the surface level are solid.
+const SOLID_LEVEL: f32 = 0.5;
+// Max span in cells of a region that can break off.
+// Bigger regions are considered supported, which keeps the search
+// bounded and lets islands be meshed on a single chunk grid
+// with a one cell border.
+const MAX_ISLAND_EXTENT: i32 = CHUNK_CUBE_SIZE as i32 - 2;
+
+const NEIGHBOR_OFFSETS: [IVec3; 6] = [
+    IVec3::X,
+    IVec3::NEG_X,
+    IVec3::Y,
+    IVec3::NEG_Y,
+    IVec3::Z,
+    IVec3::NEG_Z,
+];
+
+// Rock broken off the terrain by a cave-in,
+// falls as a dynamic rigid body and is despawned after a while.
+#[derive(Component)]
+pub struct Debris {
+    pub timer: Timer,
+    pub mesh_handle: Handle<Mesh>,
+}
+
+pub fn is_solid(cell: &Cell) -> bool {
+    return cell.value < SOLID_LEVEL;
+}
+
+// Finds the regions of solid cells around an edited box which are no longer
+// connected to the rest of the terrain, as lists of world cell positions.
+// get_solid is None for cells in chunks that aren't loaded,
+// regions reaching them are treated as supported.
+pub fn find_islands(
+    min: IVec3,
+    max: IVec3,
+    get_solid: impl Fn(IVec3) -> Option<bool>,
+) -> Vec<Vec<IVec3>> {
+    let mut islands = Vec::new();
+    // cells known to be connected to the rest of the terrain,
+    // and ones in the islands found so far
+    let mut supported: HashSet<IVec3> = HashSet::new();
+    let mut in_island: HashSet<IVec3> = HashSet::new();
+
+    // edits can only disconnect the cells next to the ones they changed
+    for x in (min.x - 1)..=(max.x + 1) {
+        for y in (min.y - 1)..=(max.y + 1) {
+            for z in (min.z - 1)..=(max.z + 1) {
+                let start = IVec3 { x, y, z };
+                if supported.contains(&start)
+                    || in_island.contains(&start)
+                    || get_solid(start) != Some(true)
+                {
+                    continue;
+                }
+
+                // a search stopped early hasn't seen all of its region,
+                // so only the cells of finished ones are skipped
+                let mut visited: HashSet<IVec3> = HashSet::from([start]);
+                let mut island = vec![start];
+                let mut queue = VecDeque::from([start]);
+                let (mut island_min, mut island_max) = (start, start);
+                let mut is_supported = false;
+
+                'search: while let Some(cell) = queue.pop_front() {
+                    for offset in NEIGHBOR_OFFSETS {
+                        let nb = cell + offset;
+                        if visited.contains(&nb) {
+                            continue;
+                        }
+                        if supported.contains(&nb) {
+                            is_supported = true;
+                            break 'search;
+                        }
+                        match get_solid(nb) {
+                            None => {
+                                is_supported = true;
+                                break 'search;
+                            }
+                            Some(false) => continue,
+                            Some(true) => {}
+                        }
+
+                        visited.insert(nb);
+                        island.push(nb);
+                        queue.push_back(nb);
+                        island_min = island_min.min(nb);
+                        island_max = island_max.max(nb);
+                        if (island_max - island_min).max_element() > MAX_ISLAND_EXTENT {
+                            is_supported = true;
+                            break 'search;
+                        }
+                    }
+                }
+
+                if is_supported {
+                    supported.extend(island);
+                } else {
+                    in_island.extend(island.iter().copied());
+                    islands.push(island);
+                }
+            }
+        }
+    }
+
+    return islands;
+}
+
+// Builds the mesh of an island, surrounded by open cells.
+// Returns the world position of the mesh origin with the mesh.
+pub fn island_mesh(island: &[IVec3], get_cell: impl Fn(IVec3) -> Cell) -> (IVec3, ChunkMesh) {
+    let island_min = island.iter().copied().fold(IVec3::MAX, IVec3::min);
+    let origin = island_min - IVec3::ONE;
+
+    let mut cells = Box::new(
+        [Cell {
+            value: 1.0,
+            ..default()
+        }; CELL_GRID_SIZE_3],
+    );
+    for world_cell in island {
+        cells[Chunk::ivec_to_index(*world_cell - origin)] = get_cell(*world_cell);
+    }
+
+    let mesh = Chunk::polygonize(ChunkMeshInput {
+        position: IVec3::ZERO,
+        cells,
+        padded_values: None,
+        lod: 0,
+        neighbor_lods: [0; 6],
+        collider: false,
+    });
+    return (origin, mesh);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_islands_next_to_stopped_searches() {
+        let mut solid: HashSet<IVec3> = HashSet::new();
+        // a ledge too long to break off, its search stops
+        // at x = 14 before looking past that cell
+        for x in -1..=20 {
+            solid.insert(IVec3::new(x, -1, -1));
+        }
+        // a pillar standing on the last cell the search reached
+        for y in 0..=2 {
+            solid.insert(IVec3::new(14, y, -1));
+        }
+        // a block floating above the ledge
+        solid.insert(IVec3::new(1, 1, 1));
+
+        let islands = find_islands(IVec3::ZERO, IVec3::new(14, 1, 0), |cell| {
+            return Some(solid.contains(&cell));
+        });
+        assert_eq!(islands, vec![vec![IVec3::new(1, 1, 1)]]);
+    }
+}
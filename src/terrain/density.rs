@@ -0,0 +1,407 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use bevy::math::DVec3;
+use bevy::prelude::*;
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin, RidgedMulti};
+use serde::Deserialize;
+
+use super::biome::BiomeMap;
+
+// Node of a density graph as written in a config file, see assets/density.ron.
+// Density is low inside solid terrain and high in open space,
+// the surface is at 0.5.
+#[derive(Clone, Debug, Deserialize)]
+pub enum DensityNode {
+    Constant(f32),
+    // Fractal noise from about -1 to 1.
+    Noise {
+        // Added to the world seed.
+        #[serde(default)]
+        seed: u32,
+        frequency: f64,
+        // Frequency multiplier along each axis.
+        #[serde(default = "default_stretch")]
+        stretch: (f64, f64, f64),
+        #[serde(default = "default_octaves")]
+        octaves: usize,
+        // Ridged noise peaks along thin sheets, useful for tunnels.
+        #[serde(default)]
+        ridged: bool,
+    },
+    // Cave density of the biomes at the position.
+    Biomes,
+    // Offsets the position its input is sampled at by noise.
+    Warp {
+        input: Box<DensityNode>,
+        #[serde(default)]
+        seed: u32,
+        frequency: f64,
+        strength: f32,
+    },
+    Add(Vec<DensityNode>),
+    Multiply(Vec<DensityNode>),
+    Min(Vec<DensityNode>),
+    Max(Vec<DensityNode>),
+    Abs(Box<DensityNode>),
+    Clamp {
+        input: Box<DensityNode>,
+        min: f32,
+        max: f32,
+    },
+    // Remaps the input through a piecewise linear curve of (input, output) points.
+    Curve {
+        input: Box<DensityNode>,
+        points: Vec<(f32, f32)>,
+    },
+    // Goes linearly from `from` at `from_y` to `to` at `to_y`, clamped outside.
+    YGradient {
+        from_y: f32,
+        to_y: f32,
+        from: f32,
+        to: f32,
+    },
+    // Signed distances, negative inside.
+    Sphere {
+        center: (f32, f32, f32),
+        radius: f32,
+    },
+    Box {
+        center: (f32, f32, f32),
+        half_extents: (f32, f32, f32),
+    },
+    Capsule {
+        start: (f32, f32, f32),
+        end: (f32, f32, f32),
+        radius: f32,
+    },
+}
+
+fn default_stretch() -> (f64, f64, f64) {
+    return (1.0, 1.0, 1.0);
+}
+
+fn default_octaves() -> usize {
+    return 4;
+}
+
+#[derive(Clone)]
+enum NoiseSource {
+    Fbm(Fbm<Perlin>),
+    Ridged(RidgedMulti<Perlin>),
+}
+
+impl NoiseSource {
+    fn new(seed: u32, octaves: usize, ridged: bool) -> Self {
+        if ridged {
+            return NoiseSource::Ridged(RidgedMulti::<Perlin>::new(seed).set_octaves(octaves));
+        }
+        return NoiseSource::Fbm(Fbm::<Perlin>::new(seed).set_octaves(octaves));
+    }
+
+    fn get(&self, pos: DVec3) -> f32 {
+        match self {
+            NoiseSource::Fbm(fbm) => return fbm.get(pos.to_array()) as f32,
+            NoiseSource::Ridged(ridged) => return ridged.get(pos.to_array()) as f32,
+        }
+    }
+}
+
+// Density node with its noise generators created.
+#[derive(Clone)]
+enum Node {
+    Constant(f32),
+    Noise {
+        noise: NoiseSource,
+        scale: DVec3,
+    },
+    Biomes,
+    Warp {
+        input: Box<Node>,
+        noise: [NoiseSource; 3],
+        frequency: f64,
+        strength: f32,
+    },
+    Add(Vec<Node>),
+    Multiply(Vec<Node>),
+    Min(Vec<Node>),
+    Max(Vec<Node>),
+    Abs(Box<Node>),
+    Clamp {
+        input: Box<Node>,
+        min: f32,
+        max: f32,
+    },
+    Curve {
+        input: Box<Node>,
+        points: Vec<(f32, f32)>,
+    },
+    YGradient {
+        from_y: f32,
+        to_y: f32,
+        from: f32,
+        to: f32,
+    },
+    Sphere {
+        center: Vec3,
+        radius: f32,
+    },
+    Box {
+        center: Vec3,
+        half_extents: Vec3,
+    },
+    Capsule {
+        start: Vec3,
+        end: Vec3,
+        radius: f32,
+    },
+}
+
+// Everything a density graph needs besides the position.
+pub struct DensityContext<'a> {
+    pub biomes: &'a BiomeMap,
+    // Biome weights at the position, see BiomeMap::weights_at.
+    pub biome_weights: &'a [(usize, f32)],
+    // Noise and scale of the Biomes node.
+    pub biome_noise: &'a Fbm<Perlin>,
+    pub biome_noise_scale: f64,
+}
+
+// Density function built from DensityNodes.
+// Cheap to clone so it can be sent to background tasks.
+#[derive(Clone)]
+pub struct DensityGraph {
+    root: Arc<Node>,
+}
+
+impl Default for DensityGraph {
+    fn default() -> Self {
+        return Self::new(&DensityNode::Biomes, 0);
+    }
+}
+
+impl DensityGraph {
+    pub fn new(root: &DensityNode, seed: u32) -> Self {
+        return DensityGraph {
+            root: Arc::new(Self::build(root, seed)),
+        };
+    }
+
+    // Loads a density graph from a RON file.
+    pub fn load(path: &Path, seed: u32) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let root: DensityNode = ron::from_str(&text).map_err(|err| err.to_string())?;
+        return Ok(Self::new(&root, seed));
+    }
+
+    pub fn sample(&self, pos: Vec3, context: &DensityContext) -> f32 {
+        return Self::sample_node(&self.root, pos, context);
+    }
+
+    fn build(node: &DensityNode, seed: u32) -> Node {
+        let build_all = |nodes: &Vec<DensityNode>| {
+            return nodes
+                .iter()
+                .map(|node| Self::build(node, seed))
+                .collect::<Vec<Node>>();
+        };
+        let to_vec3 = |v: (f32, f32, f32)| Vec3::new(v.0, v.1, v.2);
+
+        match node {
+            DensityNode::Constant(value) => return Node::Constant(*value),
+            DensityNode::Noise {
+                seed: seed_offset,
+                frequency,
+                stretch,
+                octaves,
+                ridged,
+            } => {
+                return Node::Noise {
+                    noise: NoiseSource::new(seed.wrapping_add(*seed_offset), *octaves, *ridged),
+                    scale: DVec3::new(stretch.0, stretch.1, stretch.2) * *frequency,
+                };
+            }
+            DensityNode::Biomes => return Node::Biomes,
+            DensityNode::Warp {
+                input,
+                seed: seed_offset,
+                frequency,
+                strength,
+            } => {
+                let warp_seed = seed.wrapping_add(*seed_offset);
+                return Node::Warp {
+                    input: Box::new(Self::build(input, seed)),
+                    noise: [0, 1, 2].map(|i| NoiseSource::new(warp_seed.wrapping_add(i), 2, false)),
+                    frequency: *frequency,
+                    strength: *strength,
+                };
+            }
+            DensityNode::Add(nodes) => return Node::Add(build_all(nodes)),
+            DensityNode::Multiply(nodes) => return Node::Multiply(build_all(nodes)),
+            DensityNode::Min(nodes) => return Node::Min(build_all(nodes)),
+            DensityNode::Max(nodes) => return Node::Max(build_all(nodes)),
+            DensityNode::Abs(input) => return Node::Abs(Box::new(Self::build(input, seed))),
+            DensityNode::Clamp { input, min, max } => {
+                return Node::Clamp {
+                    input: Box::new(Self::build(input, seed)),
+                    min: *min,
+                    max: *max,
+                };
+            }
+            DensityNode::Curve { input, points } => {
+                let mut points = points.clone();
+                points.sort_by(|a, b| a.0.total_cmp(&b.0));
+                return Node::Curve {
+                    input: Box::new(Self::build(input, seed)),
+                    points,
+                };
+            }
+            DensityNode::YGradient {
+                from_y,
+                to_y,
+                from,
+                to,
+            } => {
+                return Node::YGradient {
+                    from_y: *from_y,
+                    to_y: *to_y,
+                    from: *from,
+                    to: *to,
+                };
+            }
+            DensityNode::Sphere { center, radius } => {
+                return Node::Sphere {
+                    center: to_vec3(*center),
+                    radius: *radius,
+                };
+            }
+            DensityNode::Box {
+                center,
+                half_extents,
+            } => {
+                return Node::Box {
+                    center: to_vec3(*center),
+                    half_extents: to_vec3(*half_extents),
+                };
+            }
+            DensityNode::Capsule { start, end, radius } => {
+                return Node::Capsule {
+                    start: to_vec3(*start),
+                    end: to_vec3(*end),
+                    radius: *radius,
+                };
+            }
+        }
+    }
+
+    fn sample_node(node: &Node, pos: Vec3, context: &DensityContext) -> f32 {
+        match node {
+            Node::Constant(value) => return *value,
+            Node::Noise { noise, scale } => return noise.get(pos.as_dvec3() * *scale),
+            Node::Biomes => {
+                let mut value = 0.0;
+                for (biome_index, weight) in context.biome_weights {
+                    let biome = context.biomes.get(*biome_index);
+                    let scale = biome.density_scale.as_dvec3() * context.biome_noise_scale;
+                    let noise_pos = (pos.as_dvec3() * scale).to_array();
+                    let density = context.biome_noise.get(noise_pos) as f32 * 0.5 + 0.5;
+                    value += (density + biome.density_offset) * weight;
+                }
+                return value;
+            }
+            Node::Warp {
+                input,
+                noise,
+                frequency,
+                strength,
+            } => {
+                let noise_pos = pos.as_dvec3() * *frequency;
+                let offset = Vec3::new(
+                    noise[0].get(noise_pos),
+                    noise[1].get(noise_pos),
+                    noise[2].get(noise_pos),
+                );
+                return Self::sample_node(input, pos + offset * *strength, context);
+            }
+            Node::Add(nodes) => return Self::sample_all(nodes, pos, context).sum(),
+            Node::Multiply(nodes) => return Self::sample_all(nodes, pos, context).product(),
+            Node::Min(nodes) => {
+                return Self::sample_all(nodes, pos, context).fold(f32::INFINITY, f32::min);
+            }
+            Node::Max(nodes) => {
+                return Self::sample_all(nodes, pos, context).fold(f32::NEG_INFINITY, f32::max);
+            }
+            Node::Abs(input) => return Self::sample_node(input, pos, context).abs(),
+            Node::Clamp { input, min, max } => {
+                return Self::sample_node(input, pos, context).clamp(*min, *max);
+            }
+            Node::Curve { input, points } => {
+                let value = Self::sample_node(input, pos, context);
+                return Self::remap(points, value);
+            }
+            Node::YGradient {
+                from_y,
+                to_y,
+                from,
+                to,
+            } => {
+                if (to_y - from_y).abs() < f32::EPSILON {
+                    return *from;
+                }
+                let t = ((pos.y - from_y) / (to_y - from_y)).clamp(0.0, 1.0);
+                return f32::lerp(*from, *to, t);
+            }
+            Node::Sphere { center, radius } => {
+                return (pos - *center).length() - radius;
+            }
+            Node::Box {
+                center,
+                half_extents,
+            } => {
+                let q = (pos - *center).abs() - *half_extents;
+                return q.max(Vec3::ZERO).length() + q.max_element().min(0.0);
+            }
+            Node::Capsule { start, end, radius } => {
+                let segment = *end - *start;
+                let t = ((pos - *start).dot(segment) / segment.length_squared().max(f32::EPSILON))
+                    .clamp(0.0, 1.0);
+                return (pos - (*start + segment * t)).length() - radius;
+            }
+        }
+    }
+
+    fn sample_all<'a>(
+        nodes: &'a [Node],
+        pos: Vec3,
+        context: &'a DensityContext,
+    ) -> impl Iterator<Item = f32> + 'a {
+        return nodes
+            .iter()
+            .map(move |node| Self::sample_node(node, pos, context));
+    }
+
+    fn remap(points: &[(f32, f32)], value: f32) -> f32 {
+        let (Some(first), Some(last)) = (points.first(), points.last()) else {
+            return value;
+        };
+        if value <= first.0 {
+            return first.1;
+        }
+        if value >= last.0 {
+            return last.1;
+        }
+
+        for pair in points.windows(2) {
+            let (x0, y0) = pair[0];
+            let (x1, y1) = pair[1];
+            if value <= x1 {
+                if x1 - x0 < f32::EPSILON {
+                    return y1;
+                }
+                return f32::lerp(y0, y1, (value - x0) / (x1 - x0));
+            }
+        }
+        return last.1;
+    }
+}
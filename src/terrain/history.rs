@@ -0,0 +1,214 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use bevy::prelude::*;
+
+use super::chunk::{Cell, CellChange, Chunk};
+use super::material::MaterialId;
+
+// Cells changed in one chunk by an edit.
+pub struct ChunkEdit {
+    pub position: IVec3,
+    pub changes: Vec<CellChange>,
+}
+
+// Rock an edit broke off the terrain as debris.
+pub struct DebrisRecord {
+    // World cells of the rock, cleared by the edit.
+    pub cells: Vec<IVec3>,
+    // The falling debris, None while the edit is undone.
+    pub entity: Option<Entity>,
+}
+
+// Material an edit mined, dropped as an item where it hit.
+pub struct DropRecord {
+    pub material: MaterialId,
+    pub amount: f32,
+    // The drop, None while the edit is undone.
+    pub entity: Option<Entity>,
+    // Picked up drops are taken back from the inventory
+    // of the source when the edit is undone.
+    pub is_collected: bool,
+}
+
+// All chunks changed by a single TerrainCellEvent.
+pub struct EditRecord {
+    pub chunks: Vec<ChunkEdit>,
+    // Entity that made the edit, see TerrainCellEvent::source.
+    pub source: Option<Entity>,
+    // Point the edit hit.
+    pub hit: Vec3,
+    // Materials the edit removed from and added to the terrain,
+    // not counting debris. Undoing or redoing the edit refunds or
+    // takes the added ones from the inventory of the source,
+    // the removed ones come and go as drops.
+    pub removed: Vec<(MaterialId, f32)>,
+    pub added: Vec<(MaterialId, f32)>,
+    // Removed when the edit is undone and spawned again when redone.
+    pub debris: Vec<DebrisRecord>,
+    pub drops: Vec<DropRecord>,
+}
+
+impl EditRecord {
+    // Gets how much of each material the edit removed, from the drop
+    // in solidity of the changed cells. Recolored cells lose all
+    // of their old material.
+    pub fn removed_materials(&self) -> Vec<(MaterialId, f32)> {
+        return self.sum_by_material(|change| {
+            if change.after.material != change.before.material {
+                return (change.before.material, change.before.solidity());
+            }
+            return (
+                change.before.material,
+                change.before.solidity() - change.after.solidity(),
+            );
+        });
+    }
+
+    // Gets how much of each material the edit added, from the rise
+    // in solidity of the changed cells. Recolored cells are filled
+    // with the new material from scratch.
+    pub fn added_materials(&self) -> Vec<(MaterialId, f32)> {
+        return self.sum_by_material(|change| {
+            if change.after.material != change.before.material {
+                return (change.after.material, change.after.solidity());
+            }
+            return (
+                change.after.material,
+                change.after.solidity() - change.before.solidity(),
+            );
+        });
+    }
+
+    // Gets how much of each material was picked up from the drops.
+    pub fn collected_materials(&self) -> Vec<(MaterialId, f32)> {
+        let mut amounts: Vec<(MaterialId, f32)> = Vec::new();
+        for drop in self.drops.iter().filter(|drop| drop.is_collected) {
+            match amounts.iter_mut().find(|(id, _)| *id == drop.material) {
+                Some((_, amount)) => *amount += drop.amount,
+                None => amounts.push((drop.material, drop.amount)),
+            }
+        }
+        return amounts;
+    }
+
+    // Gets the cells the edit left, keyed by chunk and cell index,
+    // or the cells it changed if reapplying it. Cells changed more than
+    // once in the edit are only the last or first state of them.
+    pub fn expected_cells(&self, revert: bool) -> HashMap<(IVec3, usize), Cell> {
+        let mut cells = HashMap::new();
+        for chunk_edit in &self.chunks {
+            for change in &chunk_edit.changes {
+                let key = (chunk_edit.position, change.index);
+                if revert {
+                    cells.insert(key, change.after);
+                } else {
+                    cells.entry(key).or_insert(change.before);
+                }
+            }
+        }
+        return cells;
+    }
+
+    // Sums the positive amounts of the changes per material.
+    // Cells shared by neighboring chunks are only counted once.
+    fn sum_by_material(
+        &self,
+        amount_of: impl Fn(&CellChange) -> (MaterialId, f32),
+    ) -> Vec<(MaterialId, f32)> {
+        let mut counted: HashSet<IVec3> = HashSet::new();
+        let mut amounts: Vec<(MaterialId, f32)> = Vec::new();
+        for chunk_edit in &self.chunks {
+            for change in &chunk_edit.changes {
+                let (material, change_amount) = amount_of(change);
+                if change_amount <= 0.0 {
+                    continue;
+                }
+                let world_cell = Chunk::cell_index_to_world(chunk_edit.position, change.index);
+                if !counted.insert(world_cell) {
+                    continue;
+                }
+
+                match amounts.iter_mut().find(|(id, _)| *id == material) {
+                    Some((_, amount)) => *amount += change_amount,
+                    None => amounts.push((material, change_amount)),
+                }
+            }
+        }
+        return amounts;
+    }
+}
+
+// Reverts the latest terrain edit.
+#[derive(Event, Default)]
+pub struct UndoTerrainEdit;
+
+// Reapplies the latest undone terrain edit.
+#[derive(Event, Default)]
+pub struct RedoTerrainEdit;
+
+// Journal of applied terrain edits for undo and redo.
+// Only the latest max_len edits are kept.
+#[derive(Resource)]
+pub struct EditHistory {
+    undo: VecDeque<EditRecord>,
+    redo: Vec<EditRecord>,
+    max_len: usize,
+}
+
+impl EditHistory {
+    pub fn new(max_len: usize) -> Self {
+        return EditHistory {
+            undo: VecDeque::new(),
+            redo: Vec::new(),
+            max_len,
+        };
+    }
+
+    // Records a new edit, which makes undone edits unrecoverable.
+    pub fn push(&mut self, record: EditRecord) {
+        self.redo.clear();
+        self.push_undo(record);
+    }
+
+    // Marks a drop of an edit that can be undone as picked up.
+    pub fn mark_collected(&mut self, drop_id: Entity) {
+        let drop = self
+            .undo
+            .iter_mut()
+            .flat_map(|record| record.drops.iter_mut())
+            .find(|drop| drop.entity == Some(drop_id));
+        if let Some(drop) = drop {
+            drop.is_collected = true;
+        }
+    }
+
+    pub(super) fn peek_undo(&self) -> Option<&EditRecord> {
+        return self.undo.back();
+    }
+
+    pub(super) fn peek_redo(&self) -> Option<&EditRecord> {
+        return self.redo.last();
+    }
+
+    pub(super) fn pop_undo(&mut self) -> Option<EditRecord> {
+        return self.undo.pop_back();
+    }
+
+    pub(super) fn pop_redo(&mut self) -> Option<EditRecord> {
+        return self.redo.pop();
+    }
+
+    pub(super) fn push_undo(&mut self, record: EditRecord) {
+        if self.max_len == 0 {
+            return;
+        }
+        while self.undo.len() >= self.max_len {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(record);
+    }
+
+    pub(super) fn push_redo(&mut self, record: EditRecord) {
+        self.redo.push(record);
+    }
+}
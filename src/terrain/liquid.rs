@@ -0,0 +1,445 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use super::chunk::{Cell, Chunk, CELL_GRID_SIZE_3, CHUNK_CUBE_SIZE};
+use super::plugin::TerrainSettings;
+
+// Cells with a density above the surface level are open and can hold liquid.
+const OPEN_LEVEL: f32 = 0.5;
+// Level of a full cell, liquid above it is pushed into the cell above.
+const MAX_LEVEL: f32 = 1.0;
+// Cells with less liquid dry up.
+const MIN_LEVEL: f32 = 0.005;
+// Smallest level difference liquid spreads sideways over,
+// lets thin puddles stop spreading.
+const MIN_SPREAD: f32 = 0.02;
+// Chunks moving less liquid than this in a step go to sleep.
+const SETTLE_THRESHOLD: f32 = 0.001;
+
+// Offsets to the chunks whose max border cells are copies
+// of the min border cells of a chunk.
+pub const MIRROR_OFFSETS: [IVec3; 7] = [
+    IVec3::new(-1, 0, 0),
+    IVec3::new(0, -1, 0),
+    IVec3::new(0, 0, -1),
+    IVec3::new(-1, -1, 0),
+    IVec3::new(-1, 0, -1),
+    IVec3::new(0, -1, -1),
+    IVec3::new(-1, -1, -1),
+];
+
+const SIDE_DIRECTIONS: [IVec3; 4] = [IVec3::X, IVec3::NEG_X, IVec3::Z, IVec3::NEG_Z];
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash)]
+pub enum LiquidType {
+    #[default]
+    Water,
+    Lava,
+}
+
+impl LiquidType {
+    pub const ALL: [LiquidType; 2] = [LiquidType::Water, LiquidType::Lava];
+
+    // Fraction of the free space below a cell filled per step.
+    fn fall_rate(self) -> f32 {
+        match self {
+            LiquidType::Water => return 1.0,
+            LiquidType::Lava => return 0.25,
+        }
+    }
+
+    // Fraction of the level difference to each side flowing per step.
+    fn spread_rate(self) -> f32 {
+        match self {
+            LiquidType::Water => return 0.2,
+            LiquidType::Lava => return 0.04,
+        }
+    }
+
+    // Multiplier of the drag on things moving through the liquid.
+    pub fn drag(self) -> f32 {
+        match self {
+            LiquidType::Water => return 1.0,
+            LiquidType::Lava => return 5.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LiquidCell {
+    // How full the cell is, 0 to 1.
+    pub level: f32,
+    pub kind: LiquidType,
+}
+
+// Liquid a biome is flooded with.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct BiomeLiquid {
+    pub kind: LiquidType,
+    // Open cells below this world height start out full.
+    pub below_y: f32,
+}
+
+// Marks the mesh entities of a chunk's liquid,
+// so they are kept when the terrain mesh is replaced.
+#[derive(Component)]
+pub struct LiquidMesh;
+
+// Materials shared by all liquid meshes.
+#[derive(Resource)]
+pub struct LiquidMaterials {
+    pub water: Handle<StandardMaterial>,
+    pub lava: Handle<StandardMaterial>,
+}
+
+impl LiquidMaterials {
+    pub fn new(materials: &mut Assets<StandardMaterial>) -> Self {
+        return LiquidMaterials {
+            water: materials.add(StandardMaterial {
+                base_color: Color::rgba(0.1, 0.3, 0.5, 0.6),
+                perceptual_roughness: 0.1,
+                alpha_mode: AlphaMode::Blend,
+                ..default()
+            }),
+            lava: materials.add(StandardMaterial {
+                base_color: Color::rgb(1.0, 0.35, 0.05),
+                emissive: Color::rgb_linear(4.0, 1.0, 0.1),
+                perceptual_roughness: 0.8,
+                ..default()
+            }),
+        };
+    }
+
+    pub fn get(&self, kind: LiquidType) -> Handle<StandardMaterial> {
+        match kind {
+            LiquidType::Water => return self.water.clone(),
+            LiquidType::Lava => return self.lava.clone(),
+        }
+    }
+}
+
+// Where liquid flows to.
+#[derive(Clone, Copy)]
+enum FlowTarget {
+    Local(usize),
+    Remote(IVec3, usize),
+}
+
+// Liquid flowing into the cells of other chunks during a step,
+// keyed by chunk position and cell index.
+pub type LiquidTransfers = HashMap<(IVec3, usize), LiquidCell>;
+
+// Liquid in the cells of a chunk, simulated as a cellular automaton
+// on top of the terrain density. Cells on the max borders of the chunk
+// belong to the neighbors and only hold copies made for meshing.
+// Liquid is saved along with the chunk once it has moved,
+// chunks without saved liquid regenerate it from their biomes.
+#[derive(Component)]
+pub struct ChunkLiquid {
+    pub cells: Vec<LiquidCell>,
+    // Simulated until the liquid settles.
+    pub is_active: bool,
+    // Needs remeshing.
+    pub is_dirty: bool,
+    // Changed since it was generated or loaded, needs saving.
+    pub is_modified: bool,
+    pub mesh_handles: Vec<Handle<Mesh>>,
+    pub mesh_entities: Vec<Entity>,
+}
+
+// State of a single simulation step of a chunk.
+struct LiquidStep<'a> {
+    chunk: &'a Chunk,
+    cells: &'a mut [LiquidCell],
+    get_neighbor: &'a dyn Fn(IVec3) -> Option<(&'a Chunk, &'a ChunkLiquid)>,
+    transfers: &'a mut LiquidTransfers,
+    moved: f32,
+}
+
+impl<'a> LiquidStep<'a> {
+    // Gets a cell liquid of a type can flow into and its current level.
+    // The cell is relative to the chunk and may be in a neighbor.
+    fn target(&self, cell: IVec3, kind: LiquidType) -> Option<(FlowTarget, f32)> {
+        let (chunk_pos, index) = ChunkLiquid::owner(self.chunk.position, cell);
+        if chunk_pos == self.chunk.position {
+            if !is_open(&self.chunk.cells[index]) {
+                return None;
+            }
+            let target = self.cells[index];
+            if target.level > 0.0 && target.kind != kind {
+                return None;
+            }
+            return Some((FlowTarget::Local(index), target.level));
+        }
+
+        // neighbors that aren't loaded block the flow
+        let (nb_chunk, nb_liquid) = (self.get_neighbor)(chunk_pos)?;
+        if !is_open(&nb_chunk.cells[index]) {
+            return None;
+        }
+        let mut target = *nb_liquid.cells.get(index)?;
+        if let Some(pending) = self.transfers.get(&(chunk_pos, index)) {
+            if target.level <= 0.0 {
+                target.kind = pending.kind;
+            }
+            target.level += pending.level;
+        }
+        if target.level > 0.0 && target.kind != kind {
+            return None;
+        }
+        return Some((FlowTarget::Remote(chunk_pos, index), target.level));
+    }
+
+    fn flow(&mut self, from: usize, to: FlowTarget, amount: f32) {
+        if amount <= 0.0 {
+            return;
+        }
+        let kind = self.cells[from].kind;
+        self.cells[from].level -= amount;
+        self.moved += amount;
+
+        let target = match to {
+            FlowTarget::Local(index) => &mut self.cells[index],
+            FlowTarget::Remote(chunk_pos, index) => {
+                self.transfers.entry((chunk_pos, index)).or_default()
+            }
+        };
+        target.level += amount;
+        target.kind = kind;
+    }
+
+    fn update_cell(&mut self, cell: IVec3) {
+        let index = Chunk::ivec_to_index(cell);
+        let LiquidCell { level, kind } = self.cells[index];
+        if level <= 0.0 {
+            return;
+        }
+
+        // liquid in cells filled by terrain is pushed out the top, or lost
+        if !is_open(&self.chunk.cells[index]) {
+            match self.target(cell + IVec3::Y, kind) {
+                Some((above, _)) => self.flow(index, above, level),
+                None => {
+                    self.cells[index].level = 0.0;
+                    self.moved += level;
+                }
+            }
+            return;
+        }
+
+        // fall into the space below
+        if let Some((below, below_level)) = self.target(cell - IVec3::Y, kind) {
+            let space = (MAX_LEVEL - below_level).max(0.0);
+            self.flow(index, below, space.min(level) * kind.fall_rate());
+        }
+
+        // spread out to lower neighbors, all sides flowing
+        // from the same level so the order doesn't matter
+        let level = self.cells[index].level;
+        for dir in SIDE_DIRECTIONS {
+            if let Some((side, side_level)) = self.target(cell + dir, kind) {
+                if level - side_level > MIN_SPREAD {
+                    self.flow(index, side, (level - side_level) * kind.spread_rate());
+                }
+            }
+        }
+
+        // overfull cells push liquid up
+        let level = self.cells[index].level;
+        if level > MAX_LEVEL {
+            if let Some((above, _)) = self.target(cell + IVec3::Y, kind) {
+                self.flow(index, above, level - MAX_LEVEL);
+            }
+        }
+    }
+}
+
+impl ChunkLiquid {
+    pub fn empty() -> Self {
+        return ChunkLiquid {
+            cells: vec![LiquidCell::default(); CELL_GRID_SIZE_3],
+            is_active: false,
+            is_dirty: false,
+            is_modified: false,
+            mesh_handles: Vec::new(),
+            mesh_entities: Vec::new(),
+        };
+    }
+
+    // Liquid loaded from a saved chunk, simulated in case it was
+    // saved before it settled.
+    pub fn from_cells(cells: Vec<LiquidCell>) -> Self {
+        let has_liquid = cells.iter().any(|cell| cell.level > 0.0);
+        return ChunkLiquid {
+            cells,
+            is_active: has_liquid,
+            is_dirty: has_liquid,
+            ..Self::empty()
+        };
+    }
+
+    // Fills the open cells of flooded biomes below their liquid height.
+    pub fn generate(chunk: &Chunk, settings: &TerrainSettings) -> Self {
+        let mut liquid = Self::empty();
+
+        let highest = settings
+            .biomes
+            .iter()
+            .filter_map(|biome| biome.liquid.map(|liquid| liquid.below_y))
+            .fold(f32::NEG_INFINITY, f32::max);
+        let chunk_min_y = (chunk.position.y * CHUNK_CUBE_SIZE as i32) as f32;
+        if chunk_min_y >= highest {
+            return liquid;
+        }
+
+        for (index, cell) in chunk.cells.iter().enumerate() {
+            if !is_open(cell) {
+                continue;
+            }
+            let pos = Chunk::cell_index_to_world(chunk.position, index).as_vec3();
+            if pos.y >= highest {
+                continue;
+            }
+
+            // the strongest biome decides, so flooded areas have clear borders
+            let weights = settings.biomes.weights_at(pos);
+            let Some((biome_index, _)) = weights.iter().max_by(|a, b| a.1.total_cmp(&b.1)) else {
+                continue;
+            };
+            if let Some(biome_liquid) = settings.biomes.get(*biome_index).liquid {
+                if pos.y < biome_liquid.below_y {
+                    liquid.cells[index] = LiquidCell {
+                        level: MAX_LEVEL,
+                        kind: biome_liquid.kind,
+                    };
+                    liquid.is_active = true;
+                    liquid.is_dirty = true;
+                }
+            }
+        }
+
+        return liquid;
+    }
+
+    // Gets the chunk owning a cell relative to a chunk,
+    // and the index of the cell in it.
+    pub fn owner(chunk_pos: IVec3, cell: IVec3) -> (IVec3, usize) {
+        let size = IVec3::splat(CHUNK_CUBE_SIZE as i32);
+        return (
+            chunk_pos + cell.div_euclid(size),
+            Chunk::ivec_to_index(cell.rem_euclid(size)),
+        );
+    }
+
+    // Gets the liquid in the world cell nearest a position.
+    // get_liquid gets the liquid of a chunk, None if it isn't loaded.
+    pub fn at_world<'a>(
+        world_pos: Vec3,
+        get_liquid: impl Fn(IVec3) -> Option<&'a ChunkLiquid>,
+    ) -> Option<LiquidCell> {
+        let (chunk_pos, index) = Self::owner(IVec3::ZERO, world_pos.round().as_ivec3());
+        return get_liquid(chunk_pos).map(|liquid| liquid.cells[index]);
+    }
+
+    // Advances the liquid in the cells owned by a chunk by one step,
+    // bottom up so falling liquid moves a single cell per step.
+    // Takes the cells out of the chunk's ChunkLiquid so neighbors can be
+    // read while they change. Flows into neighbors are added to transfers
+    // and applied after all chunks stepped. Returns the total level moved.
+    pub fn step<'a>(
+        chunk: &'a Chunk,
+        cells: &'a mut [LiquidCell],
+        get_neighbor: &'a dyn Fn(IVec3) -> Option<(&'a Chunk, &'a ChunkLiquid)>,
+        transfers: &'a mut LiquidTransfers,
+    ) -> f32 {
+        let mut step = LiquidStep {
+            chunk,
+            cells,
+            get_neighbor,
+            transfers,
+            moved: 0.0,
+        };
+
+        let size = CHUNK_CUBE_SIZE as i32;
+        for y in 0..size {
+            for x in 0..size {
+                for z in 0..size {
+                    step.update_cell(IVec3 { x, y, z });
+                }
+            }
+        }
+
+        for cell in step.cells.iter_mut() {
+            if cell.level > 0.0 && cell.level < MIN_LEVEL {
+                step.moved += cell.level;
+                cell.level = 0.0;
+            }
+        }
+
+        return step.moved;
+    }
+
+    pub fn is_settled(moved: f32) -> bool {
+        return moved < SETTLE_THRESHOLD;
+    }
+
+    // Adds liquid flowing in from a neighbor.
+    pub fn receive(&mut self, index: usize, transfer: LiquidCell) {
+        let cell = &mut self.cells[index];
+        if cell.level <= 0.0 {
+            cell.kind = transfer.kind;
+        }
+        cell.level += transfer.level;
+        self.is_active = true;
+        self.is_dirty = true;
+        self.is_modified = true;
+    }
+
+    // Gets the cells of a liquid type as terrain cells to polygonize,
+    // with the liquid inside the surface. The max borders are copied
+    // from the neighbors, None if the chunk has none of the liquid.
+    pub fn mesh_cells<'a>(
+        &self,
+        chunk_pos: IVec3,
+        kind: LiquidType,
+        get_neighbor: impl Fn(IVec3) -> Option<&'a ChunkLiquid>,
+    ) -> Option<Box<[Cell; CELL_GRID_SIZE_3]>> {
+        let mut cells = Box::new(
+            [Cell {
+                value: 1.0,
+                ..default()
+            }; CELL_GRID_SIZE_3],
+        );
+
+        let mut has_kind = false;
+        for (index, cell) in cells.iter_mut().enumerate() {
+            let [x, y, z] = Chunk::index_to_cell(index);
+            let (owner_pos, owner_index) =
+                Self::owner(chunk_pos, IVec3::new(x as i32, y as i32, z as i32));
+            let liquid = if owner_pos == chunk_pos {
+                self.cells[owner_index]
+            } else {
+                match get_neighbor(owner_pos).and_then(|nb| nb.cells.get(owner_index)) {
+                    Some(liquid) => *liquid,
+                    None => continue,
+                }
+            };
+
+            if liquid.level > 0.0 && liquid.kind == kind {
+                cell.value = 1.0 - liquid.level.min(MAX_LEVEL);
+                has_kind = true;
+            }
+        }
+
+        if !has_kind {
+            return None;
+        }
+        return Some(cells);
+    }
+}
+
+fn is_open(cell: &Cell) -> bool {
+    return cell.value > OPEN_LEVEL;
+}
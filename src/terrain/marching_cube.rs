@@ -1,6 +1,7 @@
 use std::f32::EPSILON;
 
-use super::chunk::{Cell, CellType};
+use super::chunk::Cell;
+use super::material::{MaterialId, DEFAULT_MATERIAL};
 use bevy::math::Vec3;
 
 pub fn mc_interpolate_vertex(
@@ -9,31 +10,48 @@ pub fn mc_interpolate_vertex(
     p2: Vec3,
     c1: Cell,
     c2: Cell,
-) -> (Vec3, CellType) {
+) -> (Vec3, MaterialId) {
     if f32::abs(iso_level - c1.value) < EPSILON {
-        return (p1, c1.cell_type);
+        return (p1, c1.material);
     }
     if f32::abs(iso_level - c2.value) < EPSILON {
-        return (p2, c2.cell_type);
+        return (p2, c2.material);
     }
     if f32::abs(c1.value - c2.value) < EPSILON {
-        return (p1, c1.cell_type);
+        return (p1, c1.material);
     }
 
     let mu = (iso_level - c1.value) / (c2.value - c1.value);
     let p3 = p1 + (p2 - p1) * mu;
-    let mut ct = CellType::Stone;
+    let mut ct = DEFAULT_MATERIAL;
 
-    // Find which point is inside the surface for cell type.
+    // Find which point is inside the surface for the material.
     if c1.value < iso_level && c2.value > iso_level {
-        ct = c1.cell_type;
+        ct = c1.material;
     } else if c2.value < iso_level && c1.value > iso_level {
-        ct = c2.cell_type;
+        ct = c2.material;
     }
 
     return (p3, ct);
 }
 
+// Cube corners connected by each of the 12 edges,
+// in the same order as the bits of MC_EDGE_TABLE.
+pub const MC_EDGE_CORNERS: [[usize; 2]; 12] = [
+    [0, 1],
+    [1, 2],
+    [2, 3],
+    [3, 0],
+    [4, 5],
+    [5, 6],
+    [6, 7],
+    [7, 4],
+    [0, 4],
+    [1, 5],
+    [2, 6],
+    [3, 7],
+];
+
 // Marching cubes lookup tables from https://paulbourke.net/geometry/polygonise/
 pub const MC_EDGE_TABLE: [i32; 256] = [
     0x0, 0x109, 0x203, 0x30a, 0x406, 0x50f, 0x605, 0x70c, 0x80c, 0x905, 0xa0f, 0xb06, 0xc0a, 0xd03,
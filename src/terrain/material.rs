@@ -0,0 +1,167 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+// Index of a material in the CellMaterialRegistry.
+pub type MaterialId = u16;
+
+// Material used where nothing else is generated,
+// and in place of unknown ids.
+pub const DEFAULT_MATERIAL: MaterialId = 0;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CellMaterial {
+    pub name: String,
+    // Linear RGBA.
+    pub color: [f32; 4],
+    #[serde(default = "default_roughness")]
+    pub roughness: f32,
+    #[serde(default)]
+    pub metallic: f32,
+    // How much slower the material is to dig than hardness 1.
+    #[serde(default = "default_hardness")]
+    pub hardness: f32,
+    // Where the material is generated, never if None.
+    #[serde(default)]
+    pub generation: Option<MaterialGeneration>,
+}
+
+// Generates a material where the terrain type noise,
+// remapped to 0 to 1, is within min..max.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct MaterialGeneration {
+    pub min: f32,
+    pub max: f32,
+}
+
+fn default_roughness() -> f32 {
+    return 0.9;
+}
+
+fn default_hardness() -> f32 {
+    return 1.0;
+}
+
+#[derive(Deserialize)]
+struct MaterialFile {
+    materials: Vec<CellMaterial>,
+}
+
+// All materials cells can be made of, indexed by MaterialId.
+// Cheap to clone so it can be sent to background tasks.
+#[derive(Resource, Clone)]
+pub struct CellMaterialRegistry {
+    materials: Arc<Vec<CellMaterial>>,
+}
+
+impl Default for CellMaterialRegistry {
+    fn default() -> Self {
+        let material = |name: &str, color: [f32; 4], hardness: f32, min: f32, max: f32| {
+            return CellMaterial {
+                name: name.to_string(),
+                color,
+                roughness: default_roughness(),
+                metallic: 0.0,
+                hardness,
+                generation: Some(MaterialGeneration { min, max }),
+            };
+        };
+
+        // same order as the ids of old region files
+        let mut materials = vec![
+            material("stone", [0.4, 0.4, 0.4, 1.0], 1.0, 0.0, 0.0),
+            material("granite", [1.0, 1.0, 1.0, 1.0], 1.5, 0.3, 0.4),
+            material("dirt", [0.3, 0.15, 0.1, 1.0], 0.5, 0.0, 0.2),
+            material("iron", [0.6, 0.3, 0.0, 1.0], 2.0, 0.0, 0.0),
+            material("gold", [1.0, 0.8, 0.1, 1.0], 1.5, 0.0, 0.0),
+            material("ruby", [1.0, 0.0, 0.0, 1.0], 3.0, 0.0, 0.0),
+            // ores are placed by the ore pass, these only by biome palettes
+            material("limestone", [0.75, 0.7, 0.55, 1.0], 0.8, 0.0, 0.0),
+            material("basalt", [0.12, 0.12, 0.13, 1.0], 1.5, 0.0, 0.0),
+            material("obsidian", [0.05, 0.03, 0.08, 1.0], 2.5, 0.0, 0.0),
+            material("amethyst", [0.55, 0.25, 0.85, 1.0], 2.0, 0.0, 0.0),
+            material("clay", [0.55, 0.35, 0.25, 1.0], 0.4, 0.0, 0.0),
+        ];
+        // empty ranges are never generated
+        for material in materials.iter_mut() {
+            if let Some(rule) = material.generation {
+                if rule.min >= rule.max {
+                    material.generation = None;
+                }
+            }
+        }
+
+        return Self::new(materials);
+    }
+}
+
+impl CellMaterialRegistry {
+    // The first material is the default material.
+    pub fn new(materials: Vec<CellMaterial>) -> Self {
+        assert!(!materials.is_empty(), "material registry can't be empty");
+        assert!(
+            materials.len() <= MaterialId::MAX as usize + 1,
+            "too many materials"
+        );
+        return CellMaterialRegistry {
+            materials: Arc::new(materials),
+        };
+    }
+
+    // Loads materials from a RON file, like:
+    //   (materials: [(name: "stone", color: (0.4, 0.4, 0.4, 1.0)), ...])
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let file: MaterialFile = ron::from_str(&text).map_err(|err| err.to_string())?;
+        if file.materials.is_empty() {
+            return Err("no materials defined".to_string());
+        }
+        if file.materials.len() > MaterialId::MAX as usize + 1 {
+            return Err(format!(
+                "more than {} materials",
+                MaterialId::MAX as usize + 1
+            ));
+        }
+        return Ok(Self::new(file.materials));
+    }
+
+    // Gets a material, unknown ids get the default material.
+    pub fn get(&self, id: MaterialId) -> &CellMaterial {
+        return self
+            .materials
+            .get(id as usize)
+            .unwrap_or(&self.materials[DEFAULT_MATERIAL as usize]);
+    }
+
+    pub fn id_of(&self, name: &str) -> Option<MaterialId> {
+        return self
+            .materials
+            .iter()
+            .position(|material| material.name == name)
+            .map(|index| index as MaterialId);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (MaterialId, &CellMaterial)> {
+        return self
+            .materials
+            .iter()
+            .enumerate()
+            .map(|(index, material)| (index as MaterialId, material));
+    }
+
+    // Gets the material generated for a type noise value.
+    // The first material whose range contains the value wins.
+    pub fn generated_at(&self, value: f32) -> MaterialId {
+        for (id, material) in self.iter() {
+            if let Some(rule) = material.generation {
+                if value >= rule.min && value < rule.max {
+                    return id;
+                }
+            }
+        }
+        return DEFAULT_MATERIAL;
+    }
+}
@@ -1,4 +1,17 @@
+pub mod biome;
+pub mod carver;
 pub mod chunk;
+pub mod chunk_map;
+pub mod collapse;
+pub mod density;
+pub mod history;
+pub mod liquid;
 mod marching_cube;
+pub mod material;
+pub mod ore;
 pub mod plugin;
-
+pub mod region;
+pub mod render;
+pub mod rng;
+pub mod shape;
+mod transition;
@@ -0,0 +1,303 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use bevy::prelude::*;
+use serde::Deserialize;
+
+use super::chunk::{Cell, Chunk, CELL_GRID_SIZE_3, CHUNK_CUBE_SIZE};
+use super::material::{CellMaterialRegistry, MaterialId};
+use super::rng::SplitMix64;
+
+// Number of segments of worm veins.
+const WORM_SEGMENTS: usize = 6;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum VeinShape {
+    // Randomly stretched ball with a radius of the vein size.
+    Blob,
+    // Thin disc with a radius of twice the vein size.
+    Sheet,
+    // Winding tube, each segment as long as the vein size.
+    Worm,
+}
+
+// Rules for placing veins of one ore.
+#[derive(Clone, Debug)]
+pub struct Ore {
+    pub material: MaterialId,
+    // Materials the ore can replace, any if empty.
+    pub host_rock: Vec<MaterialId>,
+    // Range of depths (negative world y) veins are centered in.
+    pub depth: (f32, f32),
+    pub shape: VeinShape,
+    // Radius of blobs, half width of sheets and segment length of worms.
+    pub size: f32,
+    // Size of the grid cells veins are placed in,
+    // each cell has at most one vein.
+    pub spacing: f32,
+    // Chance of a grid cell having a vein, 0 to 1.
+    pub frequency: f32,
+}
+
+#[derive(Deserialize)]
+struct OreDef {
+    material: String,
+    #[serde(default)]
+    host_rock: Vec<String>,
+    depth: (f32, f32),
+    shape: VeinShape,
+    size: f32,
+    #[serde(default = "default_spacing")]
+    spacing: f32,
+    frequency: f32,
+}
+
+#[derive(Deserialize)]
+struct OreFile {
+    ores: Vec<OreDef>,
+}
+
+fn default_spacing() -> f32 {
+    return 32.0;
+}
+
+// A single placed vein.
+enum Vein {
+    Blob {
+        center: Vec3,
+        radius: f32,
+        // Random stretch of the blob along each axis.
+        stretch: Vec3,
+    },
+    Sheet {
+        center: Vec3,
+        normal: Vec3,
+        radius: f32,
+        thickness: f32,
+    },
+    Worm {
+        points: Vec<Vec3>,
+        radius: f32,
+    },
+}
+
+impl Vein {
+    fn contains(&self, pos: Vec3) -> bool {
+        match self {
+            Vein::Blob {
+                center,
+                radius,
+                stretch,
+            } => {
+                return ((pos - *center) / *stretch).length_squared() < radius * radius;
+            }
+            Vein::Sheet {
+                center,
+                normal,
+                radius,
+                thickness,
+            } => {
+                let offset = pos - *center;
+                let height = offset.dot(*normal);
+                let along = (offset - *normal * height).length();
+                // thins out towards the edge
+                let edge = (1.0 - along / radius).max(0.0);
+                return height.abs() < thickness * edge.sqrt();
+            }
+            Vein::Worm { points, radius } => {
+                for segment in points.windows(2) {
+                    let (a, b) = (segment[0], segment[1]);
+                    let ab = b - a;
+                    let t =
+                        ((pos - a).dot(ab) / ab.length_squared().max(f32::EPSILON)).clamp(0.0, 1.0);
+                    if (pos - (a + ab * t)).length_squared() < radius * radius {
+                        return true;
+                    }
+                }
+                return false;
+            }
+        }
+    }
+}
+
+// Ore placement pass run after the terrain materials are generated.
+// Veins are placed on a world space grid seeded from the world seed,
+// so chunks agree on the veins crossing their borders.
+// Cheap to clone so it can be sent to background tasks.
+#[derive(Clone)]
+pub struct OreRules {
+    ores: Arc<Vec<Ore>>,
+    seed: u32,
+}
+
+impl OreRules {
+    pub fn new(ores: Vec<Ore>, seed: u32) -> Self {
+        return OreRules {
+            ores: Arc::new(ores),
+            seed,
+        };
+    }
+
+    pub fn default_ores(materials: &CellMaterialRegistry) -> Vec<Ore> {
+        let stone: Vec<MaterialId> = ["stone", "granite", "limestone", "basalt"]
+            .iter()
+            .filter_map(|name| materials.id_of(name))
+            .collect();
+
+        let mut ores = Vec::new();
+        let mut add = |name: &str, depth, shape, size, frequency| {
+            if let Some(material) = materials.id_of(name) {
+                ores.push(Ore {
+                    material,
+                    host_rock: stone.clone(),
+                    depth,
+                    shape,
+                    size,
+                    spacing: default_spacing(),
+                    frequency,
+                });
+            }
+        };
+        add("iron", (-64.0, 256.0), VeinShape::Worm, 4.0, 0.5);
+        add("gold", (32.0, 512.0), VeinShape::Sheet, 4.0, 0.25);
+        add("ruby", (64.0, 1024.0), VeinShape::Blob, 1.5, 0.2);
+        return ores;
+    }
+
+    // Loads ores from a RON file, see assets/ores.ron.
+    // Materials are referenced by name.
+    pub fn load_ores(path: &Path, materials: &CellMaterialRegistry) -> Result<Vec<Ore>, String> {
+        let text = fs::read_to_string(path).map_err(|err| err.to_string())?;
+        let file: OreFile = ron::from_str(&text).map_err(|err| err.to_string())?;
+
+        let material_id = |name: &str| {
+            return materials
+                .id_of(name)
+                .ok_or_else(|| format!("unknown material {:?}", name));
+        };
+
+        let mut ores = Vec::new();
+        for def in file.ores {
+            if def.spacing < 1.0 {
+                return Err(format!("spacing of {:?} is less than 1", def.material));
+            }
+            let mut host_rock = Vec::new();
+            for name in &def.host_rock {
+                host_rock.push(material_id(name)?);
+            }
+            ores.push(Ore {
+                material: material_id(&def.material)?,
+                host_rock,
+                depth: def.depth,
+                shape: def.shape,
+                size: def.size,
+                spacing: def.spacing,
+                frequency: def.frequency,
+            });
+        }
+
+        return Ok(ores);
+    }
+
+    // Replaces the materials of a chunk's cells inside veins.
+    pub fn place(&self, chunk_pos: IVec3, cells: &mut [Cell; CELL_GRID_SIZE_3]) {
+        let chunk_min = (chunk_pos * CHUNK_CUBE_SIZE as i32).as_vec3();
+        let chunk_max = chunk_min + Vec3::splat(CHUNK_CUBE_SIZE as f32);
+
+        for (ore_index, ore) in self.ores.iter().enumerate() {
+            let veins = self.veins_near(ore_index, ore, chunk_min, chunk_max);
+            if veins.is_empty() {
+                continue;
+            }
+
+            for (index, cell) in cells.iter_mut().enumerate() {
+                if !ore.host_rock.is_empty() && !ore.host_rock.contains(&cell.material) {
+                    continue;
+                }
+                let pos = Chunk::cell_index_to_world(chunk_pos, index).as_vec3();
+                if veins.iter().any(|vein| vein.contains(pos)) {
+                    cell.material = ore.material;
+                }
+            }
+        }
+    }
+
+    // Gets the veins of an ore which may reach into a box.
+    fn veins_near(&self, ore_index: usize, ore: &Ore, min: Vec3, max: Vec3) -> Vec<Vein> {
+        let reach = Self::max_extent(ore);
+        let grid_min = ((min - reach) / ore.spacing).floor().as_ivec3();
+        let grid_max = ((max + reach) / ore.spacing).floor().as_ivec3();
+
+        let mut veins = Vec::new();
+        for x in grid_min.x..=grid_max.x {
+            for y in grid_min.y..=grid_max.y {
+                for z in grid_min.z..=grid_max.z {
+                    let grid_pos = IVec3 { x, y, z };
+                    let mut rng = SplitMix64::at(self.seed, grid_pos, ore_index as u64 + 1);
+                    if rng.next_f32() >= ore.frequency {
+                        continue;
+                    }
+
+                    let center = (grid_pos.as_vec3()
+                        + Vec3::new(rng.next_f32(), rng.next_f32(), rng.next_f32()))
+                        * ore.spacing;
+                    let depth = -center.y;
+                    if depth < ore.depth.0 || depth > ore.depth.1 {
+                        continue;
+                    }
+
+                    veins.push(Self::make_vein(ore, center, &mut rng));
+                }
+            }
+        }
+        return veins;
+    }
+
+    fn make_vein(ore: &Ore, center: Vec3, rng: &mut SplitMix64) -> Vein {
+        match ore.shape {
+            VeinShape::Blob => {
+                return Vein::Blob {
+                    center,
+                    radius: ore.size * rng.range(0.7, 1.3),
+                    stretch: Vec3::new(
+                        rng.range(0.6, 1.4),
+                        rng.range(0.6, 1.4),
+                        rng.range(0.6, 1.4),
+                    ),
+                };
+            }
+            VeinShape::Sheet => {
+                return Vein::Sheet {
+                    center,
+                    normal: rng.direction(),
+                    radius: ore.size * 2.0 * rng.range(0.7, 1.3),
+                    thickness: (ore.size * 0.25).max(0.75),
+                };
+            }
+            VeinShape::Worm => {
+                let mut points = vec![center];
+                let mut dir = rng.direction();
+                for _ in 0..WORM_SEGMENTS {
+                    // turn a bit each segment
+                    dir = (dir + rng.direction() * 0.6).normalize_or_zero();
+                    let last = *points.last().unwrap();
+                    points.push(last + dir * ore.size);
+                }
+                return Vein::Worm {
+                    points,
+                    radius: (ore.size * 0.2).max(0.75),
+                };
+            }
+        }
+    }
+
+    // Max distance from a vein center to a cell in the vein.
+    fn max_extent(ore: &Ore) -> f32 {
+        match ore.shape {
+            VeinShape::Blob => return ore.size * 1.3 * 1.4,
+            VeinShape::Sheet => return ore.size * 2.0 * 1.3,
+            VeinShape::Worm => return ore.size * WORM_SEGMENTS as f32 + ore.size,
+        }
+    }
+}
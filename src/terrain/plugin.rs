@@ -1,251 +1,1508 @@
 use std::borrow::BorrowMut;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::Duration;
 
+use bevy::app::AppExit;
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
+use bevy::tasks::{block_on, poll_once, AsyncComputeTaskPool, Task};
+use bevy_rapier3d::dynamics::RigidBody;
 use bevy_rapier3d::geometry::{Collider, ComputedColliderShape};
 use bevy_rapier3d::pipeline::QueryFilter;
 use bevy_rapier3d::plugin::RapierContext;
 use noise::{Fbm, Perlin};
+use serde::{Deserialize, Serialize};
 
-use super::super::player::plugin::Player;
+use super::biome::BiomeMap;
+use super::carver::WormCarvers;
 use super::chunk::*;
+use super::chunk_map::ChunkMap;
+use super::collapse::{find_islands, is_solid, island_mesh, Debris};
+use super::density::DensityGraph;
+use super::history::{
+    ChunkEdit, DebrisRecord, DropRecord, EditHistory, EditRecord, RedoTerrainEdit, UndoTerrainEdit,
+};
+use super::liquid::{
+    ChunkLiquid, LiquidMaterials, LiquidMesh, LiquidTransfers, LiquidType, MIRROR_OFFSETS,
+};
+use super::material::{CellMaterialRegistry, MaterialId};
+use super::ore::OreRules;
+use super::region::{RegionStore, StoredChunk};
+use super::render::{TerrainMaterial, TerrainMaterialExtension, TerrainMaterialHandle};
+pub use super::shape::TerrainEditShape;
+use crate::item::inventory::Inventory;
+use crate::item::plugin::ItemDrop;
 
-pub const RENDER_DISTANCE_CHUNKS: u32 = 5;
+pub const RENDER_DISTANCE_CHUNKS: u32 = 7;
 const CHUNK_SPAWN_DISTANCE: i32 = RENDER_DISTANCE_CHUNKS as i32 + 1;
+// Distances in chunks from the nearest loader
+// at which the next coarser level of detail is used.
+const LOD_DISTANCES: [i32; MAX_LOD as usize] = [3, 5, 7];
+// Max number of chunks being generated in the background at once.
+const MAX_PENDING_CHUNKS: usize = 64;
+const AUTOSAVE_INTERVAL_SECS: u64 = 30;
+// Frequency of the noise choosing biomes.
+const BIOME_NOISE_SCALE: f64 = 0.004;
+// Max number of terrain edits that can be undone.
+const EDIT_HISTORY_SIZE: usize = 100;
+// Islands with fewer solid cells crumble away instead of falling.
+const MIN_DEBRIS_CELLS: usize = 4;
+const DEBRIS_LIFETIME_SECS: f32 = 60.0;
+// Rounding error allowed when checking an inventory holds an amount.
+const MATERIAL_AMOUNT_TOLERANCE: f32 = 0.001;
+// Time between rebuilding the meshes of flowing liquid.
+const LIQUID_MESH_INTERVAL_SECS: f32 = 0.1;
+// Max number of liquid meshes rebuilt at once.
+const MAX_LIQUID_MESHES_PER_UPDATE: usize = 16;
 
 pub struct TerrainPlugin {
     pub seed: u32,
+    pub mesh_mode: MeshMode,
+    // Directory edited chunks are saved to.
+    // Edits are only kept in memory if None.
+    pub save_dir: Option<PathBuf>,
+    // RON file defining the cell materials,
+    // the built in materials are used if None.
+    pub materials_path: Option<PathBuf>,
+    // RON file defining the biomes,
+    // a single biome is used if None.
+    pub biomes_path: Option<PathBuf>,
+    // RON file defining the density graph terrain is generated from,
+    // the biome density is used if None.
+    pub density_path: Option<PathBuf>,
+    // RON file defining the ore veins,
+    // the built in ores are used if None.
+    pub ores_path: Option<PathBuf>,
 }
 
 #[derive(Resource)]
+struct AutosaveTimer(Timer);
+
+#[derive(Resource)]
+struct LiquidMeshTimer(Timer);
+
+// Number of fixed steps run so far, terrain edits are applied in them.
+#[derive(Resource, Default)]
+pub struct FixedTick(pub u64);
+
+// Terrain edits in FixedUpdate, systems sending TerrainCellEvent,
+// UndoTerrainEdit or RedoTerrainEdit in a step run before it.
+#[derive(SystemSet, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TerrainEditSet;
+
+#[derive(Clone, Copy, PartialEq)]
+enum HistoryStep {
+    Undo,
+    Redo,
+}
+
+// Undo and redo requests sent this step.
+#[derive(SystemParam)]
+struct HistoryEvents<'w, 's> {
+    undo: EventReader<'w, 's, UndoTerrainEdit>,
+    redo: EventReader<'w, 's, RedoTerrainEdit>,
+}
+
+// Undo and redo requests waiting for the regions of the unloaded
+// chunks they change to be read in the background.
+#[derive(Resource, Default)]
+struct PendingHistorySteps {
+    steps: VecDeque<HistoryStep>,
+    reading: Option<Task<()>>,
+}
+
+struct WantedChunk {
+    // highest priority of the loaders wanting the chunk
+    priority: i32,
+    // squared distance to the closest loader with that priority
+    dist: i32,
+    // squared distance to the closest loader of any priority
+    min_dist: i32,
+}
+
+#[derive(Resource, Clone)]
 pub struct TerrainSettings {
-    fbm: Fbm<Perlin>,
-    fbm_scale: f64,
-    type_noise: Perlin,
-    type_noise_scale: f64,
+    pub(super) seed: u32,
+    pub(super) fbm: Fbm<Perlin>,
+    pub(super) fbm_scale: f64,
+    pub(super) type_noise: Perlin,
+    pub(super) type_noise_scale: f64,
+    pub(super) mesh_mode: MeshMode,
+    pub(super) materials: CellMaterialRegistry,
+    pub(super) biomes: BiomeMap,
+    pub(super) density: DensityGraph,
+    pub(super) ores: OreRules,
+    pub(super) carvers: WormCarvers,
 }
 
-#[derive(Debug)]
-pub enum TerrainEditShape {
-    Sphere(f32),
+impl TerrainSettings {
+    pub fn seed(&self) -> u32 {
+        return self.seed;
+    }
 }
 
-#[derive(Event, Debug)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TerrainEditMode {
+    // Adds material.
+    Add,
+    // Removes material.
+    #[default]
+    Subtract,
+    // Moves cell values towards TerrainCellEvent::value.
+    Set,
+    // Blurs cell values with their neighbors.
+    Smooth,
+    // Moves cell values towards a plane through the hit point,
+    // facing the direction the edit came from.
+    Flatten,
+}
+
+#[derive(Event, Clone, Debug, Serialize, Deserialize)]
 pub struct TerrainCellEvent {
     pub origin: Vec3,
     pub dir: Vec3,
+    // Target value for TerrainEditMode::Set.
     pub value: f32,
     pub shape: TerrainEditShape,
-    pub cell_type: Option<CellType>,
+    pub mode: TerrainEditMode,
+    // How much the cell values change at full weight, 0 to 1.
+    pub strength: f32,
+    // Fraction of the shape size over which the edit fades out, 0 to 1.
+    pub falloff: f32,
+    // Material of cells material is added to,
+    // existing materials are kept if None.
+    pub material: Option<MaterialId>,
+    // Point to edit at instead of casting a ray from origin,
+    // set on recorded edits so replays change the same cells.
+    #[serde(default)]
+    pub hit: Option<Vec3>,
+    // Entity making the edit, passed on to the events the edit causes.
+    #[serde(skip)]
+    pub source: Option<Entity>,
 }
 
+#[derive(Clone, Debug)]
+pub enum TerrainEdit {
+    // The edit, with hit set to the point it was made at.
+    Cell(TerrainCellEvent),
+    // An edit taken off the EditHistory, either applied
+    // or dropped because its cells changed since.
+    Undo,
+    Redo,
+}
+
+// Sent for every TerrainCellEvent that changed the terrain
+// and every undo or redo taken off the EditHistory.
+#[derive(Event, Clone, Debug)]
+pub struct TerrainEditedEvent {
+    pub edit: TerrainEdit,
+    // FixedTick of the step the edit was applied in.
+    pub tick: u64,
+}
+
+// Sent when an edit removes material from the terrain.
+#[derive(Event, Debug)]
+pub struct TerrainMinedEvent {
+    // Point the edit hit.
+    pub position: Vec3,
+    // Amount of each material removed, in cells of solid volume.
+    pub amounts: Vec<(MaterialId, f32)>,
+    // Entities reserved for dropping each amount, in the same order.
+    // Ones that aren't dropped are despawned by the reader.
+    pub drops: Vec<Entity>,
+}
+
+// Sent when an edit adds material to the terrain.
+#[derive(Event, Debug)]
+pub struct TerrainPlacedEvent {
+    // Point the edit hit.
+    pub position: Vec3,
+    // See TerrainCellEvent::source.
+    pub source: Option<Entity>,
+    // Amount of each material added, in cells of solid volume.
+    pub amounts: Vec<(MaterialId, f32)>,
+}
+
+// A chunk being generated in the background.
+// The Chunk and ChunkLiquid components are inserted once the task completes.
+#[derive(Component)]
+pub struct ChunkGenTask {
+    pub position: IVec3,
+    task: Task<(Chunk, ChunkLiquid)>,
+}
+
+// A chunk being polygonized in the background.
 #[derive(Component)]
-pub struct Empty {}
+pub struct ChunkMeshTask(Task<ChunkMesh>);
+
+// The liquid of a chunk being polygonized in the background,
+// a mesh per liquid type in the chunk.
+#[derive(Component)]
+pub struct LiquidMeshTask(Task<Vec<(LiquidType, Mesh)>>);
+
+// Keeps chunks loaded around the entity it is attached to.
+// Any number of loaders can exist, the terrain streams
+// the union of the chunks they want.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct ChunkLoader {
+    // Distance in chunks to keep loaded around the loader.
+    pub radius: u32,
+    // Chunks wanted by higher priority loaders are spawned first.
+    pub priority: i32,
+}
+
+impl Default for ChunkLoader {
+    fn default() -> Self {
+        return ChunkLoader {
+            radius: CHUNK_SPAWN_DISTANCE as u32,
+            priority: 0,
+        };
+    }
+}
 
 impl TerrainPlugin {
-    fn spawn_chunk(commands: &mut Commands, settings: &Res<TerrainSettings>, pos: IVec3) {
-        commands
+    fn spawn_chunk(
+        commands: &mut Commands,
+        settings: &TerrainSettings,
+        store: &RegionStore,
+        chunk_map: &mut ChunkMap,
+        pos: IVec3,
+        lod: u32,
+    ) {
+        let thread_pool = AsyncComputeTaskPool::get();
+        let settings = settings.clone();
+        let store = store.clone();
+        let task = thread_pool.spawn(async move {
+            // saved chunks are loaded instead of regenerated
+            let (mut chunk, saved_liquid) = match store.load_chunk(pos) {
+                Some(stored) => (Chunk::from_cells(pos, stored.cells), stored.liquid),
+                None => (Chunk::new(&settings, pos.x, pos.y, pos.z), None),
+            };
+            chunk.lod = lod;
+            let liquid = match saved_liquid {
+                Some(cells) => ChunkLiquid::from_cells(cells),
+                None => ChunkLiquid::generate(&chunk, &settings),
+            };
+            return (chunk, liquid);
+        });
+
+        let chunk_id = commands
             .spawn((
-                Chunk::new(
-                    &settings.fbm,
-                    settings.fbm_scale,
-                    settings.type_noise,
-                    settings.type_noise_scale,
-                    pos.x,
-                    pos.y,
-                    pos.z,
-                ),
+                ChunkGenTask {
+                    position: pos,
+                    task,
+                },
                 SpatialBundle { ..default() }, // required for children
             ))
-            // FIXME
-            // need at least 1 child for the update query to work...
-            .with_children(|parent| {
-                parent.spawn(Empty {});
-            });
+            .id();
+        chunk_map.insert(pos, chunk_id);
     }
 
-    fn spawn_around_player(
+    fn spawn_around_loaders(
         mut commands: Commands,
         mut q_chunk: Query<&mut Chunk>,
-        // FIXME: should not require player plugin?,
-        // add some internal position indicator...
-        q_player: Query<&Transform, With<Player>>,
+        q_pending: Query<(Entity, &ChunkGenTask)>,
+        q_loaders: Query<(&GlobalTransform, &ChunkLoader)>,
         settings: Res<TerrainSettings>,
+        store: Res<RegionStore>,
+        mut chunk_map: ResMut<ChunkMap>,
     ) {
-        for player_trans in &q_player {
-            let player_chunk = IVec3 {
-                x: f32::floor(player_trans.translation.x / CHUNK_CUBE_SIZE as f32) as i32,
-                y: f32::floor(player_trans.translation.y / CHUNK_CUBE_SIZE as f32) as i32,
-                z: f32::floor(player_trans.translation.z / CHUNK_CUBE_SIZE as f32) as i32,
-            };
-            let mut wanted_chunks: Vec<IVec3> = Vec::new();
-            for x in
-                (player_chunk.x - CHUNK_SPAWN_DISTANCE)..(player_chunk.x + CHUNK_SPAWN_DISTANCE)
-            {
-                for y in
-                    (player_chunk.y - CHUNK_SPAWN_DISTANCE)..(player_chunk.y + CHUNK_SPAWN_DISTANCE)
-                {
-                    for z in (player_chunk.z - CHUNK_SPAWN_DISTANCE)
-                        ..(player_chunk.z + CHUNK_SPAWN_DISTANCE)
-                    {
-                        wanted_chunks.push(IVec3 { x, y, z });
+        let mut wanted_chunks: HashMap<IVec3, WantedChunk> = HashMap::new();
+
+        for (loader_trans, loader) in &q_loaders {
+            let loader_chunk = world_to_chunk(loader_trans.translation());
+            let radius = loader.radius as i32;
+            for x in (loader_chunk.x - radius)..(loader_chunk.x + radius) {
+                for y in (loader_chunk.y - radius)..(loader_chunk.y + radius) {
+                    for z in (loader_chunk.z - radius)..(loader_chunk.z + radius) {
+                        let pos = IVec3 { x, y, z };
+                        let dist = (pos - loader_chunk).length_squared();
+                        let entry = wanted_chunks.entry(pos).or_insert(WantedChunk {
+                            priority: loader.priority,
+                            dist,
+                            min_dist: dist,
+                        });
+                        if loader.priority > entry.priority
+                            || (loader.priority == entry.priority && dist < entry.dist)
+                        {
+                            entry.priority = loader.priority;
+                            entry.dist = dist;
+                        }
+                        entry.min_dist = entry.min_dist.min(dist);
                     }
                 }
             }
+        }
 
-            for mut chunk in q_chunk.iter_mut() {
-                let mut found: i32 = -1;
-                for i in 0..wanted_chunks.len() {
-                    if wanted_chunks[i] == chunk.position {
-                        found = i as i32;
-                        break;
-                    }
+        let mut lod_changed: Vec<IVec3> = Vec::new();
+        for mut chunk in q_chunk.iter_mut() {
+            if let Some(wanted) = wanted_chunks.remove(&chunk.position) {
+                let lod = Self::lod_for_distance(wanted.min_dist);
+                if chunk.lod != lod {
+                    chunk.lod = lod;
+                    chunk.is_dirty = true;
+                    lod_changed.push(chunk.position);
                 }
-                if found < 0 {
-                    chunk.should_destroy = true;
-                } else {
-                    wanted_chunks.remove(found as usize);
+            } else {
+                chunk.should_destroy = true;
+            }
+        }
+
+        // neighbors build transition cells towards chunks with less
+        // detail than them, so they need remeshing too.
+        for pos in lod_changed {
+            for offset in FACE_OFFSETS {
+                if let Some(nb_id) = chunk_map.get(pos + offset) {
+                    if let Ok(mut nb_chunk) = q_chunk.get_mut(nb_id) {
+                        nb_chunk.is_dirty = true;
+                    }
                 }
             }
+        }
+
+        // cancel generation of chunks no longer wanted,
+        // dropping the task stops it.
+        let mut pending = 0;
+        for (pending_id, gen_task) in &q_pending {
+            if wanted_chunks.remove(&gen_task.position).is_none() {
+                chunk_map.remove(gen_task.position);
+                commands.entity(pending_id).despawn_recursive();
+            } else {
+                pending += 1;
+            }
+        }
 
-            let max_spawn_per_frame = 1;
-            wanted_chunks.sort_by(|a, b| {
-                return (*a - player_chunk)
-                    .length_squared()
-                    .cmp(&(*b - player_chunk).length_squared());
-            });
+        let max_spawn_per_frame = 16.min(MAX_PENDING_CHUNKS.saturating_sub(pending));
+        let mut to_spawn: Vec<(IVec3, WantedChunk)> = wanted_chunks.into_iter().collect();
+        to_spawn.sort_by(|(_, a), (_, b)| {
+            return b.priority.cmp(&a.priority).then(a.dist.cmp(&b.dist));
+        });
 
-            for i in 0..usize::min(wanted_chunks.len(), max_spawn_per_frame) {
-                Self::spawn_chunk(commands.borrow_mut(), &settings, wanted_chunks[i]);
+        for (pos, wanted) in to_spawn.into_iter().take(max_spawn_per_frame) {
+            let lod = Self::lod_for_distance(wanted.min_dist);
+            Self::spawn_chunk(
+                commands.borrow_mut(),
+                &settings,
+                &store,
+                &mut chunk_map,
+                pos,
+                lod,
+            );
+        }
+    }
+
+    fn lod_for_distance(dist_squared: i32) -> u32 {
+        return LOD_DISTANCES
+            .iter()
+            .filter(|lod_dist| dist_squared >= *lod_dist * *lod_dist)
+            .count() as u32;
+    }
+
+    fn poll_chunk_tasks(mut commands: Commands, mut q_pending: Query<(Entity, &mut ChunkGenTask)>) {
+        for (pending_id, mut gen_task) in &mut q_pending {
+            if let Some((chunk, liquid)) = block_on(poll_once(&mut gen_task.task)) {
+                commands
+                    .entity(pending_id)
+                    .insert((chunk, liquid))
+                    .remove::<ChunkGenTask>();
             }
         }
     }
 
     fn read_terrain_events(
         mut events: EventReader<TerrainCellEvent>,
-        mut q_chunks: Query<&mut Chunk>,
+        mut q_chunks: Query<(Entity, &mut Chunk)>,
+        mut q_liquids: Query<&mut ChunkLiquid>,
         q_colliders: Query<&Parent, With<Collider>>,
-        q_player: Query<Entity, With<Player>>,
         rapier_context: Res<RapierContext>,
+        chunk_map: Res<ChunkMap>,
+        settings: Res<TerrainSettings>,
+        mut history: ResMut<EditHistory>,
+        mut mined_events: EventWriter<TerrainMinedEvent>,
+        mut placed_events: EventWriter<TerrainPlacedEvent>,
+        mut edited_events: EventWriter<TerrainEditedEvent>,
+        mut commands: Commands,
+        mut meshes: ResMut<Assets<Mesh>>,
+        material: Res<TerrainMaterialHandle>,
+        q_inventories: Query<&Inventory>,
+        tick: Res<FixedTick>,
     ) {
         for event in events.read() {
             let max_dist = 10.0;
-            let query_filter = QueryFilter {
-                exclude_collider: Some(q_player.get_single().unwrap()),
-                ..default()
+            // only edit terrain, ignore any other colliders in the way
+            let is_chunk_collider = |entity| {
+                return q_colliders
+                    .get(entity)
+                    .is_ok_and(|parent| q_chunks.contains(parent.get()));
             };
-            if let Some((collider_id, toi)) =
-                rapier_context.cast_ray(event.origin, event.dir, max_dist, true, query_filter)
-            {
-                let end_pos = event.origin + event.dir * toi;
-                let mut hit_chunk = None;
+            let query_filter = QueryFilter::new().predicate(&is_chunk_collider);
+            let hit = event.hit.or_else(|| {
+                return rapier_context
+                    .cast_ray(event.origin, event.dir, max_dist, true, query_filter)
+                    .map(|(_, toi)| event.origin + event.dir * toi);
+            });
+            if let Some(end_pos) = hit {
+                // edit every loaded chunk the shape overlaps
+                let (min, max) = event.shape.bounds(end_pos, event.dir);
 
-                if let Ok(chunk_id) = q_colliders.get(collider_id) {
-                    if let Ok(mut chunk) = q_chunks.get_mut(chunk_id.get()) {
-                        chunk.edit(end_pos, event);
-                        hit_chunk = Some(chunk);
+                // smoothing reads the neighbors of the edited cells,
+                // from before any of the chunks they are in are edited
+                let original_values = if event.mode == TerrainEditMode::Smooth {
+                    let (cell_min, cell_max) = bounds_to_cells(min, max);
+                    Self::cell_values(
+                        cell_min - IVec3::ONE,
+                        cell_max + IVec3::ONE,
+                        &q_chunks,
+                        &chunk_map,
+                    )
+                } else {
+                    HashMap::new()
+                };
+                let get_original = |world_cell| original_values.get(&world_cell).copied();
+
+                let mut record = EditRecord {
+                    chunks: Vec::new(),
+                    source: event.source,
+                    hit: end_pos,
+                    removed: Vec::new(),
+                    added: Vec::new(),
+                    debris: Vec::new(),
+                    drops: Vec::new(),
+                };
+                for (chunk_pos, chunk_id) in chunk_map.get_in_bounds(min, max) {
+                    if let Ok((_, mut chunk)) = q_chunks.get_mut(chunk_id) {
+                        let changes = chunk.edit(end_pos, event, &settings.materials, get_original);
+                        if !changes.is_empty() {
+                            record.chunks.push(ChunkEdit {
+                                position: chunk_pos,
+                                changes,
+                            });
+                        }
                     }
                 }
 
-                // edit neighboring chunks if near edge
-                if let Some(hit) = hit_chunk {
-                    // todo:
-                    // filter to chunks actually in range.
-                    // no need to check all 26 if in one corner.
-                    // also disallows radiuses larger than 1 chunk...
-                    let neighbors = hit.get_neighbors();
-                    for mut chunk in q_chunks.iter_mut() {
-                        for nb in neighbors {
-                            if chunk.position == nb {
-                                chunk.edit(end_pos, event);
-                            }
+                if record.chunks.is_empty() {
+                    continue;
+                }
+
+                record.removed = record.removed_materials();
+                record.added = record.added_materials();
+
+                // edits by a source with an inventory can only add
+                // material it holds, undo any that add more
+                let inventory = event
+                    .source
+                    .and_then(|source| q_inventories.get(source).ok());
+                if let Some(inventory) = inventory {
+                    let is_held = record.added.iter().all(|(material, amount)| {
+                        return inventory.amount_of(*material) + MATERIAL_AMOUNT_TOLERANCE
+                            >= *amount;
+                    });
+                    if !is_held {
+                        for chunk_edit in record.chunks.iter().rev() {
+                            let chunk_id = chunk_map.get(chunk_edit.position).unwrap();
+                            let (_, mut chunk) = q_chunks.get_mut(chunk_id).unwrap();
+                            chunk.apply_changes(&chunk_edit.changes, true);
                         }
+                        continue;
                     }
                 }
+
+                // rock that caves in falls as debris instead of being mined
+                Self::drop_mined_materials(&mut record, &mut commands, &mut mined_events);
+                if !record.added.is_empty() {
+                    placed_events.send(TerrainPlacedEvent {
+                        position: end_pos,
+                        source: event.source,
+                        amounts: record.added.clone(),
+                    });
+                }
+
+                Self::collapse_islands(
+                    bounds_to_cells(min, max),
+                    &mut q_chunks,
+                    &chunk_map,
+                    &mut record,
+                    &mut commands,
+                    &mut meshes,
+                    &material,
+                );
+
+                for chunk_edit in &record.chunks {
+                    Self::wake_liquid(chunk_edit.position, &chunk_map, &mut q_liquids);
+                }
+                history.push(record);
+                edited_events.send(TerrainEditedEvent {
+                    edit: TerrainEdit::Cell(TerrainCellEvent {
+                        hit: Some(end_pos),
+                        ..event.clone()
+                    }),
+                    tick: tick.0,
+                });
             }
         }
     }
 
-    fn update_chunks(
-        mut q_chunks: Query<(Entity, &mut Chunk, &Children)>,
+    // Gets the values of the loaded cells between two world cells.
+    fn cell_values(
+        min: IVec3,
+        max: IVec3,
+        q_chunks: &Query<(Entity, &mut Chunk)>,
+        chunk_map: &ChunkMap,
+    ) -> HashMap<IVec3, f32> {
+        let mut values = HashMap::new();
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    let world_cell = IVec3 { x, y, z };
+                    let chunk_pos = world_cell.div_euclid(IVec3::splat(CHUNK_CUBE_SIZE as i32));
+                    let cell = chunk_map
+                        .get(chunk_pos)
+                        .and_then(|chunk_id| q_chunks.get(chunk_id).ok())
+                        .and_then(|(_, chunk)| chunk.get_cell(world_cell));
+                    if let Some(cell) = cell {
+                        values.insert(world_cell, cell.value);
+                    }
+                }
+            }
+        }
+        return values;
+    }
+
+    fn read_history_events(
+        mut history_events: HistoryEvents,
+        mut q_chunks: Query<&mut Chunk>,
+        mut q_liquids: Query<&mut ChunkLiquid>,
+        q_debris: Query<&Debris>,
+        q_drops: Query<(), With<ItemDrop>>,
+        mut q_inventories: Query<&mut Inventory>,
+        store: Res<RegionStore>,
+        mut history: ResMut<EditHistory>,
+        mut pending: ResMut<PendingHistorySteps>,
+        mut mined_events: EventWriter<TerrainMinedEvent>,
+        mut edited_events: EventWriter<TerrainEditedEvent>,
+        mut chunk_map: ResMut<ChunkMap>,
         mut commands: Commands,
         mut meshes: ResMut<Assets<Mesh>>,
-        mut materials: ResMut<Assets<StandardMaterial>>,
+        material: Res<TerrainMaterialHandle>,
+        tick: Res<FixedTick>,
+    ) {
+        pending
+            .steps
+            .extend(history_events.undo.read().map(|_| HistoryStep::Undo));
+        pending
+            .steps
+            .extend(history_events.redo.read().map(|_| HistoryStep::Redo));
+
+        if let Some(task) = &mut pending.reading {
+            if block_on(poll_once(task)).is_none() {
+                return;
+            }
+            pending.reading = None;
+        }
+
+        let mut changed: Vec<IVec3> = Vec::new();
+        while let Some(step) = pending.steps.front().copied() {
+            let revert = step == HistoryStep::Undo;
+
+            // unloaded chunks are changed in the region store,
+            // which mustn't read from disk on the main thread
+            let next_record = if revert {
+                history.peek_undo()
+            } else {
+                history.peek_redo()
+            };
+            let unread: Vec<IVec3> = next_record
+                .iter()
+                .flat_map(|record| record.chunks.iter())
+                .map(|chunk_edit| chunk_edit.position)
+                .filter(|chunk_pos| {
+                    let is_loaded = chunk_map
+                        .get(*chunk_pos)
+                        .is_some_and(|chunk_id| q_chunks.contains(chunk_id));
+                    return !is_loaded && !store.is_region_read(*chunk_pos);
+                })
+                .collect();
+            if !unread.is_empty() {
+                let store = store.clone();
+                pending.reading = Some(AsyncComputeTaskPool::get().spawn(async move {
+                    for chunk_pos in unread {
+                        store.read_region_of(chunk_pos);
+                    }
+                }));
+                break;
+            }
+
+            pending.steps.pop_front();
+            let record = if revert {
+                history.pop_undo()
+            } else {
+                history.pop_redo()
+            };
+            let Some(mut record) = record else {
+                continue;
+            };
+            let edited_event = TerrainEditedEvent {
+                edit: if revert {
+                    TerrainEdit::Undo
+                } else {
+                    TerrainEdit::Redo
+                },
+                tick: tick.0,
+            };
+
+            if !Self::is_record_current(&record, revert, &q_chunks, &store, &chunk_map) {
+                if revert {
+                    warn!("Terrain changed since the edit, dropping it from the history");
+                } else {
+                    warn!(
+                        "Terrain changed since the edit was undone, dropping it from the history"
+                    );
+                }
+                edited_events.send(edited_event);
+                continue;
+            }
+            if !Self::exchange_edit_materials(&record, revert, &mut q_inventories) {
+                if revert {
+                    warn!("Not holding the material the edit removed, can't undo it");
+                    history.push_undo(record);
+                } else {
+                    warn!("Not holding the material the edit added, can't redo it");
+                    history.push_redo(record);
+                }
+                continue;
+            }
+
+            if !revert {
+                // the rock breaks off again, meshed before its cells are cleared
+                let get_cell = |world_cell: IVec3| {
+                    let chunk_pos = world_cell.div_euclid(IVec3::splat(CHUNK_CUBE_SIZE as i32));
+                    return chunk_map
+                        .get(chunk_pos)
+                        .and_then(|chunk_id| q_chunks.get(chunk_id).ok())
+                        .and_then(|chunk| chunk.get_cell(world_cell));
+                };
+                for debris in &mut record.debris {
+                    // rock in unloaded chunks is only cleared
+                    if debris.cells.iter().all(|cell| get_cell(*cell).is_some()) {
+                        debris.entity = Self::spawn_debris(
+                            &debris.cells,
+                            get_cell,
+                            &mut commands,
+                            &mut meshes,
+                            &material,
+                        );
+                    }
+                }
+            }
+
+            Self::apply_edit_record(
+                &record,
+                revert,
+                &mut q_chunks,
+                &store,
+                &mut chunk_map,
+                &mut commands,
+            );
+
+            if revert {
+                // the material is back in the terrain, drops still lying
+                // around go with it, picked up ones were taken back
+                for drop in &mut record.drops {
+                    let Some(drop_id) = drop.entity.take() else {
+                        continue;
+                    };
+                    if !drop.is_collected && q_drops.contains(drop_id) {
+                        commands.entity(drop_id).despawn_recursive();
+                    }
+                    drop.is_collected = false;
+                }
+
+                // the rock is back in the terrain
+                for debris in &mut record.debris {
+                    let Some(debris_id) = debris.entity.take() else {
+                        continue;
+                    };
+                    // may have been despawned already
+                    if let Ok(debris) = q_debris.get(debris_id) {
+                        meshes.remove(&debris.mesh_handle);
+                        commands.entity(debris_id).despawn_recursive();
+                    }
+                }
+            }
+
+            if !revert {
+                Self::drop_mined_materials(&mut record, &mut commands, &mut mined_events);
+            }
+
+            changed.extend(record.chunks.iter().map(|chunk_edit| chunk_edit.position));
+            if revert {
+                history.push_redo(record);
+            } else {
+                history.push_undo(record);
+            }
+            edited_events.send(edited_event);
+        }
+
+        for chunk_pos in changed {
+            Self::wake_liquid(chunk_pos, &chunk_map, &mut q_liquids);
+        }
+    }
+
+    // Whether the cells an edit changed are still as it left them,
+    // or as they were before it when reapplying it. Reverting an edit
+    // after something else changed its cells would overwrite that change.
+    fn is_record_current(
+        record: &EditRecord,
+        revert: bool,
+        q_chunks: &Query<&mut Chunk>,
+        store: &RegionStore,
+        chunk_map: &ChunkMap,
+    ) -> bool {
+        let mut stored_chunks: HashMap<IVec3, Option<StoredChunk>> = HashMap::new();
+        for ((chunk_pos, index), expected) in record.expected_cells(revert) {
+            let loaded = chunk_map
+                .get(chunk_pos)
+                .and_then(|chunk_id| q_chunks.get(chunk_id).ok());
+            let current = match loaded {
+                Some(chunk) => Some(chunk.cells[index]),
+                None => stored_chunks
+                    .entry(chunk_pos)
+                    .or_insert_with(|| store.load_chunk(chunk_pos))
+                    .as_ref()
+                    .map(|stored| stored.cells[index]),
+            };
+            // chunks that can't be found can't be changed either
+            if current.is_some_and(|cell| cell != expected) {
+                return false;
+            }
+        }
+        return true;
+    }
+
+    // Sends the materials an edit removed to be dropped,
+    // keeping the entities of the drops in its record.
+    fn drop_mined_materials(
+        record: &mut EditRecord,
+        commands: &mut Commands,
+        mined_events: &mut EventWriter<TerrainMinedEvent>,
+    ) {
+        if record.removed.is_empty() {
+            return;
+        }
+        record.drops = record
+            .removed
+            .iter()
+            .map(|(material, amount)| DropRecord {
+                material: *material,
+                amount: *amount,
+                entity: Some(commands.spawn_empty().id()),
+                is_collected: false,
+            })
+            .collect();
+        mined_events.send(TerrainMinedEvent {
+            position: record.hit,
+            amounts: record.removed.clone(),
+            drops: record.drops.iter().filter_map(|drop| drop.entity).collect(),
+        });
+    }
+
+    // Moves the materials of an edit being reverted or reapplied between
+    // the terrain and the inventory of its source, so undoing a placement
+    // refunds it and undoing mining takes back the drops picked up.
+    // Returns false without changing anything if the source doesn't
+    // hold the material going back into the terrain.
+    fn exchange_edit_materials(
+        record: &EditRecord,
+        revert: bool,
+        q_inventories: &mut Query<&mut Inventory>,
+    ) -> bool {
+        let Some(mut inventory) = record
+            .source
+            .and_then(|source| q_inventories.get_mut(source).ok())
+        else {
+            // edits made without an inventory didn't cost anything either
+            return true;
+        };
+
+        // mined material is dropped again when redone
+        let (taken, given) = if revert {
+            (record.collected_materials(), record.added.clone())
+        } else {
+            (record.added.clone(), Vec::new())
+        };
+        let is_held = taken.iter().all(|(material, amount)| {
+            return inventory.amount_of(*material) + MATERIAL_AMOUNT_TOLERANCE >= *amount;
+        });
+        if !is_held {
+            return false;
+        }
+
+        for (material, amount) in taken {
+            inventory.take(material, amount);
+        }
+        for (material, amount) in given {
+            inventory.add(material, amount);
+        }
+        return true;
+    }
+
+    // Reverts or reapplies an edit.
+    // Edited chunks that have since been unloaded were saved
+    // to the region store, so they are changed there instead.
+    // Their regions have been read in by read_history_events.
+    fn apply_edit_record(
+        record: &EditRecord,
+        revert: bool,
+        q_chunks: &mut Query<&mut Chunk>,
+        store: &RegionStore,
+        chunk_map: &mut ChunkMap,
+        commands: &mut Commands,
+    ) {
+        // reverted in reverse, chunks can be in a record more than once
+        let chunk_edits: Vec<&ChunkEdit> = if revert {
+            record.chunks.iter().rev().collect()
+        } else {
+            record.chunks.iter().collect()
+        };
+        for chunk_edit in chunk_edits {
+            let chunk_id = chunk_map.get(chunk_edit.position);
+            if let Some(mut chunk) = chunk_id.and_then(|chunk_id| q_chunks.get_mut(chunk_id).ok()) {
+                chunk.apply_changes(&chunk_edit.changes, revert);
+            } else if let Some(mut stored) = store.load_chunk(chunk_edit.position) {
+                Chunk::apply_changes_to_cells(&mut stored.cells, &chunk_edit.changes, revert);
+                store.store_chunk(chunk_edit.position, &stored.cells, stored.liquid.as_deref());
+
+                // a chunk still being generated may have loaded the old
+                // cells already, cancel it so it is loaded again.
+                if let Some(pending_id) = chunk_id {
+                    chunk_map.remove(chunk_edit.position);
+                    commands.entity(pending_id).despawn_recursive();
+                }
+            }
+        }
+    }
+
+    // Breaks off the rock an edit left without support, spawning it as
+    // falling debris. The cleared cells are added to the edit record.
+    fn collapse_islands(
+        (min, max): (IVec3, IVec3),
+        q_chunks: &mut Query<(Entity, &mut Chunk)>,
+        chunk_map: &ChunkMap,
+        record: &mut EditRecord,
+        commands: &mut Commands,
+        meshes: &mut Assets<Mesh>,
+        material: &TerrainMaterialHandle,
     ) {
-        for (chunk_id, mut chunk, children) in &mut q_chunks {
+        let get_cell = |world_cell: IVec3| {
+            let chunk_pos = world_cell.div_euclid(IVec3::splat(CHUNK_CUBE_SIZE as i32));
+            return chunk_map
+                .get(chunk_pos)
+                .and_then(|chunk_id| q_chunks.get(chunk_id).ok())
+                .and_then(|(_, chunk)| chunk.get_cell(world_cell));
+        };
+
+        let islands = find_islands(min, max, |cell| get_cell(cell).map(|c| is_solid(&c)));
+        for island in &islands {
+            if island.len() < MIN_DEBRIS_CELLS {
+                continue;
+            }
+            if let Some(debris_id) =
+                Self::spawn_debris(island, get_cell, commands, meshes, material)
+            {
+                record.debris.push(DebrisRecord {
+                    cells: island.clone(),
+                    entity: Some(debris_id),
+                });
+            }
+        }
+
+        for island in &islands {
+            let island_min = island.iter().copied().fold(IVec3::MAX, IVec3::min);
+            let island_max = island.iter().copied().fold(IVec3::MIN, IVec3::max);
+            for (chunk_pos, chunk_id) in
+                chunk_map.get_in_bounds(island_min.as_vec3(), island_max.as_vec3())
+            {
+                if let Ok((_, mut chunk)) = q_chunks.get_mut(chunk_id) {
+                    let changes = chunk.clear_cells(island);
+                    if !changes.is_empty() {
+                        record.chunks.push(ChunkEdit {
+                            position: chunk_pos,
+                            changes,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    // Spawns the rock in the given world cells as falling debris.
+    fn spawn_debris(
+        island: &[IVec3],
+        get_cell: impl Fn(IVec3) -> Option<Cell>,
+        commands: &mut Commands,
+        meshes: &mut Assets<Mesh>,
+        material: &TerrainMaterialHandle,
+    ) -> Option<Entity> {
+        let (origin, chunk_mesh) = island_mesh(island, |cell| get_cell(cell).unwrap_or_default());
+        let mesh = chunk_mesh.mesh?;
+        let collider = Collider::from_bevy_mesh(&mesh, &ComputedColliderShape::ConvexHull)?;
+
+        let mesh_handle = meshes.add(mesh);
+        let debris_id = commands
+            .spawn((
+                MaterialMeshBundle {
+                    mesh: mesh_handle.clone(),
+                    material: material.0.clone(),
+                    transform: Transform::from_translation(origin.as_vec3()),
+                    ..default()
+                },
+                RigidBody::Dynamic,
+                collider,
+                Debris {
+                    timer: Timer::from_seconds(DEBRIS_LIFETIME_SECS, TimerMode::Once),
+                    mesh_handle,
+                },
+            ))
+            .id();
+        return Some(debris_id);
+    }
+
+    fn despawn_debris(
+        mut commands: Commands,
+        mut q_debris: Query<(Entity, &mut Debris)>,
+        mut meshes: ResMut<Assets<Mesh>>,
+        time: Res<Time>,
+    ) {
+        for (debris_id, mut debris) in &mut q_debris {
+            if debris.timer.tick(time.delta()).just_finished() {
+                meshes.remove(&debris.mesh_handle);
+                commands.entity(debris_id).despawn_recursive();
+            }
+        }
+    }
+
+    fn update_chunks(
+        mut q_chunks: Query<(Entity, &mut Chunk)>,
+        q_liquids: Query<&ChunkLiquid>,
+        mut commands: Commands,
+        store: Res<RegionStore>,
+        mut chunk_map: ResMut<ChunkMap>,
+        settings: Res<TerrainSettings>,
+    ) {
+        let thread_pool = AsyncComputeTaskPool::get();
+        let smooth = settings.mesh_mode == MeshMode::Smooth;
+
+        if smooth {
+            let added_chunks: Vec<[IVec3; 6]> = q_chunks
+                .iter_mut()
+                .filter(|(_, chunk)| chunk.is_added())
+                .map(|(_, chunk)| chunk.get_face_neighbors())
+                .collect();
+
+            // normals on the borders of existing neighbors
+            // depend on the new chunks, remesh them.
+            for neighbors in added_chunks {
+                for nb in neighbors {
+                    if let Some(nb_id) = chunk_map.get(nb) {
+                        if let Ok((_, mut nb_chunk)) = q_chunks.get_mut(nb_id) {
+                            nb_chunk.is_dirty = true;
+                        }
+                    }
+                }
+            }
+        }
+
+        // Mesh a copy of the cells so edits can keep
+        // happening while the task runs.
+        let mut mesh_inputs: HashMap<Entity, ChunkMeshInput> = HashMap::new();
+        for (chunk_id, chunk) in q_chunks.iter() {
+            if !chunk.is_dirty || chunk.should_destroy {
+                continue;
+            }
+
+            let get_chunk = |pos| {
+                return chunk_map
+                    .get(pos)
+                    .and_then(|id| q_chunks.get(id).ok())
+                    .map(|(_, nb)| nb);
+            };
+
+            let padded_values = if smooth {
+                Some(chunk.padded_values(get_chunk))
+            } else {
+                None
+            };
+            // Missing neighbors are treated as having the same detail.
+            // Transition cells only join neighbors one level coarser,
+            // ones further off are remeshed once their detail catches up.
+            let neighbor_lods = chunk.get_face_neighbors().map(|nb| {
+                return get_chunk(nb)
+                    .map_or(chunk.lod, |nb| nb.lod)
+                    .min(chunk.lod + 1);
+            });
+
+            mesh_inputs.insert(
+                chunk_id,
+                ChunkMeshInput {
+                    position: chunk.position,
+                    cells: Box::new(chunk.cells),
+                    padded_values,
+                    lod: chunk.lod,
+                    neighbor_lods,
+                    // chunks near the loaders are at full detail,
+                    // nothing far from them needs to collide
+                    collider: chunk.lod == 0,
+                },
+            );
+        }
+
+        for (chunk_id, mut chunk) in &mut q_chunks {
             if chunk.should_destroy {
+                let liquid = q_liquids.get(chunk_id).ok();
+                if chunk.is_modified || liquid.is_some_and(|liquid| liquid.is_modified) {
+                    let liquid_cells = liquid.map(|liquid| liquid.cells.as_slice());
+                    store.store_chunk(chunk.position, &chunk.cells, liquid_cells);
+                }
+                chunk_map.remove(chunk.position);
                 commands.entity(chunk_id).despawn_recursive();
                 continue;
             }
 
-            let mut mesh_updated = false;
-            if chunk.is_dirty {
-                chunk.mesh = chunk.polygonize();
-                mesh_updated = true;
+            if let Some(input) = mesh_inputs.remove(&chunk_id) {
+                chunk.is_dirty = false;
+
+                // Any task already running for the chunk is replaced
+                // and cancelled since its result would be outdated.
+                let task = thread_pool.spawn(async move {
+                    return Chunk::polygonize(input);
+                });
+                commands.entity(chunk_id).insert(ChunkMeshTask(task));
             }
-            let mesh_valid = chunk.mesh.is_some();
+        }
+    }
+
+    fn apply_chunk_meshes(
+        mut q_chunks: Query<(Entity, &mut Chunk, &mut ChunkMeshTask, Option<&Children>)>,
+        q_liquid_meshes: Query<(), With<LiquidMesh>>,
+        mut commands: Commands,
+        mut meshes: ResMut<Assets<Mesh>>,
+        material: Res<TerrainMaterialHandle>,
+    ) {
+        for (chunk_id, mut chunk, mut mesh_task, children) in &mut q_chunks {
+            let Some(chunk_mesh) = block_on(poll_once(&mut mesh_task.0)) else {
+                continue;
+            };
+            commands.entity(chunk_id).remove::<ChunkMeshTask>();
 
             // TODO: Can we update the mesh in place instead?
-            let destroy_children = mesh_updated;
-            let spawn_children = mesh_updated && mesh_valid;
+            if let Some(handle) = &chunk.mesh_handle {
+                meshes.remove(handle);
+            }
 
-            if destroy_children {
-                if let Some(handle) = &chunk.mesh_handle {
-                    meshes.remove(handle);
+            if let Some(children) = children {
+                // liquid meshes are replaced separately
+                for child in children.iter().filter(|id| !q_liquid_meshes.contains(**id)) {
+                    commands.entity(*child).despawn_recursive();
                 }
-                if let Some(handle) = &chunk.material_handle {
-                    materials.remove(handle);
+            }
+
+            chunk.mesh_handle = None;
+            chunk.collider = chunk_mesh.collider;
+
+            if let Some(mesh) = chunk_mesh.mesh {
+                let mesh_handle = meshes.add(mesh);
+                chunk.mesh_handle = Some(mesh_handle.clone());
+
+                let pbr_id = commands
+                    .spawn(MaterialMeshBundle {
+                        mesh: mesh_handle,
+                        material: material.0.clone(),
+                        ..default()
+                    })
+                    .id();
+                commands.entity(chunk_id).add_child(pbr_id);
+            }
+
+            if let Some(collider) = chunk.collider.clone() {
+                let col_id = commands.spawn(collider).id();
+                commands.entity(chunk_id).add_child(col_id);
+            }
+        }
+    }
+
+    fn setup_material(
+        mut commands: Commands,
+        mut materials: ResMut<Assets<TerrainMaterial>>,
+        mut images: ResMut<Assets<Image>>,
+        cell_materials: Res<CellMaterialRegistry>,
+    ) {
+        let handle = materials.add(TerrainMaterial {
+            base: StandardMaterial { ..default() },
+            extension: TerrainMaterialExtension::new(&cell_materials, &mut images),
+        });
+        commands.insert_resource(TerrainMaterialHandle(handle));
+    }
+
+    // Wakes the liquid in a chunk and its face neighbors,
+    // which may flow again after the chunk changed.
+    fn wake_liquid(
+        chunk_pos: IVec3,
+        chunk_map: &ChunkMap,
+        q_liquids: &mut Query<&mut ChunkLiquid>,
+    ) {
+        let positions =
+            std::iter::once(chunk_pos).chain(FACE_OFFSETS.map(|offset| chunk_pos + offset));
+        for pos in positions {
+            if let Some(chunk_id) = chunk_map.get(pos) {
+                if let Ok(mut liquid) = q_liquids.get_mut(chunk_id) {
+                    liquid.is_active = true;
                 }
+            }
+        }
+    }
 
-                for child in children {
-                    commands.entity(*child).despawn_recursive();
+    // Liquid of neighbors can flow into new chunks,
+    // and neighbors copy their borders for meshing.
+    fn wake_new_liquids(
+        q_added: Query<&Chunk, Added<Chunk>>,
+        mut q_liquids: Query<&mut ChunkLiquid>,
+        chunk_map: Res<ChunkMap>,
+    ) {
+        for chunk in &q_added {
+            Self::wake_liquid(chunk.position, &chunk_map, &mut q_liquids);
+            for offset in MIRROR_OFFSETS {
+                if let Some(chunk_id) = chunk_map.get(chunk.position + offset) {
+                    if let Ok(mut liquid) = q_liquids.get_mut(chunk_id) {
+                        liquid.is_dirty = true;
+                    }
                 }
+            }
+        }
+    }
+
+    fn simulate_liquids(
+        mut q_liquids: Query<(Entity, &Chunk, &mut ChunkLiquid)>,
+        chunk_map: Res<ChunkMap>,
+    ) {
+        let active: Vec<Entity> = q_liquids
+            .iter()
+            .filter(|(_, _, liquid)| liquid.is_active)
+            .map(|(chunk_id, _, _)| chunk_id)
+            .collect();
 
-                chunk.mesh_handle = None;
-                chunk.material_handle = None;
-                chunk.collider = None;
+        let mut transfers = LiquidTransfers::new();
+        let mut changed: Vec<IVec3> = Vec::new();
+        for chunk_id in active {
+            let Ok((_, _, mut liquid)) = q_liquids.get_mut(chunk_id) else {
+                continue;
+            };
+            let mut cells = std::mem::take(&mut liquid.cells);
+
+            let (_, chunk, _) = q_liquids.get(chunk_id).unwrap();
+            let get_neighbor = |pos| {
+                return chunk_map
+                    .get(pos)
+                    .and_then(|id| q_liquids.get(id).ok())
+                    .map(|(_, nb_chunk, nb_liquid)| (nb_chunk, nb_liquid));
+            };
+            let moved = ChunkLiquid::step(chunk, &mut cells, &get_neighbor, &mut transfers);
+            let chunk_pos = chunk.position;
+
+            let (_, _, mut liquid) = q_liquids.get_mut(chunk_id).unwrap();
+            liquid.cells = cells;
+            if moved > 0.0 {
+                liquid.is_dirty = true;
+                liquid.is_modified = true;
+                changed.push(chunk_pos);
             }
+            if ChunkLiquid::is_settled(moved) {
+                liquid.is_active = false;
+            }
+        }
 
-            if spawn_children {
-                // TODO: fix all the clones
-                if let Some(mesh) = chunk.mesh.clone() {
-                    let mesh_handle = meshes.add(mesh.clone());
-                    chunk.mesh_handle = Some(mesh_handle.clone());
-                    let shape = ComputedColliderShape::TriMesh;
-                    chunk.collider = Collider::from_bevy_mesh(&mesh, &shape);
+        for ((chunk_pos, index), transfer) in transfers {
+            if let Some(chunk_id) = chunk_map.get(chunk_pos) {
+                if let Ok((_, _, mut liquid)) = q_liquids.get_mut(chunk_id) {
+                    liquid.receive(index, transfer);
+                }
+            }
+        }
 
-                    let pbr_id = commands
-                        .spawn(PbrBundle {
-                            mesh: mesh_handle,
-                            material: materials.add(StandardMaterial {
-                                metallic: 0.0,
-                                perceptual_roughness: 0.8,
-                                ..default()
-                            }),
+        // neighbors may flow into the space that was freed up,
+        // and the ones copying the changed borders need remeshing
+        for chunk_pos in changed {
+            for offset in FACE_OFFSETS {
+                if let Some(chunk_id) = chunk_map.get(chunk_pos + offset) {
+                    if let Ok((_, _, mut liquid)) = q_liquids.get_mut(chunk_id) {
+                        liquid.is_active = true;
+                    }
+                }
+            }
+            for offset in MIRROR_OFFSETS {
+                if let Some(chunk_id) = chunk_map.get(chunk_pos + offset) {
+                    if let Ok((_, _, mut liquid)) = q_liquids.get_mut(chunk_id) {
+                        liquid.is_dirty = true;
+                    }
+                }
+            }
+        }
+    }
+
+    // Rebuilds the meshes of changed liquid in the background,
+    // throttled since flowing liquid changes every step.
+    fn update_liquid_meshes(
+        mut commands: Commands,
+        mut q_liquids: Query<(Entity, &Chunk, &mut ChunkLiquid)>,
+        chunk_map: Res<ChunkMap>,
+        mut timer: ResMut<LiquidMeshTimer>,
+        time: Res<Time>,
+    ) {
+        if !timer.0.tick(time.delta()).just_finished() {
+            return;
+        }
+
+        let dirty: Vec<Entity> = q_liquids
+            .iter()
+            .filter(|(_, chunk, liquid)| liquid.is_dirty && !chunk.should_destroy)
+            .map(|(chunk_id, _, _)| chunk_id)
+            .take(MAX_LIQUID_MESHES_PER_UPDATE)
+            .collect();
+
+        let thread_pool = AsyncComputeTaskPool::get();
+        for chunk_id in dirty {
+            let (_, chunk, liquid) = q_liquids.get(chunk_id).unwrap();
+            let get_neighbor = |pos| {
+                return chunk_map
+                    .get(pos)
+                    .and_then(|id| q_liquids.get(id).ok())
+                    .map(|(_, _, nb_liquid)| nb_liquid);
+            };
+
+            let mut mesh_inputs: Vec<(LiquidType, ChunkMeshInput)> = Vec::new();
+            for kind in LiquidType::ALL {
+                let Some(cells) = liquid.mesh_cells(chunk.position, kind, get_neighbor) else {
+                    continue;
+                };
+                let input = ChunkMeshInput {
+                    position: chunk.position,
+                    cells,
+                    padded_values: None,
+                    lod: 0,
+                    neighbor_lods: [0; 6],
+                    collider: false,
+                };
+                mesh_inputs.push((kind, input));
+            }
+
+            let task = thread_pool.spawn(async move {
+                return mesh_inputs
+                    .into_iter()
+                    .filter_map(|(kind, input)| Some((kind, Chunk::polygonize(input).mesh?)))
+                    .collect();
+            });
+            // replaces a task still running for older liquid
+            commands.entity(chunk_id).insert(LiquidMeshTask(task));
+
+            let (_, _, mut liquid) = q_liquids.get_mut(chunk_id).unwrap();
+            liquid.is_dirty = false;
+        }
+    }
+
+    fn apply_liquid_meshes(
+        mut commands: Commands,
+        mut q_tasks: Query<(Entity, &mut ChunkLiquid, &mut LiquidMeshTask)>,
+        mut meshes: ResMut<Assets<Mesh>>,
+        materials: Res<LiquidMaterials>,
+    ) {
+        for (chunk_id, mut liquid, mut mesh_task) in &mut q_tasks {
+            let Some(new_meshes) = block_on(poll_once(&mut mesh_task.0)) else {
+                continue;
+            };
+            commands.entity(chunk_id).remove::<LiquidMeshTask>();
+
+            for handle in liquid.mesh_handles.drain(..) {
+                meshes.remove(&handle);
+            }
+            for mesh_id in liquid.mesh_entities.drain(..) {
+                commands.entity(mesh_id).despawn_recursive();
+            }
+
+            for (kind, mesh) in new_meshes {
+                let mesh_handle = meshes.add(mesh);
+                let mesh_id = commands
+                    .spawn((
+                        PbrBundle {
+                            mesh: mesh_handle.clone(),
+                            material: materials.get(kind),
                             ..default()
-                        })
-                        .id();
+                        },
+                        LiquidMesh,
+                    ))
+                    .id();
+                commands.entity(chunk_id).add_child(mesh_id);
+                liquid.mesh_handles.push(mesh_handle);
+                liquid.mesh_entities.push(mesh_id);
+            }
+        }
+    }
 
-                    let col_id = commands.spawn(chunk.collider.clone().unwrap()).id();
+    fn setup_liquid_materials(
+        mut commands: Commands,
+        mut materials: ResMut<Assets<StandardMaterial>>,
+    ) {
+        commands.insert_resource(LiquidMaterials::new(&mut materials));
+    }
 
-                    commands.entity(chunk_id).add_child(pbr_id);
-                    commands.entity(chunk_id).add_child(col_id);
-                }
+    fn save_modified_chunks(
+        q_chunks: &mut Query<(&mut Chunk, &mut ChunkLiquid)>,
+        store: &RegionStore,
+    ) {
+        for (mut chunk, mut liquid) in q_chunks.iter_mut() {
+            if chunk.is_modified || liquid.is_modified {
+                store.store_chunk(chunk.position, &chunk.cells, Some(&liquid.cells));
+                chunk.is_modified = false;
+                liquid.is_modified = false;
             }
         }
+        store.flush();
+    }
+
+    fn count_fixed_tick(mut tick: ResMut<FixedTick>) {
+        tick.0 += 1;
+    }
+
+    fn autosave(
+        mut q_chunks: Query<(&mut Chunk, &mut ChunkLiquid)>,
+        store: Res<RegionStore>,
+        mut timer: ResMut<AutosaveTimer>,
+        time: Res<Time>,
+    ) {
+        if timer.0.tick(time.delta()).just_finished() {
+            Self::save_modified_chunks(&mut q_chunks, &store);
+        }
+    }
+
+    fn save_on_exit(
+        mut exit_events: EventReader<AppExit>,
+        mut q_chunks: Query<(&mut Chunk, &mut ChunkLiquid)>,
+        store: Res<RegionStore>,
+    ) {
+        if exit_events.read().last().is_some() {
+            Self::save_modified_chunks(&mut q_chunks, &store);
+        }
     }
 }
 
 impl Plugin for TerrainPlugin {
     fn build(&self, app: &mut App) {
+        let materials = match &self.materials_path {
+            Some(path) => CellMaterialRegistry::load(path).unwrap_or_else(|err| {
+                error!("Failed to load materials {:?}: {}", path, err);
+                return CellMaterialRegistry::default();
+            }),
+            None => CellMaterialRegistry::default(),
+        };
+
+        let biomes = match &self.biomes_path {
+            Some(path) => BiomeMap::load_biomes(path, &materials).unwrap_or_else(|err| {
+                error!("Failed to load biomes {:?}: {}", path, err);
+                return BiomeMap::default_biomes(&materials);
+            }),
+            None => BiomeMap::default_biomes(&materials),
+        };
+
+        let density = match &self.density_path {
+            Some(path) => DensityGraph::load(path, self.seed).unwrap_or_else(|err| {
+                error!("Failed to load density graph {:?}: {}", path, err);
+                return DensityGraph::default();
+            }),
+            None => DensityGraph::default(),
+        };
+
+        let ores = match &self.ores_path {
+            Some(path) => OreRules::load_ores(path, &materials).unwrap_or_else(|err| {
+                error!("Failed to load ores {:?}: {}", path, err);
+                return OreRules::default_ores(&materials);
+            }),
+            None => OreRules::default_ores(&materials),
+        };
+
+        let material_names = materials
+            .iter()
+            .map(|(_, material)| material.name.clone())
+            .collect();
+        // saving over regions of another world would mix the two
+        let store = RegionStore::new(self.save_dir.clone(), self.seed, material_names)
+            .unwrap_or_else(|err| {
+                panic!("Can't use save directory {:?}: {}", self.save_dir, err);
+            });
+
         app.insert_resource(TerrainSettings {
+            seed: self.seed,
             fbm: Fbm::<Perlin>::new(self.seed),
             fbm_scale: 0.02,
             type_noise: Perlin::new(self.seed),
             type_noise_scale: 0.05,
+            mesh_mode: self.mesh_mode,
+            materials: materials.clone(),
+            biomes: BiomeMap::new(biomes, self.seed, BIOME_NOISE_SCALE),
+            density,
+            ores: OreRules::new(ores, self.seed),
+            carvers: WormCarvers::new(self.seed),
         })
+        .insert_resource(materials)
+        .add_plugins(MaterialPlugin::<TerrainMaterial>::default())
+        .init_resource::<ChunkMap>()
+        .insert_resource(store)
+        .insert_resource(AutosaveTimer(Timer::new(
+            Duration::from_secs(AUTOSAVE_INTERVAL_SECS),
+            TimerMode::Repeating,
+        )))
+        .insert_resource(LiquidMeshTimer(Timer::from_seconds(
+            LIQUID_MESH_INTERVAL_SECS,
+            TimerMode::Repeating,
+        )))
+        .insert_resource(EditHistory::new(EDIT_HISTORY_SIZE))
+        .init_resource::<PendingHistorySteps>()
+        .init_resource::<FixedTick>()
         .add_event::<TerrainCellEvent>()
-        .add_systems(Update, Self::spawn_around_player)
-        .add_systems(Update, Self::read_terrain_events)
-        .add_systems(Update, Self::update_chunks);
+        .add_event::<TerrainMinedEvent>()
+        .add_event::<TerrainPlacedEvent>()
+        .add_event::<TerrainEditedEvent>()
+        .add_event::<UndoTerrainEdit>()
+        .add_event::<RedoTerrainEdit>()
+        // chained so commands despawning chunks are applied
+        // before later systems try to modify them
+        .add_systems(
+            Update,
+            (
+                Self::spawn_around_loaders,
+                Self::poll_chunk_tasks,
+                Self::wake_new_liquids,
+                Self::update_chunks,
+                Self::apply_chunk_meshes,
+                Self::update_liquid_meshes,
+                Self::apply_liquid_meshes,
+            )
+                .chain(),
+        )
+        .add_systems(FixedFirst, Self::count_fixed_tick)
+        // in fixed steps so edits are applied in the step they were made,
+        // replays apply them in the same steps
+        .add_systems(
+            FixedUpdate,
+            (Self::read_terrain_events, Self::read_history_events)
+                .chain()
+                .in_set(TerrainEditSet),
+        )
+        .add_systems(FixedUpdate, Self::simulate_liquids)
+        .add_systems(
+            Startup,
+            (Self::setup_material, Self::setup_liquid_materials),
+        )
+        .add_systems(Update, (Self::autosave, Self::despawn_debris))
+        .add_systems(Last, Self::save_on_exit);
     }
 }
@@ -0,0 +1,720 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use bevy::prelude::*;
+
+use super::chunk::{Cell, CELL_GRID_SIZE_3};
+use super::liquid::{LiquidCell, LiquidType};
+use super::material::{MaterialId, DEFAULT_MATERIAL};
+
+// Number of chunks along each axis of a region file.
+pub const REGION_SIZE: i32 = 8;
+
+const REGION_MAGIC: &[u8; 4] = b"CAVR";
+const REGION_VERSION: u32 = 4;
+// Stored material ids without the names they stand for,
+// which only held while the material list didn't change.
+const REGION_VERSION_POSITIONAL_MATERIALS: u32 = 3;
+// Didn't store liquid, chunks regenerate it when loaded.
+const REGION_VERSION_NO_LIQUID: u32 = 2;
+// Stored cell types as u8 ids, same as the default material ids.
+const REGION_VERSION_U8_MATERIALS: u32 = 1;
+
+// How the liquid of a chunk is stored.
+const LIQUID_NOT_STORED: u8 = 0;
+const LIQUID_EMPTY: u8 = 1;
+const LIQUID_CELLS: u8 = 2;
+
+pub type ChunkCells = Box<[Cell; CELL_GRID_SIZE_3]>;
+
+// A saved chunk.
+#[derive(Clone)]
+pub struct StoredChunk {
+    pub cells: ChunkCells,
+    // Liquid in the cells of the chunk, None if it
+    // wasn't saved and should be regenerated.
+    pub liquid: Option<Vec<LiquidCell>>,
+}
+
+type RegionChunks = HashMap<IVec3, StoredChunk>;
+
+#[derive(Default)]
+struct Region {
+    // Shared with flushes writing the region in the background,
+    // copied on write if one is still running.
+    chunks: Arc<RegionChunks>,
+    is_dirty: bool,
+    // Set when an existing file couldn't be read, it is never
+    // overwritten so nothing in it is lost. The region is saved
+    // to a side file next to it instead.
+    is_read_only: bool,
+}
+
+// Stores edited chunks in region files so they can be
+// loaded instead of regenerated when streamed back in.
+// Regions are kept in memory once read and written back on flush.
+// Clones share the same regions, so chunk generation tasks
+// can load chunks off the main thread.
+#[derive(Resource, Clone)]
+pub struct RegionStore {
+    dir: Option<PathBuf>,
+    seed: u32,
+    // Names of the materials by id, saved with every region
+    // so ids can be matched up again if the list changes.
+    material_names: Arc<Vec<String>>,
+    regions: Arc<Mutex<HashMap<IVec3, Region>>>,
+}
+
+impl RegionStore {
+    // Fails if the directory has regions saved for another seed,
+    // editing the world would mix chunks of the two.
+    pub fn new(dir: Option<PathBuf>, seed: u32, material_names: Vec<String>) -> io::Result<Self> {
+        if let Some(dir) = &dir {
+            Self::check_seed(dir, seed)?;
+        }
+        return Ok(RegionStore {
+            dir,
+            seed,
+            material_names: Arc::new(material_names),
+            regions: Arc::new(Mutex::new(HashMap::new())),
+        });
+    }
+
+    pub fn region_of(chunk_pos: IVec3) -> IVec3 {
+        return chunk_pos.div_euclid(IVec3::splat(REGION_SIZE));
+    }
+
+    // Gets a saved chunk, if it has been saved before.
+    // Reads the region file if it isn't loaded yet, so this
+    // should be called from a background task.
+    pub fn load_chunk(&self, chunk_pos: IVec3) -> Option<StoredChunk> {
+        let region_pos = Self::region_of(chunk_pos);
+        let regions = self.get_region(region_pos);
+        return regions[&region_pos].chunks.get(&chunk_pos).cloned();
+    }
+
+    // Whether the region holding a chunk is in memory,
+    // so loading the chunk doesn't read from disk.
+    pub fn is_region_read(&self, chunk_pos: IVec3) -> bool {
+        let regions = self.regions.lock().unwrap();
+        return regions.contains_key(&Self::region_of(chunk_pos));
+    }
+
+    // Reads the region holding a chunk into memory.
+    pub fn read_region_of(&self, chunk_pos: IVec3) {
+        drop(self.get_region(Self::region_of(chunk_pos)));
+    }
+
+    // Saves the cells and liquid of a chunk.
+    // Not written to disk until the next flush.
+    pub fn store_chunk(
+        &self,
+        chunk_pos: IVec3,
+        cells: &[Cell; CELL_GRID_SIZE_3],
+        liquid: Option<&[LiquidCell]>,
+    ) {
+        let region_pos = Self::region_of(chunk_pos);
+        let mut regions = self.get_region(region_pos);
+        let region = regions.get_mut(&region_pos).unwrap();
+        let stored = StoredChunk {
+            cells: Box::new(*cells),
+            liquid: liquid.map(|liquid| liquid.to_vec()),
+        };
+        Arc::make_mut(&mut region.chunks).insert(chunk_pos, stored);
+        region.is_dirty = true;
+    }
+
+    // Writes all modified regions to disk.
+    pub fn flush(&self) {
+        let Some(dir) = &self.dir else {
+            return;
+        };
+
+        if let Err(err) = fs::create_dir_all(dir) {
+            error!("Failed to create save directory {:?}: {}", dir, err);
+            return;
+        }
+
+        // written without holding the lock, so chunk generation
+        // tasks can keep loading chunks in the meantime
+        let mut dirty: Vec<(IVec3, Arc<RegionChunks>, bool)> = Vec::new();
+        for (region_pos, region) in self.regions.lock().unwrap().iter_mut() {
+            if region.is_dirty {
+                region.is_dirty = false;
+                dirty.push((*region_pos, region.chunks.clone(), region.is_read_only));
+            }
+        }
+
+        for (region_pos, chunks, is_read_only) in dirty {
+            let path = Self::region_path(dir, region_pos, is_read_only);
+            if let Err(err) = Self::write_region(&path, self.seed, &self.material_names, &chunks) {
+                error!("Failed to write region {:?}: {}", path, err);
+                // tried again on the next flush
+                if let Some(region) = self.regions.lock().unwrap().get_mut(&region_pos) {
+                    region.is_dirty = true;
+                }
+            }
+        }
+    }
+
+    // Locks the regions, reading the given one in first if needed.
+    fn get_region(&self, region_pos: IVec3) -> MutexGuard<'_, HashMap<IVec3, Region>> {
+        let regions = self.regions.lock().unwrap();
+        if regions.contains_key(&region_pos) {
+            return regions;
+        }
+        drop(regions);
+
+        // read without holding the lock, if another thread
+        // got to the region first its copy is kept instead.
+        let region = self.read_region(region_pos);
+        let mut regions = self.regions.lock().unwrap();
+        regions.entry(region_pos).or_insert(region);
+        return regions;
+    }
+
+    fn region_file_name(region_pos: IVec3) -> String {
+        return format!(
+            "r.{}.{}.{}.region",
+            region_pos.x, region_pos.y, region_pos.z
+        );
+    }
+
+    // Path regions are saved to, read only regions
+    // go to a side file next to their region file.
+    fn region_path(dir: &Path, region_pos: IVec3, is_read_only: bool) -> PathBuf {
+        let file_name = Self::region_file_name(region_pos);
+        if is_read_only {
+            return dir.join(format!("{}.new", file_name));
+        }
+        return dir.join(file_name);
+    }
+
+    // Checks the headers of the region files in a directory
+    // were saved with the seed.
+    fn check_seed(dir: &Path, seed: u32) -> io::Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension() != Some("region".as_ref()) {
+                continue;
+            }
+            // unreadable files are dealt with when their region is loaded
+            let Ok(mut file) = File::open(&path) else {
+                continue;
+            };
+            let Ok(file_seed) = read_header(&mut file).map(|(_, file_seed)| file_seed) else {
+                continue;
+            };
+            if file_seed != seed {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "region {:?} saved with seed {}, world seed is {}",
+                        path, file_seed, seed
+                    ),
+                ));
+            }
+        }
+        return Ok(());
+    }
+
+    fn read_region(&self, region_pos: IVec3) -> Region {
+        let Some(dir) = &self.dir else {
+            return Region::default();
+        };
+
+        let path = Self::region_path(dir, region_pos, false);
+        let result = File::open(&path).and_then(|file| {
+            let mut reader = BufReader::new(file);
+            return Self::parse_region(&mut reader, self.seed, &self.material_names);
+        });
+        match result {
+            Ok(region) => return region,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Region::default(),
+            // truncated or malformed, nothing more can be read from it
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                error!("Region {:?} is corrupted: {}", path, err);
+                return Self::unreadable_region(&path);
+            }
+            // may be readable again later, keep the file as it is
+            Err(err) => {
+                error!("Failed to read region {:?}: {}", path, err);
+                return self.read_only_region(region_pos);
+            }
+        }
+    }
+
+    // Moves a corrupted region file out of the way so saving edits
+    // to the region doesn't overwrite it. If it can't be moved the
+    // region is kept read only instead.
+    fn unreadable_region(path: &Path) -> Region {
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let bad_path = (0..)
+            .map(|i| path.with_file_name(format!("{}.bad{}", file_name, i)))
+            .find(|bad_path| !bad_path.exists())
+            .unwrap();
+
+        match fs::rename(path, &bad_path) {
+            Ok(()) => {
+                warn!("Moved unreadable region {:?} to {:?}", path, bad_path);
+                return Region::default();
+            }
+            Err(err) => {
+                error!("Failed to move region {:?} aside: {}", path, err);
+                return Region {
+                    is_read_only: true,
+                    ..default()
+                };
+            }
+        }
+    }
+
+    // A region whose file can't be read right now, continuing
+    // from the edits saved to its side file in earlier sessions.
+    fn read_only_region(&self, region_pos: IVec3) -> Region {
+        let mut region = Region {
+            is_read_only: true,
+            ..default()
+        };
+        let Some(dir) = &self.dir else {
+            return region;
+        };
+
+        let side_path = Self::region_path(dir, region_pos, true);
+        let result = File::open(&side_path).and_then(|file| {
+            let mut reader = BufReader::new(file);
+            return Self::parse_region(&mut reader, self.seed, &self.material_names);
+        });
+        match result {
+            Ok(saved) => region.chunks = saved.chunks,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => error!("Failed to read region {:?}: {}", side_path, err),
+        }
+        return region;
+    }
+
+    // Region file layout, little endian:
+    //   magic, version: u32, seed: u32,
+    //   material count: u16, per material: name length: u16, utf-8 name
+    //   chunk count: u32
+    //   per chunk: x, y, z: i32, then per cell: value: f32, material: u16,
+    //   then liquid: u8, if LIQUID_CELLS per cell: level: f32, kind: u8
+    // Version 3 files had no material names, version 2 files also had
+    // no liquid, version 1 files also stored the material as a u8.
+    fn parse_region(
+        reader: &mut impl Read,
+        seed: u32,
+        material_names: &[String],
+    ) -> io::Result<Region> {
+        let (version, file_seed) = read_header(reader)?;
+        // not corruption, the file belongs to another world
+        if file_seed != seed {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "region saved with seed {}, world seed is {}",
+                    file_seed, seed
+                ),
+            ));
+        }
+
+        // older files hold the ids the materials had back then
+        let material_ids = if version <= REGION_VERSION_POSITIONAL_MATERIALS {
+            None
+        } else {
+            Some(read_material_ids(reader, material_names)?)
+        };
+
+        let mut chunks = RegionChunks::new();
+        let count = read_u32(reader)?;
+        for _ in 0..count {
+            let chunk_pos = IVec3 {
+                x: read_i32(reader)?,
+                y: read_i32(reader)?,
+                z: read_i32(reader)?,
+            };
+            let mut cells = Box::new([Cell::default(); CELL_GRID_SIZE_3]);
+            for cell in cells.iter_mut() {
+                cell.value = read_f32(reader)?;
+                if version == REGION_VERSION_U8_MATERIALS {
+                    let mut material = [0u8; 1];
+                    reader.read_exact(&mut material)?;
+                    cell.material = material[0] as MaterialId;
+                } else {
+                    cell.material = read_u16(reader)?;
+                }
+                if let Some(material_ids) = &material_ids {
+                    cell.material = *material_ids.get(cell.material as usize).ok_or_else(|| {
+                        return io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("material {} missing from the region", cell.material),
+                        );
+                    })?;
+                }
+            }
+
+            let liquid = if version <= REGION_VERSION_NO_LIQUID {
+                None
+            } else {
+                read_liquid(reader)?
+            };
+            chunks.insert(chunk_pos, StoredChunk { cells, liquid });
+        }
+
+        return Ok(Region {
+            chunks: Arc::new(chunks),
+            ..default()
+        });
+    }
+
+    fn write_region(
+        path: &Path,
+        seed: u32,
+        material_names: &[String],
+        chunks: &RegionChunks,
+    ) -> io::Result<()> {
+        // write to a temporary file first so a crash
+        // mid-write doesn't corrupt the existing region
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        Self::write_chunks(&mut writer, seed, material_names, chunks)?;
+        writer.flush()?;
+        drop(writer);
+
+        fs::rename(&tmp_path, path)?;
+        return Ok(());
+    }
+
+    fn write_chunks(
+        writer: &mut impl Write,
+        seed: u32,
+        material_names: &[String],
+        chunks: &RegionChunks,
+    ) -> io::Result<()> {
+        writer.write_all(REGION_MAGIC)?;
+        writer.write_all(&REGION_VERSION.to_le_bytes())?;
+        writer.write_all(&seed.to_le_bytes())?;
+        writer.write_all(&(material_names.len() as u16).to_le_bytes())?;
+        for name in material_names {
+            writer.write_all(&(name.len() as u16).to_le_bytes())?;
+            writer.write_all(name.as_bytes())?;
+        }
+        writer.write_all(&(chunks.len() as u32).to_le_bytes())?;
+        for (chunk_pos, stored) in chunks {
+            writer.write_all(&chunk_pos.x.to_le_bytes())?;
+            writer.write_all(&chunk_pos.y.to_le_bytes())?;
+            writer.write_all(&chunk_pos.z.to_le_bytes())?;
+            for cell in stored.cells.iter() {
+                writer.write_all(&cell.value.to_le_bytes())?;
+                writer.write_all(&cell.material.to_le_bytes())?;
+            }
+            write_liquid(writer, stored.liquid.as_deref())?;
+        }
+        return Ok(());
+    }
+}
+
+// Reads the magic, version and seed at the start of a region file.
+fn read_header(reader: &mut impl Read) -> io::Result<(u32, u32)> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != REGION_MAGIC {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a region file",
+        ));
+    }
+
+    let version = read_u32(reader)?;
+    if !(REGION_VERSION_U8_MATERIALS..=REGION_VERSION).contains(&version) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported region version {}", version),
+        ));
+    }
+
+    let seed = read_u32(reader)?;
+    return Ok((version, seed));
+}
+
+// Reads the material names saved with a region, mapped to the ids
+// the materials have now. Materials that were removed since
+// the region was saved become the default material.
+fn read_material_ids(
+    reader: &mut impl Read,
+    material_names: &[String],
+) -> io::Result<Vec<MaterialId>> {
+    let count = read_u16(reader)?;
+    let mut material_ids = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut name = vec![0u8; read_u16(reader)? as usize];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8(name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+        let id = material_names.iter().position(|known| *known == name);
+        if id.is_none() {
+            warn!("Unknown material {:?} in region, using the default", name);
+        }
+        material_ids.push(id.map_or(DEFAULT_MATERIAL, |id| id as MaterialId));
+    }
+    return Ok(material_ids);
+}
+
+fn read_liquid(reader: &mut impl Read) -> io::Result<Option<Vec<LiquidCell>>> {
+    let mut state = [0u8; 1];
+    reader.read_exact(&mut state)?;
+    match state[0] {
+        LIQUID_NOT_STORED => return Ok(None),
+        LIQUID_EMPTY => return Ok(Some(vec![LiquidCell::default(); CELL_GRID_SIZE_3])),
+        LIQUID_CELLS => {}
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "invalid liquid state",
+            ))
+        }
+    }
+
+    let mut liquid = vec![LiquidCell::default(); CELL_GRID_SIZE_3];
+    for cell in liquid.iter_mut() {
+        cell.level = read_f32(reader)?;
+        let mut kind = [0u8; 1];
+        reader.read_exact(&mut kind)?;
+        cell.kind = match kind[0] {
+            0 => LiquidType::Water,
+            1 => LiquidType::Lava,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid liquid type",
+                ))
+            }
+        };
+    }
+    return Ok(Some(liquid));
+}
+
+fn write_liquid(writer: &mut impl Write, liquid: Option<&[LiquidCell]>) -> io::Result<()> {
+    let Some(liquid) = liquid else {
+        return writer.write_all(&[LIQUID_NOT_STORED]);
+    };
+    if liquid.iter().all(|cell| cell.level <= 0.0) {
+        return writer.write_all(&[LIQUID_EMPTY]);
+    }
+
+    writer.write_all(&[LIQUID_CELLS])?;
+    for cell in liquid {
+        let kind: u8 = match cell.kind {
+            LiquidType::Water => 0,
+            LiquidType::Lava => 1,
+        };
+        writer.write_all(&cell.level.to_le_bytes())?;
+        writer.write_all(&[kind])?;
+    }
+    return Ok(());
+}
+
+fn read_u16(reader: &mut impl Read) -> io::Result<u16> {
+    let mut bytes = [0u8; 2];
+    reader.read_exact(&mut bytes)?;
+    return Ok(u16::from_le_bytes(bytes));
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    return Ok(u32::from_le_bytes(bytes));
+}
+
+fn read_i32(reader: &mut impl Read) -> io::Result<i32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    return Ok(i32::from_le_bytes(bytes));
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    return Ok(f32::from_le_bytes(bytes));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: u32 = 1337;
+
+    fn material_names() -> Vec<String> {
+        return ["stone", "dirt", "sand", "coal", "iron"]
+            .map(String::from)
+            .to_vec();
+    }
+
+    fn test_cells() -> ChunkCells {
+        let mut cells = Box::new([Cell::default(); CELL_GRID_SIZE_3]);
+        for (index, cell) in cells.iter_mut().enumerate() {
+            cell.value = (index % 7) as f32 / 6.0;
+            cell.material = (index % 5) as MaterialId;
+        }
+        return cells;
+    }
+
+    // Writes a region in the layout of an older version, without
+    // material names. Chunks in it have no liquid.
+    fn write_old_region(version: u32, chunk_pos: IVec3, cells: &ChunkCells) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend(REGION_MAGIC);
+        bytes.extend(version.to_le_bytes());
+        bytes.extend(SEED.to_le_bytes());
+        bytes.extend(1u32.to_le_bytes());
+        bytes.extend(chunk_pos.to_array().map(i32::to_le_bytes).concat());
+        for cell in cells.iter() {
+            bytes.extend(cell.value.to_le_bytes());
+            if version == REGION_VERSION_U8_MATERIALS {
+                bytes.push(cell.material as u8);
+            } else {
+                bytes.extend(cell.material.to_le_bytes());
+            }
+        }
+        if version > REGION_VERSION_NO_LIQUID {
+            bytes.push(LIQUID_NOT_STORED);
+        }
+        return bytes;
+    }
+
+    #[test]
+    fn round_trips_current_version() {
+        let mut liquid = vec![LiquidCell::default(); CELL_GRID_SIZE_3];
+        liquid[10] = LiquidCell {
+            level: 0.5,
+            kind: LiquidType::Lava,
+        };
+        let mut chunks = RegionChunks::new();
+        chunks.insert(
+            IVec3::new(0, 1, 2),
+            StoredChunk {
+                cells: test_cells(),
+                liquid: Some(liquid),
+            },
+        );
+        chunks.insert(
+            IVec3::new(-1, 0, 3),
+            StoredChunk {
+                cells: test_cells(),
+                liquid: None,
+            },
+        );
+
+        let path = std::env::temp_dir().join(format!("region_test_{}.region", std::process::id()));
+        RegionStore::write_region(&path, SEED, &material_names(), &chunks).unwrap();
+        let file = File::open(&path).unwrap();
+        let region =
+            RegionStore::parse_region(&mut BufReader::new(file), SEED, &material_names()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(region.chunks.len(), 2);
+        let stored = &region.chunks[&IVec3::new(0, 1, 2)];
+        assert!(stored.cells == test_cells());
+        let liquid = stored.liquid.as_ref().unwrap();
+        assert_eq!(liquid[10].level, 0.5);
+        assert_eq!(liquid[10].kind, LiquidType::Lava);
+        assert!(liquid
+            .iter()
+            .enumerate()
+            .all(|(i, cell)| i == 10 || cell.level == 0.0));
+
+        let stored = &region.chunks[&IVec3::new(-1, 0, 3)];
+        assert!(stored.cells == test_cells());
+        assert!(stored.liquid.is_none());
+    }
+
+    #[test]
+    fn remaps_materials_by_name() {
+        let mut chunks = RegionChunks::new();
+        chunks.insert(
+            IVec3::ZERO,
+            StoredChunk {
+                cells: test_cells(),
+                liquid: None,
+            },
+        );
+        let mut bytes = Vec::new();
+        RegionStore::write_chunks(&mut bytes, SEED, &material_names(), &chunks).unwrap();
+
+        // materials reordered, with iron removed
+        let names = ["dirt", "coal", "stone", "sand"].map(String::from);
+        let region = RegionStore::parse_region(&mut bytes.as_slice(), SEED, &names).unwrap();
+
+        let expected_ids: [MaterialId; 5] = [2, 0, 3, 1, DEFAULT_MATERIAL];
+        let stored = &region.chunks[&IVec3::ZERO];
+        for (cell, expected) in stored.cells.iter().zip(test_cells().iter()) {
+            assert_eq!(cell.value, expected.value);
+            assert_eq!(cell.material, expected_ids[expected.material as usize]);
+        }
+    }
+
+    #[test]
+    fn reads_version_3() {
+        let chunk_pos = IVec3::new(1, 2, -3);
+        let bytes = write_old_region(
+            REGION_VERSION_POSITIONAL_MATERIALS,
+            chunk_pos,
+            &test_cells(),
+        );
+        let region =
+            RegionStore::parse_region(&mut bytes.as_slice(), SEED, &material_names()).unwrap();
+
+        let stored = &region.chunks[&chunk_pos];
+        assert!(stored.cells == test_cells());
+        assert!(stored.liquid.is_none());
+    }
+
+    #[test]
+    fn reads_version_2() {
+        let chunk_pos = IVec3::new(4, -2, 0);
+        let bytes = write_old_region(REGION_VERSION_NO_LIQUID, chunk_pos, &test_cells());
+        let region =
+            RegionStore::parse_region(&mut bytes.as_slice(), SEED, &material_names()).unwrap();
+
+        let stored = &region.chunks[&chunk_pos];
+        assert!(stored.cells == test_cells());
+        assert!(stored.liquid.is_none());
+    }
+
+    #[test]
+    fn reads_version_1() {
+        let chunk_pos = IVec3::new(0, 0, -5);
+        let bytes = write_old_region(REGION_VERSION_U8_MATERIALS, chunk_pos, &test_cells());
+        let region =
+            RegionStore::parse_region(&mut bytes.as_slice(), SEED, &material_names()).unwrap();
+
+        let stored = &region.chunks[&chunk_pos];
+        assert!(stored.cells == test_cells());
+        assert!(stored.liquid.is_none());
+    }
+
+    #[test]
+    fn rejects_other_seed_without_corruption() {
+        let bytes = write_old_region(REGION_VERSION_NO_LIQUID, IVec3::ZERO, &test_cells());
+        let Err(err) =
+            RegionStore::parse_region(&mut bytes.as_slice(), SEED + 1, &material_names())
+        else {
+            panic!("region with another seed was read");
+        };
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
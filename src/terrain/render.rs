@@ -0,0 +1,169 @@
+use std::f64::consts::TAU;
+
+use bevy::{
+    pbr::{ExtendedMaterial, MaterialExtension, MaterialExtensionKey, MaterialExtensionPipeline},
+    prelude::*,
+    render::{
+        mesh::{MeshVertexAttribute, MeshVertexBufferLayout},
+        render_asset::RenderAssetUsages,
+        render_resource::{
+            AsBindGroup, Extent3d, RenderPipelineDescriptor, ShaderRef,
+            SpecializedMeshPipelineError, TextureDimension, TextureFormat, TextureViewDescriptor,
+            TextureViewDimension, VertexFormat,
+        },
+        texture::{ImageAddressMode, ImageSampler, ImageSamplerDescriptor},
+    },
+};
+use noise::{NoiseFn, Perlin};
+
+use super::material::CellMaterialRegistry;
+
+// Materials of the triangle a vertex belongs to.
+pub const ATTRIBUTE_MATERIAL_IDS: MeshVertexAttribute =
+    MeshVertexAttribute::new("TerrainMaterialIds", 872_310_455, VertexFormat::Uint32x3);
+// Barycentric weights of the materials in ATTRIBUTE_MATERIAL_IDS.
+pub const ATTRIBUTE_MATERIAL_WEIGHTS: MeshVertexAttribute = MeshVertexAttribute::new(
+    "TerrainMaterialWeights",
+    872_310_456,
+    VertexFormat::Float32x3,
+);
+
+// Max number of materials with their own shading,
+// higher ids are shaded like the last one.
+// Must match the size of the array in terrain.wgsl.
+pub const MAX_SHADED_MATERIALS: usize = 256;
+
+const TEXTURE_SIZE: u32 = 64;
+// Textures repeat every this many world units.
+const TEXTURE_WORLD_SIZE: f32 = 4.0;
+
+const SHADER_PATH: &str = "shaders/terrain.wgsl";
+
+pub type TerrainMaterial = ExtendedMaterial<StandardMaterial, TerrainMaterialExtension>;
+
+// Material shared by all chunks.
+#[derive(Resource)]
+pub struct TerrainMaterialHandle(pub Handle<TerrainMaterial>);
+
+// Shades terrain by the materials of its cells,
+// blending between them at material boundaries.
+#[derive(Asset, AsBindGroup, TypePath, Clone, Debug)]
+pub struct TerrainMaterialExtension {
+    // One layer per material, sampled with triplanar mapping.
+    #[texture(100, dimension = "2d_array")]
+    #[sampler(101)]
+    pub textures: Handle<Image>,
+    // Roughness and metallic of each material in x and y.
+    #[uniform(102)]
+    pub surfaces: [Vec4; MAX_SHADED_MATERIALS],
+    // Texture repeats per world unit.
+    #[uniform(103)]
+    pub texture_scale: f32,
+}
+
+impl MaterialExtension for TerrainMaterialExtension {
+    fn vertex_shader() -> ShaderRef {
+        return SHADER_PATH.into();
+    }
+
+    fn fragment_shader() -> ShaderRef {
+        return SHADER_PATH.into();
+    }
+
+    fn specialize(
+        _pipeline: &MaterialExtensionPipeline,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+        _key: MaterialExtensionKey<Self>,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        // prepasses (including shadows) use the default shaders,
+        // which only need the positions
+        if descriptor
+            .vertex
+            .shader_defs
+            .contains(&"PREPASS_PIPELINE".into())
+        {
+            return Ok(());
+        }
+
+        let vertex_layout = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            ATTRIBUTE_MATERIAL_IDS.at_shader_location(8),
+            ATTRIBUTE_MATERIAL_WEIGHTS.at_shader_location(9),
+        ])?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        return Ok(());
+    }
+}
+
+impl TerrainMaterialExtension {
+    pub fn new(materials: &CellMaterialRegistry, images: &mut Assets<Image>) -> Self {
+        let mut surfaces = [Vec4::ZERO; MAX_SHADED_MATERIALS];
+        for (id, material) in materials.iter().take(MAX_SHADED_MATERIALS) {
+            surfaces[id as usize] = Vec4::new(material.roughness, material.metallic, 0.0, 0.0);
+        }
+
+        return TerrainMaterialExtension {
+            textures: images.add(Self::texture_array(materials)),
+            surfaces,
+            texture_scale: 1.0 / TEXTURE_WORLD_SIZE,
+        };
+    }
+
+    // Builds a texture array with a tiling detail texture
+    // in the color of each material.
+    fn texture_array(materials: &CellMaterialRegistry) -> Image {
+        let layer_count = materials.iter().take(MAX_SHADED_MATERIALS).count();
+        let mut data = Vec::with_capacity((TEXTURE_SIZE * TEXTURE_SIZE * 4) as usize * layer_count);
+
+        for (id, material) in materials.iter().take(MAX_SHADED_MATERIALS) {
+            let noise = Perlin::new(id as u32);
+            let color = Vec4::from_array(material.color);
+
+            for y in 0..TEXTURE_SIZE {
+                for x in 0..TEXTURE_SIZE {
+                    // sample noise on a torus so the texture tiles
+                    let u = x as f64 / TEXTURE_SIZE as f64 * TAU;
+                    let v = y as f64 / TEXTURE_SIZE as f64 * TAU;
+                    let radius = 1.5;
+                    let detail = noise.get([
+                        u.cos() * radius,
+                        u.sin() * radius,
+                        v.cos() * radius,
+                        v.sin() * radius,
+                    ]) as f32;
+
+                    let shade = 0.85 + detail * 0.15;
+                    let texel = (color * Vec4::new(shade, shade, shade, 1.0))
+                        .clamp(Vec4::ZERO, Vec4::ONE)
+                        * 255.0;
+                    data.extend(texel.to_array().map(|c| c as u8));
+                }
+            }
+        }
+
+        let mut image = Image::new(
+            Extent3d {
+                width: TEXTURE_SIZE,
+                height: TEXTURE_SIZE,
+                depth_or_array_layers: layer_count as u32,
+            },
+            TextureDimension::D2,
+            data,
+            // colors are linear
+            TextureFormat::Rgba8Unorm,
+            RenderAssetUsages::RENDER_WORLD,
+        );
+        image.texture_view_descriptor = Some(TextureViewDescriptor {
+            dimension: Some(TextureViewDimension::D2Array),
+            ..default()
+        });
+        image.sampler = ImageSampler::Descriptor(ImageSamplerDescriptor {
+            address_mode_u: ImageAddressMode::Repeat,
+            address_mode_v: ImageAddressMode::Repeat,
+            ..ImageSamplerDescriptor::linear()
+        });
+        return image;
+    }
+}
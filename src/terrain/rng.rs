@@ -0,0 +1,57 @@
+use bevy::prelude::*;
+
+// Small deterministic random number generator,
+// used where generation has to be reproducible from the world seed.
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> Self {
+        return SplitMix64 { state: seed };
+    }
+
+    // Seeds a generator from the world seed, a grid position
+    // and a salt so different features get different sequences.
+    pub fn at(seed: u32, pos: IVec3, salt: u64) -> Self {
+        let mut hash = Self::new(seed as u64 ^ salt.wrapping_mul(0x9E37_79B9_7F4A_7C15));
+        let mut seed = hash.next_u64();
+        for coord in [pos.x, pos.y, pos.z] {
+            seed ^= coord as u32 as u64;
+            seed = Self::new(seed).next_u64();
+        }
+        return Self::new(seed);
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        return z ^ (z >> 31);
+    }
+
+    // Random value from 0 to 1, excluding 1.
+    pub fn next_f32(&mut self) -> f32 {
+        return (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32;
+    }
+
+    pub fn range(&mut self, min: f32, max: f32) -> f32 {
+        return min + (max - min) * self.next_f32();
+    }
+
+    // Random unit vector.
+    pub fn direction(&mut self) -> Vec3 {
+        loop {
+            let v = Vec3::new(
+                self.range(-1.0, 1.0),
+                self.range(-1.0, 1.0),
+                self.range(-1.0, 1.0),
+            );
+            let length_sq = v.length_squared();
+            if length_sq > 0.0001 && length_sq <= 1.0 {
+                return v / length_sq.sqrt();
+            }
+        }
+    }
+}
@@ -0,0 +1,544 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::sync::Arc;
+
+use bevy::{
+    prelude::*,
+    render::mesh::{Indices, VertexAttributeValues},
+};
+use serde::{Deserialize, Serialize};
+
+// Shapes used for terrain edits.
+// Shapes are defined in a local space where +Y points
+// along the edit direction and the origin is at the hit point.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum TerrainEditShape {
+    Sphere(f32),
+    // Box with the given half extents.
+    Box(Vec3),
+    // Cylinder centered on the hit point.
+    Cylinder {
+        radius: f32,
+        height: f32,
+    },
+    // Capsule from the hit point along the edit direction,
+    // for drilling tunnels.
+    Capsule {
+        radius: f32,
+        length: f32,
+    },
+    // Cone with its base on the hit point,
+    // narrowing to a tip along the edit direction.
+    Cone {
+        radius: f32,
+        height: f32,
+    },
+    // User supplied signed distance function, can't be serialized.
+    #[serde(skip)]
+    Sdf(TerrainSdf),
+}
+
+// Signed distance function stamped into the terrain,
+// negative inside the shape.
+#[derive(Clone)]
+pub struct TerrainSdf {
+    pub func: Arc<dyn Fn(Vec3) -> f32 + Send + Sync>,
+    // Half extents of the local space bounding box of the shape.
+    pub half_extents: Vec3,
+}
+
+impl fmt::Debug for TerrainSdf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return f
+            .debug_struct("TerrainSdf")
+            .field("half_extents", &self.half_extents)
+            .finish_non_exhaustive();
+    }
+}
+
+impl TerrainSdf {
+    pub fn new(half_extents: Vec3, func: impl Fn(Vec3) -> f32 + Send + Sync + 'static) -> Self {
+        return TerrainSdf {
+            func: Arc::new(func),
+            half_extents,
+        };
+    }
+
+    // Creates a signed distance function from a closed triangle mesh,
+    // centered on the mesh origin.
+    pub fn from_mesh(mesh: &Mesh) -> Option<Self> {
+        let Some(VertexAttributeValues::Float32x3(positions)) =
+            mesh.attribute(Mesh::ATTRIBUTE_POSITION)
+        else {
+            return None;
+        };
+        let positions: Vec<Vec3> = positions.iter().map(|p| Vec3::from(*p)).collect();
+
+        let indices: Vec<usize> = match mesh.indices() {
+            Some(Indices::U16(indices)) => indices.iter().map(|i| *i as usize).collect(),
+            Some(Indices::U32(indices)) => indices.iter().map(|i| *i as usize).collect(),
+            None => (0..positions.len()).collect(),
+        };
+
+        let mut triangles: Vec<[Vec3; 3]> = Vec::new();
+        for tri in indices.chunks_exact(3) {
+            // indices past the vertices mean a broken mesh
+            let [Some(a), Some(b), Some(c)] = [tri[0], tri[1], tri[2]].map(|i| positions.get(i))
+            else {
+                return None;
+            };
+            triangles.push([*a, *b, *c]);
+        }
+        if triangles.is_empty() {
+            return None;
+        }
+
+        let half_extents = positions
+            .iter()
+            .fold(Vec3::ZERO, |extents, p| extents.max(p.abs()));
+
+        let grid = MeshGrid::new(triangles);
+        return Some(Self::new(half_extents, move |p| {
+            return grid.distance(p);
+        }));
+    }
+}
+
+impl TerrainEditShape {
+    // Gets the rotation from shape local space to world space.
+    pub fn rotation(dir: Vec3) -> Quat {
+        let dir = dir.normalize_or_zero();
+        if dir == Vec3::ZERO {
+            return Quat::IDENTITY;
+        }
+        return Quat::from_rotation_arc(Vec3::Y, dir);
+    }
+
+    // Gets the world space bounding box of the shape
+    // at a position, oriented along a direction.
+    pub fn bounds(&self, center: Vec3, dir: Vec3) -> (Vec3, Vec3) {
+        let (local_min, local_max) = self.local_bounds();
+        let rotation = Self::rotation(dir);
+
+        let mut min = Vec3::INFINITY;
+        let mut max = Vec3::NEG_INFINITY;
+        for i in 0..8 {
+            let corner = Vec3 {
+                x: if i & 1 == 0 { local_min.x } else { local_max.x },
+                y: if i & 2 == 0 { local_min.y } else { local_max.y },
+                z: if i & 4 == 0 { local_min.z } else { local_max.z },
+            };
+            let world = center + rotation * corner;
+            min = min.min(world);
+            max = max.max(world);
+        }
+
+        return (min, max);
+    }
+
+    // Gets the signed distance from the shape surface
+    // to a point in shape local space, negative inside.
+    pub fn distance(&self, local_pos: Vec3) -> f32 {
+        let p = local_pos;
+        match self {
+            TerrainEditShape::Sphere(radius) => {
+                return p.length() - radius;
+            }
+            TerrainEditShape::Box(half_extents) => {
+                let q = p.abs() - *half_extents;
+                return q.max(Vec3::ZERO).length() + q.max_element().min(0.0);
+            }
+            TerrainEditShape::Cylinder { radius, height } => {
+                let d = Vec2 {
+                    x: p.xz().length() - radius,
+                    y: p.y.abs() - height * 0.5,
+                };
+                return d.max_element().min(0.0) + d.max(Vec2::ZERO).length();
+            }
+            TerrainEditShape::Capsule { radius, length } => {
+                let on_segment = Vec3::Y * p.y.clamp(0.0, *length);
+                return (p - on_segment).length() - radius;
+            }
+            TerrainEditShape::Cone { radius, height } => {
+                // capped cone from https://iquilezles.org/articles/distfunctions/
+                // with the base radius at y = 0 and the tip at y = height
+                let h = height * 0.5;
+                let q = Vec2 {
+                    x: p.xz().length(),
+                    y: p.y - h,
+                };
+                let k1 = Vec2 { x: 0.0, y: h };
+                let k2 = Vec2 {
+                    x: -radius,
+                    y: 2.0 * h,
+                };
+                let ca = Vec2 {
+                    x: q.x - q.x.min(if q.y < 0.0 { *radius } else { 0.0 }),
+                    y: q.y.abs() - h,
+                };
+                let cb = q - k1 + k2 * ((k1 - q).dot(k2) / k2.length_squared()).clamp(0.0, 1.0);
+                let sign = if cb.x < 0.0 && ca.y < 0.0 { -1.0 } else { 1.0 };
+                return sign * ca.length_squared().min(cb.length_squared()).sqrt();
+            }
+            TerrainEditShape::Sdf(sdf) => {
+                return (sdf.func)(p);
+            }
+        }
+    }
+
+    // Gets the distance from the shape surface to its center,
+    // which the falloff is relative to.
+    pub fn size(&self) -> f32 {
+        match self {
+            TerrainEditShape::Sphere(radius) => {
+                return *radius;
+            }
+            TerrainEditShape::Box(half_extents) => {
+                return half_extents.min_element();
+            }
+            TerrainEditShape::Cylinder { radius, height } => {
+                return radius.min(height * 0.5);
+            }
+            TerrainEditShape::Capsule { radius, .. } => {
+                return *radius;
+            }
+            TerrainEditShape::Cone { radius, height } => {
+                return radius.min(*height) * 0.5;
+            }
+            TerrainEditShape::Sdf(sdf) => {
+                return sdf.half_extents.min_element();
+            }
+        }
+    }
+
+    fn local_bounds(&self) -> (Vec3, Vec3) {
+        match self {
+            TerrainEditShape::Sphere(radius) => {
+                return (Vec3::splat(-radius), Vec3::splat(*radius));
+            }
+            TerrainEditShape::Box(half_extents) => {
+                return (-*half_extents, *half_extents);
+            }
+            TerrainEditShape::Cylinder { radius, height } => {
+                let extents = Vec3::new(*radius, height * 0.5, *radius);
+                return (-extents, extents);
+            }
+            TerrainEditShape::Capsule { radius, length } => {
+                return (
+                    Vec3::splat(-radius),
+                    Vec3::new(*radius, length + radius, *radius),
+                );
+            }
+            TerrainEditShape::Cone { radius, height } => {
+                return (
+                    Vec3::new(-radius, 0.0, -radius),
+                    Vec3::new(*radius, *height, *radius),
+                );
+            }
+            TerrainEditShape::Sdf(sdf) => {
+                return (-sdf.half_extents, sdf.half_extents);
+            }
+        }
+    }
+}
+
+// Most cells a mesh grid has along its longest side.
+const MESH_GRID_MAX_SIZE: f32 = 32.0;
+
+// Clusters closer than this many times their radius
+// add up the solid angles of their triangles exactly.
+const CLUSTER_NEAR_RADII: f32 = 2.0;
+
+// Triangles of a closed mesh sorted into a uniform grid, so distances
+// only look at the triangles in the cells around a point.
+struct MeshGrid {
+    triangles: Vec<[Vec3; 3]>,
+    min: Vec3,
+    cell_size: f32,
+    size: IVec3,
+    // triangles overlapping each cell
+    cells: Vec<Vec<usize>>,
+    clusters: Vec<TriangleCluster>,
+}
+
+// Triangles with their centroid in the same cell, seen
+// as a single patch from far enough away.
+struct TriangleCluster {
+    triangles: Vec<usize>,
+    center: Vec3,
+    radius: f32,
+    // sum of the triangle normals scaled by their areas
+    area: Vec3,
+}
+
+impl MeshGrid {
+    fn new(triangles: Vec<[Vec3; 3]>) -> Self {
+        let mut min = Vec3::INFINITY;
+        let mut max = Vec3::NEG_INFINITY;
+        for p in triangles.iter().flatten() {
+            min = min.min(*p);
+            max = max.max(*p);
+        }
+
+        // about one triangle per cell along each side
+        let resolution = (triangles.len() as f32)
+            .cbrt()
+            .ceil()
+            .min(MESH_GRID_MAX_SIZE);
+        let cell_size = ((max - min).max_element() / resolution).max(f32::EPSILON);
+        let size = ((max - min) / cell_size).ceil().as_ivec3().max(IVec3::ONE);
+
+        let mut grid = MeshGrid {
+            triangles: Vec::new(),
+            min,
+            cell_size,
+            size,
+            cells: vec![Vec::new(); (size.x * size.y * size.z) as usize],
+            clusters: Vec::new(),
+        };
+
+        let mut cluster_triangles: Vec<Vec<usize>> = vec![Vec::new(); grid.cells.len()];
+        for (i, triangle) in triangles.iter().enumerate() {
+            let tri_min = triangle[0].min(triangle[1]).min(triangle[2]);
+            let tri_max = triangle[0].max(triangle[1]).max(triangle[2]);
+            let (cell_min, cell_max) = (grid.cell_of(tri_min), grid.cell_of(tri_max));
+            for x in cell_min.x..=cell_max.x {
+                for y in cell_min.y..=cell_max.y {
+                    for z in cell_min.z..=cell_max.z {
+                        let index = grid.cell_index(IVec3 { x, y, z });
+                        grid.cells[index].push(i);
+                    }
+                }
+            }
+
+            let centroid = (triangle[0] + triangle[1] + triangle[2]) / 3.0;
+            cluster_triangles[grid.cell_index(grid.cell_of(centroid))].push(i);
+        }
+
+        for cluster in cluster_triangles {
+            if cluster.is_empty() {
+                continue;
+            }
+            let points = cluster.iter().flat_map(|i| triangles[*i]);
+            let center = points.clone().sum::<Vec3>() / (cluster.len() * 3) as f32;
+            let radius = points.fold(0.0, |radius: f32, p| radius.max(p.distance(center)));
+            let area = cluster
+                .iter()
+                .map(|i| {
+                    let [a, b, c] = triangles[*i];
+                    return (b - a).cross(c - a) * 0.5;
+                })
+                .sum();
+            grid.clusters.push(TriangleCluster {
+                triangles: cluster,
+                center,
+                radius,
+                area,
+            });
+        }
+
+        grid.triangles = triangles;
+        return grid;
+    }
+
+    // Gets the cell holding a point, clamped to the grid.
+    fn cell_of(&self, p: Vec3) -> IVec3 {
+        let cell = ((p - self.min) / self.cell_size).floor().as_ivec3();
+        return cell.clamp(IVec3::ZERO, self.size - IVec3::ONE);
+    }
+
+    fn cell_index(&self, cell: IVec3) -> usize {
+        return (cell.x * self.size.y * self.size.z + cell.y * self.size.z + cell.z) as usize;
+    }
+
+    // Signed distance to the mesh.
+    // The sign comes from the winding number of the mesh around the point,
+    // the solid angles of the triangles added up, which is about 1 inside
+    // and 0 outside however the mesh is shaped or wound.
+    fn distance(&self, p: Vec3) -> f32 {
+        let winding = self.solid_angle(p) / (4.0 * PI);
+        let sign = if winding.abs() > 0.5 { -1.0 } else { 1.0 };
+        return sign * self.closest_distance(p);
+    }
+
+    // Searches the cells in growing shells around the point, until
+    // the next shell is further away than the closest triangle found.
+    fn closest_distance(&self, p: Vec3) -> f32 {
+        let start = self.cell_of(p);
+        let mut closest_dist_sq = f32::INFINITY;
+
+        for shell in 0..=self.size.max_element() {
+            let shell_dist = (shell - 1).max(0) as f32 * self.cell_size;
+            if closest_dist_sq <= shell_dist * shell_dist {
+                break;
+            }
+
+            let shell_min = (start - IVec3::splat(shell)).max(IVec3::ZERO);
+            let shell_max = (start + IVec3::splat(shell)).min(self.size - IVec3::ONE);
+            for x in shell_min.x..=shell_max.x {
+                for y in shell_min.y..=shell_max.y {
+                    for z in shell_min.z..=shell_max.z {
+                        let cell = IVec3 { x, y, z };
+                        if (cell - start).abs().max_element() != shell {
+                            continue;
+                        }
+                        for i in &self.cells[self.cell_index(cell)] {
+                            let [a, b, c] = self.triangles[*i];
+                            let closest = closest_point_on_triangle(p, a, b, c);
+                            closest_dist_sq = closest_dist_sq.min((p - closest).length_squared());
+                        }
+                    }
+                }
+            }
+        }
+
+        return closest_dist_sq.sqrt();
+    }
+
+    // Sums up the solid angles of the triangles seen from the point,
+    // far away clusters as a single flat patch.
+    fn solid_angle(&self, p: Vec3) -> f32 {
+        let mut solid_angle = 0.0;
+        for cluster in &self.clusters {
+            let to_center = cluster.center - p;
+            let dist = to_center.length();
+            if dist > cluster.radius * CLUSTER_NEAR_RADII {
+                solid_angle += cluster.area.dot(to_center) / (dist * dist * dist);
+                continue;
+            }
+
+            for i in &cluster.triangles {
+                let [a, b, c] = self.triangles[*i];
+                solid_angle += triangle_solid_angle(a - p, b - p, c - p);
+            }
+        }
+        return solid_angle;
+    }
+}
+
+// Solid angle of a triangle seen from the origin, signed by its winding.
+// From A. Van Oosterom and J. Strackee, The Solid Angle of a Plane Triangle.
+fn triangle_solid_angle(a: Vec3, b: Vec3, c: Vec3) -> f32 {
+    let (la, lb, lc) = (a.length(), b.length(), c.length());
+    let numerator = a.dot(b.cross(c));
+    let denominator = la * lb * lc + a.dot(b) * lc + b.dot(c) * la + c.dot(a) * lb;
+    return 2.0 * numerator.atan2(denominator);
+}
+
+// From Real-Time Collision Detection by Christer Ericson.
+fn closest_point_on_triangle(p: Vec3, a: Vec3, b: Vec3, c: Vec3) -> Vec3 {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = ab.dot(ap);
+    let d2 = ac.dot(ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return a;
+    }
+
+    let bp = p - b;
+    let d3 = ab.dot(bp);
+    let d4 = ac.dot(bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    let cp = p - c;
+    let d5 = ab.dot(cp);
+    let d6 = ac.dot(cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    let denom = 1.0 / (va + vb + vc);
+    return a + ab * (vb * denom) + ac * (vc * denom);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A closed cube with half extents of one, split into n by n squares
+    // on every face.
+    fn cube_mesh(n: usize) -> Mesh {
+        let mut positions: Vec<[f32; 3]> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+        for axis in 0..3 {
+            for side in [-1.0, 1.0] {
+                let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+                let start = positions.len() as u32;
+                for i in 0..=n {
+                    for j in 0..=n {
+                        let mut p = [0.0; 3];
+                        p[axis] = side;
+                        p[u_axis] = i as f32 / n as f32 * 2.0 - 1.0;
+                        p[v_axis] = j as f32 / n as f32 * 2.0 - 1.0;
+                        positions.push(p);
+                    }
+                }
+                for i in 0..n as u32 {
+                    for j in 0..n as u32 {
+                        let row = n as u32 + 1;
+                        let corner = start + i * row + j;
+                        // wound counter clockwise seen from outside
+                        let mut quad = [corner, corner + row, corner + row + 1, corner + 1];
+                        if side < 0.0 {
+                            quad.reverse();
+                        }
+                        indices.extend([quad[0], quad[1], quad[2]]);
+                        indices.extend([quad[0], quad[2], quad[3]]);
+                    }
+                }
+            }
+        }
+
+        let mut mesh = Mesh::new(
+            bevy::render::render_resource::PrimitiveTopology::TriangleList,
+            bevy::render::render_asset::RenderAssetUsages::default(),
+        );
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_indices(Indices::U32(indices));
+        return mesh;
+    }
+
+    #[test]
+    fn cube_mesh_matches_box() {
+        let cube = TerrainEditShape::Box(Vec3::ONE);
+        for mesh in [Mesh::from(Cuboid::new(2.0, 2.0, 2.0)), cube_mesh(8)] {
+            let sdf = TerrainSdf::from_mesh(&mesh).unwrap();
+            assert_eq!(sdf.half_extents, Vec3::ONE);
+
+            for x in -6..=6 {
+                for y in -6..=6 {
+                    for z in -6..=6 {
+                        let p = Vec3::new(x as f32, y as f32, z as f32) * 0.33;
+                        let expected = cube.distance(p);
+                        let distance = (sdf.func)(p);
+                        assert!(
+                            (distance - expected).abs() < 1e-4,
+                            "distance {} at {} should be {}",
+                            distance,
+                            p,
+                            expected
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
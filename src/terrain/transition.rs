@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+use super::chunk::CHUNK_CUBE_SIZE;
+
+// Transition cells join the mesh of a chunk to a neighbor meshed at half
+// the detail. They serve the purpose of the transition cells of Lengyel's
+// Transvoxel algorithm, but are traced from their faces instead of looked
+// up in its tables. Cubes along the face are grouped into cells twice their
+// size. The faces of a cell on the neighbor's side only sample the coarse
+// lattice the neighbor meshes, the faces towards the rest of the chunk
+// sample every cell. The surface is found by connecting the points where it
+// crosses the faces of the cell, splitting faces with several crossings the
+// same way as the marching cubes tables, so the cells line up with the cubes
+// around them. The tests in chunk.rs check the seams for every combination
+// of coarse faces.
+
+// Whether the neighbor at each face, in get_face_neighbors order,
+// is meshed at half the detail of the chunk.
+pub(super) type CoarseFaces = [bool; 6];
+
+// Whether the block of cubes at min, twice the step in size,
+// touches a face of the chunk with a coarser neighbor.
+pub(super) fn is_transition_block(min: IVec3, step: i32, coarse: &CoarseFaces) -> bool {
+    let size = CHUNK_CUBE_SIZE as i32;
+    return (0..6).any(|face| {
+        let axis = face / 2;
+        let touches = if face % 2 == 0 {
+            min[axis] + step * 2 == size
+        } else {
+            min[axis] == 0
+        };
+        return coarse[face] && touches;
+    });
+}
+
+// Gets the faces of the transition cell at min as polygons of cell
+// positions, wound counter clockwise seen from outside the cell.
+pub(super) fn transition_faces(min: IVec3, step: i32, coarse: &CoarseFaces) -> Vec<Vec<IVec3>> {
+    let size = CHUNK_CUBE_SIZE as i32;
+    let mut polygons = Vec::new();
+
+    for face in 0..6 {
+        let axis = face / 2;
+        let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+        let positive = face % 2 == 0;
+
+        let mut origin = min;
+        if positive {
+            origin[axis] += step * 2;
+        }
+        let point = |u: i32, v: i32| {
+            let mut point = origin;
+            point[u_axis] += u * step;
+            point[v_axis] += v * step;
+            return point;
+        };
+        // u cross v points along the positive axis
+        let square = |u: i32, v: i32, length: i32| {
+            let mut corners = vec![
+                point(u, v),
+                point(u + length, v),
+                point(u + length, v + length),
+                point(u, v + length),
+            ];
+            if !positive {
+                corners.reverse();
+            }
+            return corners;
+        };
+
+        // the neighbor only samples the corners of faces it shares
+        let chunk_face_depth = if positive { size } else { 0 };
+        if coarse[face] && origin[axis] == chunk_face_depth {
+            polygons.push(square(0, 0, 2));
+            continue;
+        }
+
+        let mut face_polygons = vec![
+            square(0, 0, 1),
+            square(1, 0, 1),
+            square(1, 1, 1),
+            square(0, 1, 1),
+        ];
+        // edges lying on a coarse face lose their middle point
+        for midpoint in [point(1, 0), point(2, 1), point(1, 2), point(0, 1)] {
+            if is_dropped(midpoint, step, coarse) {
+                merge_at(&mut face_polygons, midpoint);
+            }
+        }
+        polygons.extend(face_polygons);
+    }
+
+    return polygons;
+}
+
+// Connects the points where the surface crosses the edges of the polygons
+// into closed loops. The crossings are given as the cell positions at the
+// solid and empty end of the edge. Each run of solid corners along a
+// polygon is cut off from the rest, which is how the marching cubes tables
+// split faces with two solid corners opposite each other.
+pub(super) fn surface_loops(
+    polygons: &[Vec<IVec3>],
+    is_solid: impl Fn(IVec3) -> bool,
+) -> Vec<Vec<(IVec3, IVec3)>> {
+    // Every crossing is entered from one polygon and left into the one
+    // on the other side of the edge, since they are wound the same way.
+    let mut next_crossing: HashMap<(IVec3, IVec3), (IVec3, IVec3)> = HashMap::new();
+    let mut starts: Vec<(IVec3, IVec3)> = Vec::new();
+
+    for polygon in polygons {
+        // crossed edges in order around the polygon, with whether
+        // the edge goes into the solid part
+        let mut crossings = Vec::new();
+        for (i, a) in polygon.iter().enumerate() {
+            let b = polygon[(i + 1) % polygon.len()];
+            match (is_solid(*a), is_solid(b)) {
+                (false, true) => crossings.push(((b, *a), true)),
+                (true, false) => crossings.push(((*a, b), false)),
+                _ => {}
+            }
+        }
+
+        // entering and leaving alternate
+        for (i, (edge, entering)) in crossings.iter().enumerate() {
+            if *entering {
+                let (exit, _) = crossings[(i + 1) % crossings.len()];
+                next_crossing.insert(*edge, exit);
+                starts.push(*edge);
+            }
+        }
+    }
+
+    let mut loops = Vec::new();
+    for start in starts {
+        let Some(mut edge) = next_crossing.remove(&start) else {
+            // already part of a loop
+            continue;
+        };
+        let mut surface_loop = vec![start];
+        while edge != start {
+            surface_loop.push(edge);
+            let Some(next) = next_crossing.remove(&edge) else {
+                break;
+            };
+            edge = next;
+        }
+
+        // two polygons sharing more than one edge
+        // can give a loop without any area
+        if surface_loop.len() >= 3 {
+            loops.push(surface_loop);
+        }
+    }
+
+    return loops;
+}
+
+// Whether a point lies on a face meshed at half detail by
+// the neighbor without being on the coarse lattice.
+fn is_dropped(point: IVec3, step: i32, coarse: &CoarseFaces) -> bool {
+    let size = CHUNK_CUBE_SIZE as i32;
+    let on_coarse_face = (0..6).any(|face| {
+        let depth = if face % 2 == 0 { size } else { 0 };
+        return coarse[face] && point[face / 2] == depth;
+    });
+    let on_coarse_lattice = (point % (step * 2)).cmpeq(IVec3::ZERO).all();
+    return on_coarse_face && !on_coarse_lattice;
+}
+
+// Merges the two polygons sharing the edge from a point into one,
+// without the point.
+fn merge_at(polygons: &mut Vec<Vec<IVec3>>, point: IVec3) {
+    let sharing: Vec<usize> = (0..polygons.len())
+        .filter(|i| polygons[*i].contains(&point))
+        .collect();
+    let [first, second] = sharing[..] else {
+        return;
+    };
+    let (a, b) = (&polygons[first], &polygons[second]);
+    let Some(merged) = merge_polygons(a, b, point).or_else(|| merge_polygons(b, a, point)) else {
+        return;
+    };
+
+    polygons.remove(second);
+    polygons.remove(first);
+    polygons.push(merged);
+}
+
+// Merges polygon a, going from the point to a vertex shared with b, with
+// b going back from that vertex to the point. None if they don't.
+fn merge_polygons(a: &[IVec3], b: &[IVec3], point: IVec3) -> Option<Vec<IVec3>> {
+    let a_point = a.iter().position(|v| *v == point)?;
+    let b_point = b.iter().position(|v| *v == point)?;
+    let shared = a[(a_point + 1) % a.len()];
+    if b[(b_point + b.len() - 1) % b.len()] != shared {
+        return None;
+    }
+
+    // around a from the shared vertex to the point,
+    // then around b from the point to the shared vertex
+    let mut merged: Vec<IVec3> = (1..=a.len()).map(|i| a[(a_point + i) % a.len()]).collect();
+    merged.extend((1..b.len() - 1).map(|i| b[(b_point + i) % b.len()]));
+    merged.retain(|v| *v != point);
+    return Some(merged);
+}